@@ -0,0 +1,240 @@
+//! Post-processing of a run's stats files (the `analyze` subcommand): summarises the
+//! trials of the driver log by capping scenario, so that the scenarios in which the BMC
+//! fails to cap stand out without loading the CSV files into a notebook. The summary
+//! is printed and saved in the stats directory next to the driver log.
+
+use crate::cli::Configuration;
+use crate::ResultType;
+use glob::glob;
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The trials of one capping scenario: capping order and operation, from one level to
+/// another
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScenarioSummary {
+    pub capping_order: String,
+    pub capping_operation: String,
+    pub cap_from: u64,
+    pub cap_to: u64,
+    pub trials: u64,
+    pub passed: u64,
+    pub failed: u64,
+    pub inconclusive: u64,
+    /// Trials whose load generator didn't run as asked (see `LoadStatus`)
+    pub load_problems: u64,
+    /// The time to compliance of each trial that passed
+    pub times_to_compliance_millis: Vec<u64>,
+}
+
+impl ScenarioSummary {
+    fn new(capping_order: &str, capping_operation: &str, cap_from: u64, cap_to: u64) -> Self {
+        Self {
+            capping_order: String::from(capping_order),
+            capping_operation: String::from(capping_operation),
+            cap_from,
+            cap_to,
+            trials: 0,
+            passed: 0,
+            failed: 0,
+            inconclusive: 0,
+            load_problems: 0,
+            times_to_compliance_millis: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn mean_time_to_compliance_millis(&self) -> Option<u64> {
+        let n = self.times_to_compliance_millis.len() as u64;
+        (n > 0).then(|| self.times_to_compliance_millis.iter().sum::<u64>() / n)
+    }
+}
+
+impl Display for ScenarioSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{},{},{},{},{},{},{},{}",
+            self.capping_order,
+            self.capping_operation,
+            self.cap_from,
+            self.cap_to,
+            self.trials,
+            self.passed,
+            self.failed,
+            self.inconclusive,
+            self.mean_time_to_compliance_millis().map_or(String::new(), |t| t.to_string()),
+            self.times_to_compliance_millis.iter().max().map_or(String::new(), u64::to_string),
+            self.load_problems,
+        )
+    }
+}
+
+/// The summary of a run's driver log
+#[derive(Debug)]
+pub struct Analysis {
+    pub test_timestamp: String,
+    pub driver_log: PathBuf,
+    pub scenarios: Vec<ScenarioSummary>,
+}
+
+impl Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let trials: u64 = self.scenarios.iter().map(|s| s.trials).sum();
+        writeln!(f, "Run {}: {trials} trials in {}", self.test_timestamp, self.driver_log.display())?;
+        for s in &self.scenarios {
+            write!(f, "  {} {} {} W -> {} W: {} trials, {} passed, {} failed, {} inconclusive",
+                s.capping_order, s.capping_operation, s.cap_from, s.cap_to, s.trials, s.passed, s.failed, s.inconclusive)?;
+            if let Some(mean) = s.mean_time_to_compliance_millis() {
+                write!(f, ", mean time to compliance {mean} ms")?;
+            }
+            if s.load_problems > 0 {
+                write!(f, ", {} with load generator problems", s.load_problems)?;
+            }
+            writeln!(f)?;
+        }
+        let failed: u64 = self.scenarios.iter().map(|s| s.failed).sum();
+        write!(f, "{failed} of {trials} trials failed")
+    }
+}
+
+/// Summarises the driver log of the run with the given test timestamp, or of the most
+/// recent run in the stats directory
+///
+/// # Errors
+/// If there's no driver log, or it can't be read or parsed
+pub fn analyze(config: &Configuration, test_timestamp: Option<&str>) -> ResultType<Analysis> {
+    let test_timestamp = match test_timestamp {
+        Some(test_timestamp) => String::from(test_timestamp),
+        None => latest_timestamp(&config.stats_dir, &config.driver_log_filename_prefix)
+            .ok_or_else(|| format!("no driver log in {}", config.stats_dir))?,
+    };
+    let driver_log = Path::new(&config.stats_dir)
+        .join(format!("{}_{test_timestamp}.csv", config.driver_log_filename_prefix));
+    debug!("Analysing driver log: {driver_log:?}");
+    let contents = fs::read_to_string(&driver_log).map_err(|e| format!("can't read {}: {e}", driver_log.display()))?;
+    let scenarios = summarise_driver_log(&contents)?;
+    Ok(Analysis { test_timestamp, driver_log, scenarios })
+}
+
+/// The test timestamp of the most recent driver log in `stats_dir`, if any
+fn latest_timestamp(stats_dir: &str, prefix: &str) -> Option<String> {
+    let pattern = Path::new(stats_dir).join(format!("{prefix}_*.csv"));
+    glob(pattern.to_str()?)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            Some(String::from(stem.strip_prefix(prefix)?.strip_prefix('_')?))
+        })
+        .max()
+}
+
+/// One summary per capping scenario of the driver log, ordered by capping order,
+/// operation and cap levels. Logs written before the load status was recorded are
+/// taken to have had no load generator problems.
+///
+/// # Errors
+/// If the header lacks a column the summary needs, or a row doesn't parse
+pub fn summarise_driver_log(contents: &str) -> ResultType<Vec<ScenarioSummary>> {
+    let mut lines = contents.lines();
+    let header: HashMap<&str, usize> = lines
+        .next()
+        .ok_or("empty driver log")?
+        .split(',')
+        .enumerate()
+        .map(|(i, column)| (column, i))
+        .collect();
+    let column = |name: &str| header.get(name).copied().ok_or_else(|| format!("no {name} column in driver log"));
+    let (order, operation, cap_from, cap_to, verdict, time_to_compliance) = (
+        column("capping_order")?,
+        column("capping_operation")?,
+        column("cap_from")?,
+        column("cap_to")?,
+        column("compliance_verdict")?,
+        column("time_to_compliance_millis")?,
+    );
+    let load_status = header.get("load_status").copied();
+
+    let mut scenarios: BTreeMap<(String, String, u64, u64), ScenarioSummary> = BTreeMap::new();
+    for (i, line) in lines.enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split(',').collect();
+        let field = |index: usize| fields.get(index).copied().ok_or_else(|| format!("driver log row {}: too few fields", i + 1));
+        let watts = |index: usize| -> ResultType<u64> {
+            let value = field(index)?;
+            value.parse().map_err(|e| format!("driver log row {}: invalid cap {value:?}: {e}", i + 1).into())
+        };
+        let key = (String::from(field(order)?), String::from(field(operation)?), watts(cap_from)?, watts(cap_to)?);
+        let summary = scenarios
+            .entry(key)
+            .or_insert_with_key(|(order, operation, from, to)| ScenarioSummary::new(order, operation, *from, *to));
+        summary.trials += 1;
+        match field(verdict)? {
+            "pass" => summary.passed += 1,
+            "fail" => summary.failed += 1,
+            _ => summary.inconclusive += 1,
+        }
+        if let Ok(millis) = field(time_to_compliance)?.parse() {
+            summary.times_to_compliance_millis.push(millis);
+        }
+        if load_status.is_some_and(|index| fields.get(index).is_some_and(|status| *status != "ok")) {
+            summary.load_problems += 1;
+        }
+    }
+    Ok(scenarios.into_values().collect())
+}
+
+/// Writes the summary to CSV file, next to the driver log, replacing any earlier one
+///
+/// # Errors
+/// If the file can't be written
+pub fn save_summary(config: &Configuration, analysis: &Analysis) -> ResultType<PathBuf> {
+    let filename = format!("{}_{}.csv",
+        config.trial_summary_filename_prefix,
+        analysis.test_timestamp
+    );
+    let filepath = Path::new(&config.stats_dir).join(filename);
+    debug!("Saving trial summary to: {filepath:?}");
+
+    let handle = File::create(&filepath)?;
+    let mut writer = BufWriter::new(handle);
+    writeln!(writer, "capping_order,capping_operation,cap_from,cap_to,trials,passed,failed,inconclusive,mean_time_to_compliance_millis,max_time_to_compliance_millis,load_problems")?;
+    for scenario in &analysis.scenarios {
+        writeln!(writer, "{scenario}")?;
+    }
+    Ok(filepath)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarise_driver_log() {
+        let log = "\
+            trial_id,capping_order,capping_operation,cap_from,cap_to,compliance_verdict,time_to_compliance_millis,load_status\n\
+            0,LevelToLevel,Activate,580,400,pass,3000,ok\n\
+            1,LevelToLevel,Activate,580,400,pass,5000,ok\n\
+            2,LevelToLevel,Activate,580,400,fail,,ended_early\n\
+            3,LevelBeforeActivate,Deactivate,580,400,inconclusive,,ok\n";
+        let scenarios = summarise_driver_log(log).unwrap();
+        let rows: Vec<String> = scenarios.iter().map(ToString::to_string).collect();
+        assert_eq!(rows, [
+            "LevelBeforeActivate,Deactivate,580,400,1,0,0,1,,,0",
+            "LevelToLevel,Activate,580,400,3,2,1,0,4000,5000,1",
+        ]);
+
+        // logs from before the load status was recorded
+        let log = "capping_order,capping_operation,cap_from,cap_to,compliance_verdict,time_to_compliance_millis\n\
+            LevelToLevel,Activate,580,400,fail,\n";
+        assert_eq!(summarise_driver_log(log).unwrap()[0].load_problems, 0);
+
+        assert!(summarise_driver_log("").is_err());
+        assert!(summarise_driver_log("trial_id,capping_order\n0,LevelToLevel\n").is_err());
+        let log = "capping_order,capping_operation,cap_from,cap_to,compliance_verdict,time_to_compliance_millis\n\
+            LevelToLevel,Activate,lots,400,fail,\n";
+        assert!(summarise_driver_log(log).is_err());
+    }
+}
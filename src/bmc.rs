@@ -2,31 +2,39 @@ use chrono::NaiveDateTime;
 use log::{trace, error};
 use std::process::Command;
 use std::fmt::{self, Display, Debug};
-use crate::cli::CONFIGURATION;
+use crate::cli::Configuration;
+use crate::ResultType;
 
 const BMC_READ_POWER_CMD: &str = "dcmi power reading";
 const BMC_CAP_SETTINGS_CMD: &str = "dcmi power get_limit";
 const BMC_SET_CAP_CMD: &str = "dcmi power set_limit limit";
 const BMC_ACTIVATE_CAP_CMD: &str = "dcmi power activate";
 const BMC_DEACTIVATE_CAP_CMD: &str = "dcmi power deactivate";
+// DCMI has no query for the range of limits the BMC accepts, Node Manager does
+const BMC_CAP_CAPABILITY_CMD: &str = "nm capability";
 
 pub struct BMC {
     pub hostname: String,
     pub username: String,
     pub password: String,
+    /// Path to the ipmitool executable the commands are run with
+    pub ipmi: String,
 }
 
+/// The parsed output of the DCMI power reading command. The timestamp is the BMC's own
+/// clock, which has a one second resolution and needn't agree with the host's.
 #[allow(non_camel_case_types)]
 #[derive(Debug, Copy, Clone)]
-struct BMC_PowerReading {
-    instant: u64,
-    minimum: u64,
-    maximum: u64,
-    average: u64,
-    timestamp: NaiveDateTime,
+pub struct BMC_PowerReading {
+    pub instant: u64,
+    pub minimum: u64,
+    pub maximum: u64,
+    pub average: u64,
+    pub timestamp: NaiveDateTime,
 }
 
 impl BMC_PowerReading {
+    #[must_use]
     pub fn new() -> Self {
         Self {
             instant: 0,
@@ -45,19 +53,79 @@ impl Default for BMC_PowerReading {
 }
 
 #[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct BMC_CapSetting {
     pub is_active: bool,
     pub power_limit: u64,
 }
 
+/// The range of power limits the BMC accepts, in W, from its capability query
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BMC_CapRange {
+    pub minimum: u64,
+    pub maximum: u64,
+}
+
+/// A change to the BMC's capping configuration
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BMC_Action {
+    SetLevel(u64),
+    Activate,
+    Deactivate,
+}
+
+impl BMC_Action {
+    /// The ipmitool arguments (less the credentials) that carry out the action
+    #[must_use]
+    pub fn command(&self) -> String {
+        match self {
+            Self::SetLevel(cap) => format!("{BMC_SET_CAP_CMD} {cap}"),
+            Self::Activate => String::from(BMC_ACTIVATE_CAP_CMD),
+            Self::Deactivate => String::from(BMC_DEACTIVATE_CAP_CMD),
+        }
+    }
+}
+
 impl BMC {
     #[must_use]
-    pub fn new(hostname: &str, username: &str, password: &str) -> Self {
+    pub fn new(hostname: &str, username: &str, password: &str, ipmi: &str) -> Self {
         Self {
             hostname: String::from(hostname),
             username: String::from(username),
             password: String::from(password),
+            ipmi: String::from(ipmi),
+        }
+    }
+
+    /// The BMC, and ipmitool, of the configuration
+    #[must_use]
+    pub fn from_config(config: &Configuration) -> Self {
+        Self::new(&config.bmc_hostname, &config.bmc_username, &config.bmc_password, &config.ipmi)
+    }
+
+    /// `try_run_command`
+    ///
+    /// Like `run_command` below, but reports failure, whether ipmitool couldn't be run
+    /// or exited with an error, rather than panicking. Used by the pre-flight checks.
+    ///
+    /// # Errors
+    /// If the command can't be launched, or exits unsuccessfully
+    pub fn try_run_command(&self, bmc_command: &str) -> ResultType<String> {
+        let ipmi_args = format!("{self} {bmc_command}");
+        trace!("BMC running command: {self:?} {bmc_command}");
+        let ipmi_args: Vec<&str> = ipmi_args.split_whitespace().collect();
+
+        let out = Command::new(&self.ipmi)
+            .args(&ipmi_args)
+            .output()
+            .map_err(|e| format!("can't run {}: {e}", self.ipmi))?;
+        if !out.status.success() {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            return Err(format!("{bmc_command:?} failed ({}): {}", out.status, stderr.trim()).into());
         }
+        Ok(String::from_utf8_lossy(&out.stdout).to_string())
     }
 
     /// `run_command`
@@ -82,7 +150,7 @@ impl BMC {
 
         // process::Command requires arguments as an array
         let ipmi_args: Vec<&str> = ipmi_args.split_whitespace().collect();
-        let ipmi_path = &CONFIGURATION.ipmi;
+        let ipmi_path = &self.ipmi;
 
         // Launch the command
         match Command::new(ipmi_path).args(&ipmi_args).output() {
@@ -90,7 +158,7 @@ impl BMC {
                 let stdout = String::from_utf8_lossy(&out.stdout);
                 let stderr = String::from_utf8_lossy(&out.stderr);
 
-                if stderr.len() > 0 {
+                if !stderr.is_empty() {
                     error!("BMC run_command({bmc_command}) stderr: {stderr}");
                 }
 
@@ -120,6 +188,29 @@ impl BMC {
         BMC::parse_cap_settings(&bmc_output)
     }
 
+    /// As `current_cap_settings`, but fails if the BMC doesn't support DCMI power capping
+    ///
+    /// # Errors
+    /// If the command fails or its output has no power limit
+    pub fn try_current_cap_settings(&self) -> ResultType<BMC_CapSetting> {
+        let bmc_output = self.try_run_command(BMC_CAP_SETTINGS_CMD)?;
+        if !bmc_output.contains("Power Limit:") {
+            return Err(format!("no power limit in {BMC_CAP_SETTINGS_CMD:?} output").into());
+        }
+        Ok(BMC::parse_cap_settings(&bmc_output))
+    }
+
+    /// The range of power limits the BMC accepts, from the Node Manager capability
+    /// query, which not every BMC supports
+    ///
+    /// # Errors
+    /// If the command fails or its output has no power limit range
+    pub fn try_cap_range(&self) -> ResultType<BMC_CapRange> {
+        let bmc_output = self.try_run_command(BMC_CAP_CAPABILITY_CMD)?;
+        BMC::parse_cap_range(&bmc_output)
+            .ok_or_else(|| format!("no power limit range in {BMC_CAP_CAPABILITY_CMD:?} output").into())
+    }
+
     #[must_use]
     pub fn capping_is_active(&self) -> bool {
         self.current_cap_settings().is_active
@@ -131,28 +222,66 @@ impl BMC {
     }
 
     pub fn set_cap_power_level(&self, cap: u64) {
-        let cap_cmd = format!("{BMC_SET_CAP_CMD} {cap}");
-        self.run_command(&cap_cmd);
-        // TODO: check the output
+        self.apply(BMC_Action::SetLevel(cap));
     }
 
     pub fn activate_power_cap(&self) {
-        self.run_command(BMC_ACTIVATE_CAP_CMD);
+        self.apply(BMC_Action::Activate);
     }
 
     pub fn deactivate_power_cap(&self) {
-        self.run_command(BMC_DEACTIVATE_CAP_CMD);
+        self.apply(BMC_Action::Deactivate);
+    }
+
+    pub fn apply(&self, action: BMC_Action) {
+        self.run_command(&action.command());
+        // TODO: check the output
+    }
+
+    /// As `apply`, but fails if the command does, e.g. if the BMC rejects the level
+    ///
+    /// # Errors
+    /// If ipmitool can't be run or exits with an error
+    pub fn try_apply(&self, action: BMC_Action) -> ResultType<()> {
+        self.try_run_command(&action.command()).map(|_| ())
+    }
+
+    /// Sets the cap power level and activation state, e.g. to restore the settings
+    /// captured before a campaign
+    pub fn apply_cap_settings(&self, settings: &BMC_CapSetting) {
+        self.set_cap_power_level(settings.power_limit);
+        if settings.is_active {
+            self.activate_power_cap();
+        } else {
+            self.deactivate_power_cap();
+        }
     }
 
 
     // Power management
     #[must_use]
     pub fn current_power(&self) -> u64 {
-        let bmc_output = self.run_command(BMC_READ_POWER_CMD);
-        BMC::parse_power_reading(&bmc_output).instant
+        self.current_power_reading().instant
     }
 
+    /// Returns the full power reading, including the BMC's timestamp
+    #[must_use]
+    pub fn current_power_reading(&self) -> BMC_PowerReading {
+        let bmc_output = self.run_command(BMC_READ_POWER_CMD);
+        BMC::parse_power_reading(&bmc_output)
+    }
 
+    /// As `current_power_reading`, but fails if the BMC doesn't support DCMI power readings
+    ///
+    /// # Errors
+    /// If the command fails or its output has no instantaneous reading
+    pub fn try_current_power_reading(&self) -> ResultType<BMC_PowerReading> {
+        let bmc_output = self.try_run_command(BMC_READ_POWER_CMD)?;
+        if !bmc_output.contains("Instantaneous power reading") {
+            return Err(format!("no power reading in {BMC_READ_POWER_CMD:?} output").into());
+        }
+        Ok(BMC::parse_power_reading(&bmc_output))
+    }
 
 
     /// Parses a u64 from the first word in the `power_reading` string
@@ -199,7 +328,6 @@ impl BMC {
 
     /// Parses the ouptut of BMC ipmi dcmi power command, returning a `BMC_PowerReading` struct
     #[must_use]
-    #[allow(clippy::match_on_vec_items)]
     fn parse_power_reading(output: &str) -> BMC_PowerReading {
         let mut readings = BMC_PowerReading::new();
 
@@ -257,6 +385,17 @@ impl BMC {
             power_limit,
         }
     }
+
+    /// Parses the "Power Limit Range: 100 - 700 (watts)" line of the capability query
+    fn parse_cap_range(output: &str) -> Option<BMC_CapRange> {
+        let range = output.lines().find_map(|line| {
+            let (lhs, rhs) = line.split_once(':')?;
+            (lhs.trim() == "Power Limit Range").then_some(rhs)
+        })?;
+        let (minimum, maximum) = range.split_once('-')?;
+        let watts = |s: &str| s.split_ascii_whitespace().next()?.parse::<u64>().ok();
+        Some(BMC_CapRange { minimum: watts(minimum)?, maximum: watts(maximum)? })
+    }
 }
 
 impl Display for BMC {
@@ -328,4 +467,17 @@ mod tests {
         assert!(reading.is_active);
         assert_eq!(reading.power_limit, 2000);
     }
+
+    #[test]
+    fn test_parse_cap_range() {
+        let bmc_output = "
+            power policies:\t\t16
+            Power Limit Range:\t\t120 - 750 (watts)
+            Min Correction Time:\t1000 - 600000 (msec)
+            Statistics Reporting Period:\t1 - 3600 (sec)
+        ";
+
+        assert_eq!(BMC::parse_cap_range(bmc_output), Some(BMC_CapRange { minimum: 120, maximum: 750 }));
+        assert_eq!(BMC::parse_cap_range("Power Limit Range: unknown"), None);
+    }
 }
@@ -0,0 +1,699 @@
+//! Campaigns: the matrix of trials run by the driver. A campaign is either the built-in
+//! default (see `Campaign::builtin()`) or is read from a TOML file, in which any key that
+//! is left out takes its value from the built-in campaign. For example:
+//!
+//! ```toml
+//! warmup_secs = 10
+//! test_time_secs = 15
+//! repetitions = 1
+//! shuffle = true                          # run the trials in a random order...
+//! seed = 42                               # ...from this seed, or a random one if unset
+//! cap_pairs = [[400, 580], [580, 400]]    # [cap_from, cap_to]
+//! capping_orders = [
+//!     "LevelBeforeActivate", "LevelAfterActivate", "LevelToLevel",
+//!     { RepeatedToggle = { cycles = 3, interval_millis = 2000 } },  # see CappingOrder
+//!     { MultiLevel = { steps = 4, interval_millis = 1000 } },
+//!     { BelowMinimum = { watts = 10 } },
+//! ]
+//! capping_operations = ["Activate", "Deactivate"]
+//!
+//! [[loads]]
+//! load_pct = [100, 95, 90]
+//! load_period_us = [10000, 100000]
+//! n_threads = [0]                         # 0 = all available threads
+//!
+//! [[loads]]
+//! load_pct = [100]
+//! load_period_us = [0]
+//! n_threads = "decreasing"                # all cores, down to 90% of them
+//! profiles = [{ kind = "step", to_pct = 50, after_secs = 5 }]  # see driver/load_profile.rs
+//! ```
+//!
+//! The campaign is expanded into the ordered list of trials: for each cap pair, each
+//! capping order and each capping operation, every combination of each load block's
+//! load percentage, period, thread count and load profile (constant if not given), each
+//! repeated `repetitions` times. If shuffled, the whole list is then put in a random
+//! order, so that drift over the campaign (temperature, BMC state) isn't confounded
+//! with the trial parameters. The seed is recorded with each trial, so the order can
+//! be reproduced.
+//!
+//! The optional `[sweep]` table replaces the cap pairs with a sweep over many cap
+//! levels (see `driver/sweep.rs`).
+//!
+//! The optional `[search]` table sets the ranges explored by the adaptive search
+//! (see `driver/search.rs`), which is run instead of the trial matrix:
+//!
+//! ```toml
+//! [search]
+//! dimensions = ["LoadPct", "LoadPeriod", "Threads"]
+//! load_pct = [50, 100]
+//! load_pct_resolution = 1
+//! load_search_period_us = 10000           # period used while searching load_pct
+//! load_period_us = [10000, 1000000]
+//! load_period_resolution_us = 10000
+//! period_search_load_pct = 90             # load used while searching load_period_us
+//! threads_resolution = 1                  # threads are searched from 1 to all cores
+//! probe_repeats = 1                       # trials per bisection step, majority wins
+//! boundary_repeats = 3                    # trials either side of the boundary found
+//! ```
+
+use crate::driver::load_generator::{check_load_pct, check_load_period, MIN_LOAD_PERIOD_US};
+use crate::driver::load_profile::LoadProfile;
+use crate::driver::search::SearchDimension;
+use crate::driver::sweep::CapSweep;
+use crate::driver::{CappingOperation, CappingOrder};
+use crate::ResultType;
+use log::info;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::max;
+use std::fs;
+
+/// The thread counts of a load block: an explicit list, or a rule evaluated against
+/// the number of online cores when the campaign is expanded
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ThreadCounts {
+    Counts(Vec<u64>),
+    Rule(ThreadRule),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadRule {
+    /// All cores, then one fewer, ... down to all but max(1, cores / 10)
+    Decreasing,
+}
+
+/// A set of load levels, periods and thread counts, every combination of which is run
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadBlock {
+    pub load_pct: Vec<u64>,
+    pub load_period_us: Vec<u64>,
+    #[serde(default = "LoadBlock::all_threads")]
+    pub n_threads: ThreadCounts,
+    #[serde(default = "LoadBlock::constant")]
+    pub profiles: Vec<LoadProfile>,
+}
+
+impl LoadBlock {
+    fn all_threads() -> ThreadCounts {
+        ThreadCounts::Counts(vec![0])
+    }
+
+    fn constant() -> Vec<LoadProfile> {
+        vec![LoadProfile::Constant]
+    }
+
+    /// The thread counts, with any rule applied to `core_count`
+    fn thread_counts(&self, core_count: u64) -> Vec<u64> {
+        match &self.n_threads {
+            ThreadCounts::Counts(counts) => counts.clone(),
+            ThreadCounts::Rule(ThreadRule::Decreasing) => {
+                if core_count > 1 {
+                    let max_idle_threads = max(1, core_count / 10);
+                    (0..=max_idle_threads).map(|idle_threads| core_count - idle_threads).collect()
+                } else {
+                    info!("Can't run decreasing cores with only one core");
+                    Vec::new()
+                }
+            }
+        }
+    }
+}
+
+/// The ranges explored by the adaptive search, and its resolution. Missing keys take
+/// their default values.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SearchSpace {
+    pub dimensions: Vec<SearchDimension>,
+    pub load_pct: (u64, u64),
+    pub load_pct_resolution: u64,
+    pub load_search_period_us: u64,
+    pub load_period_us: (u64, u64),
+    pub load_period_resolution_us: u64,
+    pub period_search_load_pct: u64,
+    pub threads_resolution: u64,
+    /// Trials run at each bisection step, the majority verdict is taken
+    pub probe_repeats: u64,
+    /// Trials run either side of the boundary once found
+    pub boundary_repeats: u64,
+}
+
+impl Default for SearchSpace {
+    fn default() -> Self {
+        Self {
+            dimensions: vec![SearchDimension::LoadPct, SearchDimension::LoadPeriod, SearchDimension::Threads],
+            load_pct: (50, 100),
+            load_pct_resolution: 1,
+            load_search_period_us: 10_000,
+            load_period_us: (10_000, 1_000_000),
+            load_period_resolution_us: 10_000,
+            period_search_load_pct: 90,
+            threads_resolution: 1,
+            probe_repeats: 1,
+            boundary_repeats: 3,
+        }
+    }
+}
+
+impl SearchSpace {
+    fn validate(&self) -> ResultType<()> {
+        let (load_lo, load_hi) = self.load_pct;
+        let (period_lo, period_hi) = self.load_period_us;
+        if self.dimensions.is_empty() {
+            return Err("search: dimensions must not be empty".into());
+        }
+        if load_lo == 0 || load_lo >= load_hi || load_hi > 100 {
+            return Err(format!("search: load_pct [{load_lo}, {load_hi}] is not an increasing range in 1..=100").into());
+        }
+        if period_lo >= period_hi {
+            return Err(format!("search: load_period_us [{period_lo}, {period_hi}] is not an increasing range").into());
+        }
+        if self.period_search_load_pct == 0 || self.period_search_load_pct > 100 {
+            return Err(format!("search: period_search_load_pct {} is not in 1..=100", self.period_search_load_pct).into());
+        }
+        check_load_period(self.load_search_period_us).map_err(|e| format!("search: load_search_period_us: {e}"))?;
+        if period_lo < MIN_LOAD_PERIOD_US {
+            return Err(format!("search: load_period_us {period_lo} is shorter than {MIN_LOAD_PERIOD_US} µs").into());
+        }
+        if self.load_pct_resolution == 0 || self.load_period_resolution_us == 0 || self.threads_resolution == 0 {
+            return Err("search: resolutions must be greater than 0".into());
+        }
+        if self.probe_repeats == 0 || self.boundary_repeats == 0 {
+            return Err("search: probe_repeats and boundary_repeats must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Campaign {
+    pub warmup_secs: u64,
+    pub test_time_secs: u64,
+    /// Number of times each trial configuration is run
+    pub repetitions: u64,
+    /// If set, the trials are shuffled using this seed
+    pub seed: Option<u64>,
+    /// True if the seed was chosen at random, rather than given
+    pub seed_is_random: bool,
+    /// (`cap_from`, `cap_to`) pairs
+    pub cap_pairs: Vec<(u64, u64)>,
+    pub capping_orders: Vec<CappingOrder>,
+    pub capping_operations: Vec<CappingOperation>,
+    pub loads: Vec<LoadBlock>,
+    /// If set, replaces `cap_pairs` and the capping operations
+    pub sweep: Option<CapSweep>,
+    pub search: SearchSpace,
+}
+
+/// A campaign file, as read. Missing keys are taken from the built-in campaign.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CampaignFile {
+    warmup_secs: Option<u64>,
+    test_time_secs: Option<u64>,
+    repetitions: Option<u64>,
+    shuffle: Option<bool>,
+    seed: Option<u64>,
+    cap_pairs: Option<Vec<(u64, u64)>>,
+    capping_orders: Option<Vec<CappingOrder>>,
+    capping_operations: Option<Vec<CappingOperation>>,
+    loads: Option<Vec<LoadBlock>>,
+    sweep: Option<CapSweep>,
+    search: Option<SearchSpace>,
+}
+
+/// The parameters of a single trial
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrialSpec {
+    /// Unique for the campaign: the trial's index in the expanded list
+    pub trial_id: u64,
+    /// 0-based index of this run of the configuration
+    pub repetition: u64,
+    /// Seed the trials were shuffled with, if they were
+    #[serde(default)]
+    pub seed: Option<u64>,
+    pub cap_from: u64,
+    pub cap_to: u64,
+    pub capping_order: CappingOrder,
+    pub capping_operation: CappingOperation,
+    pub load_pct: u64,
+    pub load_period_us: u64,
+    /// 0 = all available threads
+    pub n_threads: u64,
+    /// How the load changes after the cap request
+    #[serde(default)]
+    pub profile: LoadProfile,
+    pub warmup_secs: u64,
+    pub test_time_secs: u64,
+}
+
+impl Campaign {
+    /// The default campaign, using the cap levels and times from the command line.
+    /// For each capping scenario, three mechanisms of load reduction are used:
+    /// * Increase the period for which the load is averaged. For example for a load running
+    ///   at 99%, calculate 99% over 10,000µs (a 100µs idle period every 10ms), 100,000µs
+    ///   and 1,000,000µs.
+    /// * Decrease the average load: 100%, 99%, 98%... 85%
+    /// * Run at 100% load over a diminishing number of threads (starting with all available).
+    #[must_use]
+    pub fn builtin(cap_low_watts: u64, cap_high_watts: u64, warmup_secs: u64, test_time_secs: u64) -> Self {
+        Self {
+            warmup_secs,
+            test_time_secs,
+            repetitions: 1,
+            seed: None,
+            seed_is_random: false,
+            cap_pairs: vec![(cap_low_watts, cap_high_watts), (cap_high_watts, cap_low_watts)],
+            capping_orders: vec![
+                CappingOrder::LevelBeforeActivate,
+                CappingOrder::LevelAfterActivate,
+                CappingOrder::LevelToLevel,
+            ],
+            capping_operations: vec![CappingOperation::Activate, CappingOperation::Deactivate],
+            loads: vec![
+                LoadBlock {
+                    load_pct: (85..=100).rev().collect(),
+                    load_period_us: vec![10_000, 100_000, 1_000_000],
+                    n_threads: LoadBlock::all_threads(),
+                    profiles: LoadBlock::constant(),
+                },
+                LoadBlock {
+                    load_pct: vec![100],
+                    load_period_us: vec![0],
+                    n_threads: ThreadCounts::Rule(ThreadRule::Decreasing),
+                    profiles: LoadBlock::constant(),
+                },
+            ],
+            sweep: None,
+            search: SearchSpace::default(),
+        }
+    }
+
+    /// Reads and validates a campaign file. Keys missing from the file are taken
+    /// from `defaults`.
+    ///
+    /// # Errors
+    /// If the file can't be read, isn't a valid campaign or fails validation
+    pub fn load(path: &str, defaults: Campaign) -> ResultType<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read campaign file {path}: {e}"))?;
+        Campaign::from_toml(&contents, defaults)
+            .map_err(|e| format!("Invalid campaign file {path}: {e}").into())
+    }
+
+    /// Parses and validates a campaign. Keys missing from `toml` are taken from `defaults`.
+    ///
+    /// # Errors
+    /// If `toml` isn't a valid campaign or fails validation
+    pub fn from_toml(toml: &str, defaults: Campaign) -> ResultType<Self> {
+        let file: CampaignFile = toml::from_str(toml)?;
+        // a seed implies shuffling; shuffling without a seed takes a random one
+        let (seed, seed_is_random) = match (file.shuffle, file.seed) {
+            (Some(false), _) => (None, false),
+            (_, Some(seed)) => (Some(seed), false),
+            (Some(true), None) => (Some(Campaign::random_seed()), true),
+            (None, None) => (defaults.seed, defaults.seed_is_random),
+        };
+        let campaign = Self {
+            warmup_secs: file.warmup_secs.unwrap_or(defaults.warmup_secs),
+            test_time_secs: file.test_time_secs.unwrap_or(defaults.test_time_secs),
+            repetitions: file.repetitions.unwrap_or(defaults.repetitions),
+            seed,
+            seed_is_random,
+            cap_pairs: file.cap_pairs.unwrap_or(defaults.cap_pairs),
+            capping_orders: file.capping_orders.unwrap_or(defaults.capping_orders),
+            capping_operations: file.capping_operations.unwrap_or(defaults.capping_operations),
+            loads: file.loads.unwrap_or(defaults.loads),
+            sweep: file.sweep.or(defaults.sweep),
+            search: file.search.unwrap_or(defaults.search),
+        };
+        campaign.validate()?;
+        Ok(campaign)
+    }
+
+    /// Checks that the campaign describes at least one trial and that every trial
+    /// can be run
+    ///
+    /// # Errors
+    /// Describing the first problem found
+    pub fn validate(&self) -> ResultType<()> {
+        if self.test_time_secs == 0 {
+            return Err("test_time_secs must be greater than 0".into());
+        }
+        if self.repetitions == 0 {
+            return Err("repetitions must be greater than 0".into());
+        }
+        if self.cap_pairs.is_empty() || self.capping_orders.is_empty()
+            || self.capping_operations.is_empty() || self.loads.is_empty()
+        {
+            return Err("cap_pairs, capping_orders, capping_operations and loads must not be empty".into());
+        }
+        if let Some((cap_from, cap_to)) = self.cap_pairs.iter().find(|(from, to)| *from == 0 || *to == 0) {
+            return Err(format!("cap pair [{cap_from}, {cap_to}]: cap levels must be greater than 0").into());
+        }
+        if let Some(sweep) = &self.sweep {
+            sweep.validate()?;
+        }
+        for capping_order in &self.capping_orders {
+            capping_order.validate()?;
+        }
+
+        for (i, load) in self.loads.iter().enumerate() {
+            if load.load_pct.is_empty() || load.load_period_us.is_empty() || load.profiles.is_empty() {
+                return Err(format!("loads[{i}]: load_pct, load_period_us and profiles must not be empty").into());
+            }
+            if let ThreadCounts::Counts(counts) = &load.n_threads {
+                if counts.is_empty() {
+                    return Err(format!("loads[{i}]: n_threads must not be empty").into());
+                }
+            }
+            for load_pct in &load.load_pct {
+                check_load_pct(*load_pct).map_err(|e| format!("loads[{i}]: {e}"))?;
+            }
+            for load_period_us in &load.load_period_us {
+                check_load_period(*load_period_us).map_err(|e| format!("loads[{i}]: {e}"))?;
+            }
+            for profile in &load.profiles {
+                profile.validate().map_err(|e| format!("loads[{i}]: {e}"))?;
+            }
+        }
+        self.search.validate()
+    }
+
+    /// Checks that the load generator can follow the load profiles: one that's
+    /// restarted for each change of load (see `LoadGenerator::changes_load_in_place`)
+    /// would dip the power many times over a ramp or burst, which the compliance
+    /// detector would take for the cap's doing
+    ///
+    /// # Errors
+    /// If a load block has a ramp or burst profile and the generator can't change its
+    /// load in place
+    pub fn validate_profiles(&self, changes_load_in_place: bool) -> ResultType<()> {
+        if changes_load_in_place {
+            return Ok(());
+        }
+        for (i, load) in self.loads.iter().enumerate() {
+            if let Some(profile) = load.profiles.iter().find(|profile| profile.changes_repeatedly()) {
+                return Err(format!("loads[{i}]: profile {profile} needs the builtin load generator").into());
+            }
+        }
+        Ok(())
+    }
+
+    /// A seed for shuffling the trials, for when none is given
+    #[must_use]
+    pub fn random_seed() -> u64 {
+        rand::random()
+    }
+
+    /// The (`cap_from`, `cap_to`) pairs run, those of the sweep if there is one
+    #[must_use]
+    pub fn cap_pairs(&self) -> Vec<(u64, u64)> {
+        self.sweep.as_ref().map_or_else(|| self.cap_pairs.clone(), CapSweep::cap_pairs)
+    }
+
+    /// The capping operations run: a sweep caps to each of its levels, so only activates
+    #[must_use]
+    pub fn capping_operations(&self) -> Vec<CappingOperation> {
+        if self.sweep.is_some() {
+            vec![CappingOperation::Activate]
+        } else {
+            self.capping_operations.clone()
+        }
+    }
+
+    /// Expands the campaign into the ordered list of trials, shuffled if the campaign
+    /// has a seed. Trial ids follow the order the trials are run in.
+    ///
+    /// # Arguments
+    /// * `core_count` - number of online cores, for the thread count rules
+    #[must_use]
+    pub fn trials(&self, core_count: u64) -> Vec<TrialSpec> {
+        let mut trials = Vec::new();
+        for (cap_from, cap_to) in &self.cap_pairs() {
+            for capping_order in &self.capping_orders {
+                for capping_operation in &self.capping_operations() {
+                    // e.g. no sense in running Level after Activate when operation is Deactivate
+                    if !capping_order.runs_with(*capping_operation) {
+                        continue;
+                    }
+                    for load in &self.loads {
+                        let thread_counts = load.thread_counts(core_count);
+                        for load_pct in &load.load_pct {
+                            for load_period_us in &load.load_period_us {
+                                for n_threads in &thread_counts {
+                                    for profile in &load.profiles {
+                                        for repetition in 0..self.repetitions {
+                                            trials.push(TrialSpec {
+                                                trial_id: trials.len() as u64,
+                                                repetition,
+                                                seed: self.seed,
+                                                cap_from: *cap_from,
+                                                cap_to: *cap_to,
+                                                capping_order: *capping_order,
+                                                capping_operation: *capping_operation,
+                                                load_pct: *load_pct,
+                                                load_period_us: *load_period_us,
+                                                n_threads: *n_threads,
+                                                profile: *profile,
+                                                warmup_secs: self.warmup_secs,
+                                                test_time_secs: self.test_time_secs,
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(seed) = self.seed {
+            trials.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+            for (trial_id, trial) in trials.iter_mut().enumerate() {
+                trial.trial_id = trial_id as u64;
+            }
+        }
+        trials
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builtin() -> Campaign {
+        Campaign::builtin(400, 580, 10, 15)
+    }
+
+    #[test]
+    fn test_builtin_campaign() {
+        let campaign = builtin();
+        assert!(campaign.validate().is_ok());
+
+        // 2 cap pairs * 5 scenarios * (16 loads * 3 periods + 4 thread counts)
+        let trials = campaign.trials(32);
+        assert_eq!(trials.len(), 2 * 5 * (16 * 3 + 4));
+        assert_eq!(trials[0], TrialSpec {
+            trial_id: 0,
+            repetition: 0,
+            seed: None,
+            cap_from: 400,
+            cap_to: 580,
+            capping_order: CappingOrder::LevelBeforeActivate,
+            capping_operation: CappingOperation::Activate,
+            load_pct: 100,
+            load_period_us: 10_000,
+            n_threads: 0,
+            profile: LoadProfile::Constant,
+            warmup_secs: 10,
+            test_time_secs: 15,
+        });
+        assert_eq!((trials[1].load_pct, trials[1].load_period_us), (100, 100_000));
+        assert_eq!((trials[3].load_pct, trials[3].load_period_us), (99, 10_000));
+        let thread_counts: Vec<u64> = trials[48..52].iter().map(|t| t.n_threads).collect();
+        assert_eq!(thread_counts, [32, 31, 30, 29]);
+        assert!(trials.iter().enumerate().all(|(i, t)| t.trial_id == i as u64));
+
+        // on a single core machine, there are no decreasing thread trials
+        assert_eq!(campaign.trials(1).len(), 2 * 5 * 16 * 3);
+    }
+
+    #[test]
+    fn test_campaign_file() {
+        let campaign = Campaign::from_toml(r#"
+            repetitions = 2
+            cap_pairs = [[500, 300]]
+            capping_orders = ["LevelToLevel"]
+
+            [[loads]]
+            load_pct = [90, 80]
+            load_period_us = [0]
+            n_threads = [4]
+            "#,
+            builtin(),
+        ).unwrap();
+
+        assert_eq!(campaign.warmup_secs, 10);
+        assert_eq!(campaign.search, SearchSpace::default());
+        assert_eq!(campaign.capping_operations, [CappingOperation::Activate, CappingOperation::Deactivate]);
+
+        let trials = campaign.trials(8);
+        let summary: Vec<(CappingOperation, u64, u64)> = trials
+            .iter()
+            .map(|t| (t.capping_operation, t.load_pct, t.repetition))
+            .collect();
+        assert_eq!(summary, [
+            (CappingOperation::Activate, 90, 0),
+            (CappingOperation::Activate, 90, 1),
+            (CappingOperation::Activate, 80, 0),
+            (CappingOperation::Activate, 80, 1),
+            (CappingOperation::Deactivate, 90, 0),
+            (CappingOperation::Deactivate, 90, 1),
+            (CappingOperation::Deactivate, 80, 0),
+            (CappingOperation::Deactivate, 80, 1),
+        ]);
+        assert!(trials.iter().all(|t| (t.cap_from, t.cap_to, t.n_threads) == (500, 300, 4)));
+    }
+
+    #[test]
+    fn test_shuffled_campaign() {
+        let ordered = Campaign::from_toml("repetitions = 3", builtin()).unwrap().trials(8);
+        let campaign = Campaign::from_toml("repetitions = 3\nseed = 42", builtin()).unwrap();
+        let shuffled = campaign.trials(8);
+
+        // the same trials, in a different order that is reproducible from the seed
+        assert_ne!(shuffled, ordered);
+        assert_eq!(shuffled, campaign.trials(8));
+        assert!(shuffled.iter().enumerate().all(|(i, t)| t.trial_id == i as u64 && t.seed == Some(42)));
+        let unordered = |trials: &[TrialSpec]| {
+            let mut trials: Vec<TrialSpec> = trials.iter().map(|t| TrialSpec { trial_id: 0, seed: None, ..t.clone() }).collect();
+            trials.sort_by_key(|t| format!("{t:?}"));
+            trials
+        };
+        assert_eq!(unordered(&shuffled), unordered(&ordered));
+
+        let campaign = Campaign::from_toml("shuffle = true", builtin()).unwrap();
+        assert!(campaign.seed.is_some() && campaign.seed_is_random);
+        assert!(!Campaign::from_toml("shuffle = true\nseed = 42", builtin()).unwrap().seed_is_random);
+        assert_eq!(Campaign::from_toml("shuffle = false\nseed = 42", builtin()).unwrap().seed, None);
+    }
+
+    #[test]
+    fn test_load_profiles() {
+        let campaign = Campaign::from_toml(r#"
+            cap_pairs = [[500, 300]]
+            capping_orders = ["LevelToLevel"]
+            capping_operations = ["Activate"]
+
+            [[loads]]
+            load_pct = [90]
+            load_period_us = [10000]
+            profiles = [
+                { kind = "constant" },
+                { kind = "step", to_pct = 50, after_secs = 5 },
+                { kind = "burst", low_pct = 10, on_secs = 2, off_secs = 3 },
+            ]
+            "#,
+            builtin(),
+        ).unwrap();
+        let profiles: Vec<String> = campaign.trials(8).iter().map(|t| t.profile.to_string()).collect();
+        assert_eq!(profiles, ["constant", "step:50@5", "burst:10:2/3"]);
+        // the burst is only followed by a generator that changes its load in place
+        assert!(campaign.validate_profiles(true).is_ok());
+        assert!(campaign.validate_profiles(false).is_err());
+        assert!(builtin().validate_profiles(false).is_ok());
+
+        // trials recorded before profiles were added are constant
+        let trial: TrialSpec = serde_json::from_str(r#"{
+            "trial_id": 0, "repetition": 0, "cap_from": 500, "cap_to": 300,
+            "capping_order": "LevelToLevel", "capping_operation": "Activate",
+            "load_pct": 90, "load_period_us": 10000, "n_threads": 0, "warmup_secs": 10, "test_time_secs": 15
+        }"#).unwrap();
+        assert_eq!(trial.profile, LoadProfile::Constant);
+
+        for profiles in [
+            "[]",
+            r#"[{ kind = "step", to_pct = 0, after_secs = 5 }]"#,
+            r#"[{ kind = "step", to_pct = 50 }]"#,
+            r#"[{ kind = "spike", to_pct = 50, after_secs = 5 }]"#,
+        ] {
+            let toml = format!("[[loads]]\nload_pct = [90]\nload_period_us = [10000]\nprofiles = {profiles}");
+            assert!(Campaign::from_toml(&toml, builtin()).is_err(), "{profiles}");
+        }
+        // a period is in µs, whatever the levels the profile changes the load to
+        let toml = "[[loads]]\nload_pct = [50]\nload_period_us = [60]\nprofiles = [{ kind = \"step\", to_pct = 90, after_secs = 5 }]";
+        assert!(Campaign::from_toml(toml, builtin()).is_ok());
+    }
+
+    #[test]
+    fn test_capping_sequences() {
+        let campaign = Campaign::from_toml(r#"
+            cap_pairs = [[500, 300]]
+            capping_orders = [
+                { RepeatedToggle = { cycles = 2, interval_millis = 1000 } },
+                { MultiLevel = { steps = 4, interval_millis = 500 } },
+                { BelowMinimum = { watts = 10 } },
+            ]
+            capping_operations = ["Activate", "Deactivate"]
+
+            [[loads]]
+            load_pct = [90]
+            load_period_us = [10000]
+            "#,
+            builtin(),
+        ).unwrap();
+        let orders: Vec<String> = campaign.trials(8).iter()
+            .map(|t| format!("{} {}", t.capping_order, t.capping_operation))
+            .collect();
+        assert_eq!(orders, [
+            "RepeatedToggle:2x1000ms Activate",
+            "RepeatedToggle:2x1000ms Deactivate",
+            "MultiLevel:4x500ms Activate",
+            "BelowMinimum:10W Activate",
+        ]);
+    }
+
+    #[test]
+    fn test_search_space() {
+        let campaign = Campaign::from_toml(r#"
+            [search]
+            dimensions = ["Threads"]
+            boundary_repeats = 5
+            "#,
+            builtin(),
+        ).unwrap();
+        assert_eq!(campaign.search.dimensions, [SearchDimension::Threads]);
+        assert_eq!(campaign.search.boundary_repeats, 5);
+        assert_eq!(campaign.search.load_pct, SearchSpace::default().load_pct);
+    }
+
+    #[test]
+    fn test_invalid_campaign_files() {
+        for toml in [
+            "repetitions = 0",
+            "cap_pairs = []",
+            "cap_pairs = [[0, 400]]",
+            "capping_orders = [\"Sideways\"]",
+            "capping_orders = [{ RepeatedToggle = { cycles = 0, interval_millis = 1000 } }]",
+            "capping_orders = [{ MultiLevel = { steps = 0, interval_millis = 1000 } }]",
+            "unknown_key = 1",
+            "[[loads]]\nload_pct = [101]\nload_period_us = [0]",
+            "[[loads]]\nload_pct = [90]\nload_period_us = [5]",
+            "[[loads]]\nload_pct = [90]\nload_period_us = [0]\nn_threads = []",
+            "[[loads]]\nload_pct = [90]\nload_period_us = [0]\nn_threads = \"increasing\"",
+            "[search]\nload_pct = [100, 50]",
+            "[search]\nprobe_repeats = 0",
+            "[search]\ndimensions = [\"Voltage\"]",
+        ] {
+            assert!(Campaign::from_toml(toml, builtin()).is_err(), "accepted: {toml}");
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Pre-flight checks (the `check` subcommand): verifies the environment a campaign
+//! depends on, so that a wrong path, an unreachable BMC or a permissions problem is
+//! reported up front rather than after the first warmup.
+
+use crate::bmc::{BMC, BMC_CapRange};
+use crate::cli::Configuration;
+use crate::driver::load_generator;
+use crate::rapl::RAPL;
+use crate::topology::format_cpu_list;
+use crate::ResultType;
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            Status::Ok => "[ OK ]",
+            Status::Warn => "[WARN]",
+            Status::Fail => "[FAIL]",
+        })
+    }
+}
+
+/// The outcome of a single check
+#[derive(Debug)]
+pub struct Check {
+    pub status: Status,
+    pub name: String,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(status: Status, name: &str, detail: &str) -> Self {
+        Self { status, name: String::from(name), detail: String::from(detail) }
+    }
+
+    /// A check that passes with `ok_detail`, or fails with the error
+    fn from_result<T>(name: &str, result: &ResultType<T>, ok_detail: impl FnOnce(&T) -> String) -> Self {
+        match result {
+            Ok(value) => Check::new(Status::Ok, name, &ok_detail(value)),
+            Err(e) => Check::new(Status::Fail, name, &e.to_string()),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.name, self.detail)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// True unless a check failed; warnings don't stop a campaign
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.status != Status::Fail)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "{check}")?;
+        }
+        let failures = self.checks.iter().filter(|check| check.status == Status::Fail).count();
+        let warnings = self.checks.iter().filter(|check| check.status == Status::Warn).count();
+        write!(f, "{} checks: {failures} failed, {warnings} warnings", self.checks.len())
+    }
+}
+
+/// Runs all the checks. Checks that depend on an earlier one failing (e.g. the BMC
+/// when ipmitool is missing) are reported as failed with the reason.
+#[must_use]
+pub fn run_checks(config: &Configuration) -> Report {
+    let mut report = Report::default();
+
+    let ipmi = find_executable(&config.ipmi);
+    report.checks.push(Check::from_result("ipmi executable", &ipmi, |path| path.display().to_string()));
+    report.checks.push(match load_generator::from_config(config) {
+        Ok(generator) => match generator.executable() {
+            Some(executable) => Check::from_result(
+                "load generator executable",
+                &find_executable(executable),
+                |path| format!("{} ({})", path.display(), config.load_generator),
+            ),
+            None => Check::new(Status::Ok, "load generator", &config.load_generator.to_string()),
+        },
+        Err(e) => Check::new(Status::Fail, "load generator", &e.to_string()),
+    });
+
+    let bmc = BMC::from_config(config);
+    let reading = if ipmi.is_ok() {
+        bmc.try_current_power_reading()
+    } else {
+        Err("ipmi executable not found".into())
+    };
+    report.checks.push(Check::from_result("BMC DCMI power reading", &reading, |reading| {
+        format!("{} ({}) reports {} W", config.bmc_hostname, reading.timestamp, reading.instant)
+    }));
+    let cap_settings = if reading.is_ok() {
+        bmc.try_current_cap_settings()
+    } else {
+        Err("BMC power reading failed".into())
+    };
+    let mut capping = Check::from_result("BMC DCMI power capping", &cap_settings, |settings| {
+        format!("limit {} W, {}", settings.power_limit, if settings.is_active { "active" } else { "inactive" })
+    });
+    // an active cap skews the first trials' baseline, though it's restored at exit
+    if matches!(cap_settings, Ok(settings) if settings.is_active) {
+        capping.status = Status::Warn;
+    }
+    report.checks.push(capping);
+
+    report.checks.push(Check::from_result("RAPL files", &check_rapl_files(), |n| format!("{n} files readable")));
+    report.checks.push(Check::from_result("stats directory", &check_stats_dir(Path::new(&config.stats_dir)), |()| {
+        format!("{} is writable", config.stats_dir)
+    }));
+
+    // every CPU the placement allows, each trial taking the first of them
+    report.checks.push(Check::from_result("thread placement", &config.placement.place(0), |cpus| match cpus {
+        Some(cpus) => format!("{} on CPUs {}", config.placement, format_cpu_list(cpus, ",")),
+        None => String::from("threads not bound"),
+    }));
+
+    report.checks.push(check_cap_order(config.cap_low_watts, config.cap_high_watts));
+    // a sweep's levels replace the caps
+    let (cap_low, cap_high) = match &config.sweep {
+        Some(sweep) => {
+            let levels = sweep.cap_pairs().into_iter().flat_map(|(from, to)| [from, to]);
+            (levels.clone().min().unwrap_or(0), levels.max().unwrap_or(0))
+        }
+        None => (config.cap_low_watts, config.cap_high_watts),
+    };
+    report.checks.push(match &cap_settings {
+        Ok(_) => match bmc.try_cap_range() {
+            Ok(range) => check_cap_range(cap_low, cap_high, &range),
+            // the range comes from Node Manager, which not every BMC has
+            Err(e) => Check::new(Status::Warn, "caps in BMC range", &format!("BMC cap range unknown: {e}")),
+        },
+        Err(_) => Check::new(Status::Fail, "caps in BMC range", "BMC power capping failed"),
+    });
+    report
+}
+
+/// Resolves `path` as `Command` would, searching `PATH` for a bare name, and
+/// checks that it's an executable file
+fn find_executable(path: &str) -> ResultType<PathBuf> {
+    let candidates: Vec<PathBuf> = if path.contains('/') {
+        vec![PathBuf::from(path)]
+    } else {
+        env::split_paths(&env::var_os("PATH").unwrap_or_default())
+            .map(|dir| dir.join(path))
+            .collect()
+    };
+
+    for candidate in candidates {
+        if let Ok(metadata) = fs::metadata(&candidate) {
+            if !metadata.is_file() {
+                return Err(format!("{} is not a file", candidate.display()).into());
+            }
+            if metadata.permissions().mode() & 0o111 == 0 {
+                return Err(format!("{} is not executable", candidate.display()).into());
+            }
+            return Ok(candidate);
+        }
+    }
+    Err(format!("{path} not found").into())
+}
+
+/// Reads every file the RAPL monitor reads, returning how many there are
+fn check_rapl_files() -> ResultType<usize> {
+    if RAPL::domain_count() == 0 {
+        return Err("no RAPL domains found".into());
+    }
+    let files = RAPL::new().files();
+    for file in &files {
+        let contents = fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+        contents.trim().parse::<u64>().map_err(|e| format!("{}: {e}", file.display()))?;
+    }
+    Ok(files.len())
+}
+
+/// Creates the stats directory if need be and writes (then removes) a file in it
+fn check_stats_dir(stats_dir: &Path) -> ResultType<()> {
+    fs::create_dir_all(stats_dir).map_err(|e| format!("can't create {}: {e}", stats_dir.display()))?;
+    let probe = stats_dir.join(format!(".check_{}", std::process::id()));
+    fs::write(&probe, "").map_err(|e| format!("can't write to {}: {e}", stats_dir.display()))?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+fn check_cap_order(cap_low: u64, cap_high: u64) -> Check {
+    let name = "cap order";
+    if cap_low < cap_high {
+        Check::new(Status::Ok, name, &format!("low cap {cap_low} W < high cap {cap_high} W"))
+    } else {
+        Check::new(Status::Fail, name, &format!("low cap {cap_low} W must be below high cap {cap_high} W"))
+    }
+}
+
+/// Checks the caps against the range of limits the BMC accepts: it would reject a cap
+/// outside it
+fn check_cap_range(cap_low: u64, cap_high: u64, cap_range: &BMC_CapRange) -> Check {
+    let name = "caps in BMC range";
+    let range = format!("{}-{} W", cap_range.minimum, cap_range.maximum);
+    let outside: Vec<String> = [("low", cap_low), ("high", cap_high)]
+        .iter()
+        .filter(|(_, cap)| *cap < cap_range.minimum || *cap > cap_range.maximum)
+        .map(|(label, cap)| format!("{label} cap {cap} W"))
+        .collect();
+
+    if outside.is_empty() {
+        Check::new(Status::Ok, name, &format!("both caps within {range}"))
+    } else {
+        Check::new(Status::Fail, name, &format!("{} outside {range}", outside.join(" and ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_executable() {
+        assert_eq!(find_executable("/bin/sh").unwrap(), PathBuf::from("/bin/sh"));
+        assert!(find_executable("sh").is_ok());
+        assert!(find_executable("/no/such/ipmitool").is_err());
+        assert!(find_executable("/").is_err());
+
+        let not_executable = env::temp_dir().join(format!("check_test_exe_{}", std::process::id()));
+        fs::write(&not_executable, "").unwrap();
+        assert!(find_executable(not_executable.to_str().unwrap()).is_err());
+        fs::remove_file(not_executable).unwrap();
+    }
+
+    #[test]
+    fn test_check_caps() {
+        assert_eq!(check_cap_order(400, 580).status, Status::Ok);
+        assert_eq!(check_cap_order(580, 580).status, Status::Fail);
+
+        let cap_range = BMC_CapRange { minimum: 300, maximum: 600 };
+        assert_eq!(check_cap_range(400, 580, &cap_range).status, Status::Ok);
+        let check = check_cap_range(200, 700, &cap_range);
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check.detail, "low cap 200 W and high cap 700 W outside 300-600 W");
+    }
+
+    #[test]
+    fn test_check_stats_dir() {
+        let dir = env::temp_dir().join(format!("check_test_{}", std::process::id())).join("stats");
+        assert!(check_stats_dir(&dir).is_ok());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::default();
+        report.checks.push(Check::new(Status::Ok, "a", "fine"));
+        report.checks.push(Check::new(Status::Warn, "b", "hmm"));
+        assert!(report.passed());
+        report.checks.push(Check::new(Status::Fail, "c", "broken"));
+        assert!(!report.passed());
+        assert_eq!(report.to_string(), "[ OK ] a: fine\n[WARN] b: hmm\n[FAIL] c: broken\n3 checks: 1 failed, 1 warnings");
+    }
+}
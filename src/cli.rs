@@ -1,32 +1,34 @@
-use ::clap::Parser;
-use lazy_static::lazy_static;
+use ::clap::error::ErrorKind;
+use ::clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use ::clap::parser::ValueSource;
 use chrono::Local;
+use std::ffi::OsString;
+use crate::driver::journal::Journal;
+use crate::driver::builtin_load::LoadKernel;
+use crate::driver::load_generator::LoadGeneratorKind;
+use crate::driver::sweep::CapSweep;
+use crate::settings::{self, ConfigFile, Setting, CONFIG_KEY, PROFILE_KEY};
+use crate::topology::Placement;
 
 const BMC_STATS_FILENAME_PREFIX: &str = "bmc_stats";
 const RAPL_STATS_FILENAME_PREFIX: &str = "rapl_stats";
+const CPU_STATS_FILENAME_PREFIX: &str = "cpu_stats";
 const DRIVER_LOG_FILENAME_PREFIX: &str = "driver_log";
-const MONITOR_POLL_FREQ_HZ: u64 = 4;
+const EVENTS_FILENAME_PREFIX: &str = "trial_events";
+const MONITOR_SUMMARY_FILENAME_PREFIX: &str = "monitor_summary";
+const SEARCH_RESULTS_FILENAME_PREFIX: &str = "search_results";
+const JOURNAL_FILENAME_PREFIX: &str = "campaign_journal";
+const LOAD_OUTPUT_FILENAME_PREFIX: &str = "load_output";
+const COMPLIANCE_CURVE_FILENAME_PREFIX: &str = "compliance_curve";
+const TRIAL_SUMMARY_FILENAME_PREFIX: &str = "trial_summary";
+const EFFECTIVE_CONFIG_FILENAME_PREFIX: &str = "effective_config";
+const CONFIG_ARG: &str = "config file";
+const PROFILE_ARG: &str = "profile";
 
-lazy_static! {
-    /*
-        Global configuration variable.
-
-        Lazy-static creates singleton (one-off) types that wraps a value
-        providing single initialization and thread-safety.
-
-        For a given: static ref NAME: TYPE = EXPR;
-        The lazy_static macro creates a unique type that implements
-        Deref<TYPE> and stores it in a static with name NAME.
-
-        It is the wrapped value that implements any traits (eg Debug, Clone),
-        NOT the wrapper. Because of this, must deref (*NAME) when debug/trace
-        printing.
-    */
-
-    pub static ref CONFIGURATION: Configuration = Configuration::new();
-}
-
-#[derive(Debug)]
+/// The runtime configuration. Built from the command line by `main()`, or by a
+/// program embedding the crate, and passed to the driver, trials and monitors, which
+/// share it behind an `Arc`.
+#[derive(Debug, Clone)]
 pub struct Configuration {
     pub bmc_hostname: String,
     pub bmc_username: String,
@@ -38,36 +40,221 @@ pub struct Configuration {
     pub stats_dir: String,
     pub bmc_stats_filename_prefix: String,
     pub rapl_stats_filename_prefix: String,
+    pub cpu_stats_filename_prefix: String,
     pub driver_log_filename_prefix: String,
-    pub monitor_poll_freq_hz: u64,
+    pub events_filename_prefix: String,
+    pub monitor_summary_filename_prefix: String,
+    pub search_results_filename_prefix: String,
+    pub journal_filename_prefix: String,
+    pub load_output_filename_prefix: String,
+    pub compliance_curve_filename_prefix: String,
+    pub trial_summary_filename_prefix: String,
+    pub effective_config_filename_prefix: String,
+    pub bmc_poll_interval_millis: u64,
+    pub rapl_poll_interval_millis: u64,
+    pub cpu_poll_interval_millis: u64,
+    pub bmc_inter_command_millis: u64,
+    pub setup_pause_millis: u64,
+    pub metrics_addr: Option<String>,
+    pub campaign: Option<String>,
+    pub search: bool,
+    pub sweep: Option<CapSweep>,
+    pub resume: bool,
+    pub dry_run: bool,
+    pub repetitions: Option<u64>,
+    pub shuffle: bool,
+    pub seed: Option<u64>,
+    pub command: Command,
     pub test_timestamp: String,
+    pub load_generator: LoadGeneratorKind,
     pub firestarter: String,
+    pub stress_ng: String,
+    pub load_command: Option<String>,
+    pub load_kernel: LoadKernel,
+    pub placement: Placement,
     pub ipmi: String,
+    pub config_file: Option<String>,
+    /// The config file's profile applied, if any
+    pub profile: Option<String>,
+    /// The effective value of each option, and where it came from (see `settings.rs`)
+    pub settings: Vec<Setting>,
 }
 
 impl Configuration {
-    fn new() -> Self {
-        let args = CLI::parse();
+    /// From the process's command-line arguments, over the settings of the config file
+    /// and the environment (see `settings.rs`), exiting with a usage message if they
+    /// can't be parsed
+    #[must_use]
+    pub fn from_args() -> Self {
+        Self::try_from_sources(std::env::args_os(), |var| std::env::var(var).ok(), settings::hostname().as_deref())
+            .unwrap_or_else(|e| e.exit())
+    }
+
+    /// From the given command-line arguments, the first being the program name, over
+    /// the settings of the config file they give, if any. Unlike `from_args`, the
+    /// process's environment and host name aren't taken into account.
+    ///
+    /// # Errors
+    /// If the arguments or config file can't be parsed, or `--help` or `--version` is
+    /// given
+    pub fn try_from_args<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        Self::try_from_sources(args, |_| None, None)
+    }
+
+    /// From the given command-line arguments, over the settings of the config file,
+    /// its profile, defaulting to the one named `hostname`, and the environment,
+    /// looked up by `env`
+    fn try_from_sources<I, T>(args: I, env: impl Fn(&str) -> Option<String>, hostname: Option<&str>) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let setting_error = |e: Box<dyn std::error::Error>| CLI::command().error(ErrorKind::InvalidValue, e);
+
+        // The config file and profile are needed before the other options can be parsed
+        let prescan = CLI::command().ignore_errors(true).try_get_matches_from(&args).ok();
+        let chosen = |id: &str, key: &str| {
+            prescan.as_ref().and_then(|m| m.get_one::<String>(id).cloned()).or_else(|| env(&settings::env_var(key)))
+        };
+        let config_file = chosen(CONFIG_ARG, CONFIG_KEY);
+        let profile = chosen(PROFILE_ARG, PROFILE_KEY);
+        let file = config_file.as_deref().map(ConfigFile::load).transpose().map_err(setting_error)?;
+
+        let mut keys = settings::keys(&CLI::command());
+        keys.retain(|key| key != CONFIG_KEY && key != PROFILE_KEY);
+        let layers = settings::layers(file.as_ref(), profile.as_deref(), hostname, &keys, env).map_err(setting_error)?;
+        let profile = layers.values().find_map(|setting| match &setting.origin {
+            settings::Origin::Profile(name) => Some(name.clone()),
+            _ => None,
+        }).or(profile);
+
+        let command = settings::with_defaults(CLI::command(), &layers).map_err(setting_error)?;
+        let matches = command.clone().try_get_matches_from(&args)?;
+        // the run options before a subcommand are only taken by the default run
+        if matches.subcommand().is_some() {
+            let before = command.get_arguments().filter(|arg| !arg.is_global_set()).find(|arg| {
+                matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+            });
+            if let Some(arg) = before {
+                return Err(command.clone().error(
+                    ErrorKind::ArgumentConflict,
+                    format!("--{} is a run option, given before a subcommand", arg.get_long().unwrap_or_default()),
+                ));
+            }
+        }
+        let mut config = Self::from_cli(CLI::from_arg_matches(&matches)?)?;
+        config.config_file = config_file;
+        config.profile = profile;
+        config.settings = settings::effective(&command, &matches, &layers);
+        Ok(config)
+    }
+
+    /// The command-line defaults of the `run` command, for the given BMC, whatever the
+    /// environment. The fields can then be set as needed, e.g. by a program embedding
+    /// the crate or by tests.
+    ///
+    /// # Panics
+    /// Never: the defaults always parse
+    #[must_use]
+    pub fn for_bmc(hostname: &str, username: &str, password: &str) -> Self {
+        Self::try_from_args([env!("CARGO_PKG_NAME"), "-H", hostname, "-U", username, "-P", password, "run"])
+            .expect("Failed to build the default configuration")
+    }
+
+    fn from_cli(args: CLI) -> Result<Self, clap::Error> {
+        let (command, run) = match args.command {
+            None => (Command::Run, args.run),
+            Some(CliCommand::Run(run)) => (Command::Run, run),
+            Some(CliCommand::Monitor) => (Command::Monitor, RunArgs::default()),
+            Some(CliCommand::Bmc { action }) => (Command::Bmc(action), RunArgs::default()),
+            Some(CliCommand::Analyze { timestamp }) => (Command::Analyze { timestamp }, RunArgs::default()),
+            Some(CliCommand::Check(run)) => (Command::Check, run),
+        };
+        // only the analysis of the stats files does without the BMC
+        let (bmc_hostname, bmc_username, bmc_password) = match (args.bmc_hostname, args.bmc_username, args.bmc_password) {
+            (Some(hostname), Some(username), Some(password)) => (hostname, username, password),
+            _ if matches!(command, Command::Analyze { .. }) => (String::new(), String::new(), String::new()),
+            _ => {
+                return Err(CLI::command().error(
+                    ErrorKind::MissingRequiredArgument,
+                    "the BMC's --bmc-hostname, --bmc-username and --bmc-password are required",
+                ));
+            }
+        };
+
+        // the BMC monitor reads the cap this long after the power, within each poll
+        if args.bmc_inter_command_millis >= args.bmc_poll_millis {
+            return Err(CLI::command().error(
+                ErrorKind::ValueValidation,
+                "--bmc-inter-command-millis must be shorter than --bmc-poll-millis",
+            ));
+        }
+
         let timestamp_format = "%y%m%d_%H%M";
-        let test_timestamp = Local::now().format(timestamp_format).to_string();
-
-        Configuration {
-            bmc_hostname: args.bmc_hostname,
-            bmc_username: args.bmc_username,
-            bmc_password: args.bmc_password,
-            warmup_secs: args.warmup,
-            test_time_secs: args.test_time,
-            cap_low_watts: args.cap_low_watts,
-            cap_high_watts: args.cap_high_watts,
+        // A resumed campaign carries on with the timestamp, and so the stats files, of
+        // the run it resumes
+        let test_timestamp = if run.resume && command == Command::Run {
+            Journal::latest_timestamp(&args.stats_dir, JOURNAL_FILENAME_PREFIX).ok_or_else(|| CLI::command().error(
+                ErrorKind::ValueValidation,
+                format!("--resume found no campaign journal in {}", args.stats_dir),
+            ))?
+        } else {
+            Local::now().format(timestamp_format).to_string()
+        };
+
+        Ok(Configuration {
+            bmc_hostname,
+            bmc_username,
+            bmc_password,
+            warmup_secs: run.warmup,
+            test_time_secs: run.test_time,
+            cap_low_watts: run.cap_low_watts,
+            cap_high_watts: run.cap_high_watts,
             stats_dir: args.stats_dir,
             bmc_stats_filename_prefix: String::from(BMC_STATS_FILENAME_PREFIX),
             rapl_stats_filename_prefix: String::from(RAPL_STATS_FILENAME_PREFIX),
+            cpu_stats_filename_prefix: String::from(CPU_STATS_FILENAME_PREFIX),
             driver_log_filename_prefix: String::from(DRIVER_LOG_FILENAME_PREFIX),
-            monitor_poll_freq_hz: MONITOR_POLL_FREQ_HZ,
+            events_filename_prefix: String::from(EVENTS_FILENAME_PREFIX),
+            monitor_summary_filename_prefix: String::from(MONITOR_SUMMARY_FILENAME_PREFIX),
+            search_results_filename_prefix: String::from(SEARCH_RESULTS_FILENAME_PREFIX),
+            journal_filename_prefix: String::from(JOURNAL_FILENAME_PREFIX),
+            load_output_filename_prefix: String::from(LOAD_OUTPUT_FILENAME_PREFIX),
+            compliance_curve_filename_prefix: String::from(COMPLIANCE_CURVE_FILENAME_PREFIX),
+            trial_summary_filename_prefix: String::from(TRIAL_SUMMARY_FILENAME_PREFIX),
+            effective_config_filename_prefix: String::from(EFFECTIVE_CONFIG_FILENAME_PREFIX),
+            bmc_poll_interval_millis: args.bmc_poll_millis,
+            rapl_poll_interval_millis: args.rapl_poll_millis,
+            cpu_poll_interval_millis: args.cpu_poll_millis,
+            bmc_inter_command_millis: args.bmc_inter_command_millis,
+            setup_pause_millis: run.setup_pause_millis,
+            metrics_addr: args.metrics_addr,
+            campaign: run.campaign,
+            search: run.search,
+            sweep: run.sweep,
+            resume: run.resume,
+            dry_run: run.dry_run,
+            repetitions: run.repetitions,
+            shuffle: run.shuffle,
+            seed: run.seed,
+            command,
             test_timestamp,
-            firestarter: args.firestarter,
+            load_generator: run.load_generator,
+            firestarter: run.firestarter,
+            stress_ng: run.stress_ng,
+            load_command: run.load_command,
+            load_kernel: run.load_kernel,
+            placement: run.placement,
             ipmi: args.ipmi,
-        }
+            config_file: args.config,
+            profile: args.profile,
+            settings: Vec::new(),
+        })
     }
 }
 
@@ -78,20 +265,171 @@ impl Configuration {
         update the Configuration structure (and its implementation) too.
 */
 
+/// What the program does, as given by the subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Run the campaign, or the search or sweep in its place
+    Run,
+    /// Only record the BMC, RAPL and CPU stats, until interrupted
+    Monitor,
+    /// Read or change the BMC's cap, then exit
+    Bmc(BmcCommand),
+    /// Summarise the trials of an earlier run from its stats files, then exit
+    Analyze { timestamp: Option<String> },
+    /// Check the executables, BMC, RAPL files, stats directory and caps, then exit
+    Check,
+}
+
+/// The actions of the `bmc` subcommand
+#[derive(Subcommand, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BmcCommand {
+    /// Print the cap level and whether it's active
+    Get,
+    /// Set the cap level, without changing whether it's active
+    Set {
+        /// The cap level, in W
+        watts: u64,
+    },
+    /// Activate the cap
+    Activate,
+    /// Deactivate the cap
+    Deactivate,
+    /// Print the current power reading
+    Power,
+}
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Parser)]
-#[command(author, version, about, long_about=None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Without a subcommand, runs the campaign as `run` does, taking the run options"
+)]
 struct CLI {
-    // Passing default values here for the tests - to to deleted
-    #[arg(long, short = 'H', name = "host")]
-    bmc_hostname: String,
+    #[arg(
+        long,
+        global = true,
+        name = "config file",
+        help = "TOML file of settings for every host and per-host [profiles.NAME], under the command line and CAPPING_* environment variables (also CAPPING_CONFIG)"
+    )]
+    config: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        name = "profile",
+        help = "Profile of the config file to apply, by default the one named after this host, if any (also CAPPING_PROFILE)"
+    )]
+    profile: Option<String>,
+
+    #[arg(long, short = 'H', name = "host", global = true, help = "BMC hostname, required by every command but analyze")]
+    bmc_hostname: Option<String>,
+
+    #[arg(long, short = 'U', name = "user", global = true, help = "BMC username, required by every command but analyze")]
+    bmc_username: Option<String>,
+
+    #[arg(long, short = 'P', name = "password", global = true, help = "BMC password, required by every command but analyze")]
+    bmc_password: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "/usr/bin/ipmitool",
+        name = "ipmi path",
+        help = "Path to ipmi executable (relative or absolute)"
+    )]
+    ipmi: String,
 
-    #[arg(long, short = 'U', name = "user")]
-    bmc_username: String,
+    #[arg(
+        long,
+        short,
+        global = true,
+        default_value = "./stats",
+        name = "stats directory",
+        help = "Directory to store runtime stats in"
+    )]
+    stats_dir: String,
 
-    #[arg(long, short = 'P', name = "password")]
-    bmc_password: String,
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(1..),
+        name = "bmc poll milliseconds",
+        help = "Interval between BMC power and cap readings"
+    )]
+    bmc_poll_millis: u64,
 
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 500,
+        name = "bmc inter-command milliseconds",
+        help = "Pause between the BMC power and cap reading commands of each poll, shorter than --bmc-poll-millis"
+    )]
+    bmc_inter_command_millis: u64,
+
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 250,
+        value_parser = clap::value_parser!(u64).range(1..),
+        name = "rapl poll milliseconds",
+        help = "Interval between RAPL energy readings"
+    )]
+    rapl_poll_millis: u64,
+
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(1..),
+        name = "cpu poll milliseconds",
+        help = "Interval between /proc/stat CPU utilisation readings"
+    )]
+    cpu_poll_millis: u64,
+
+    #[arg(
+        long,
+        global = true,
+        name = "metrics address",
+        help = "Serve live Prometheus metrics on this address, e.g. 127.0.0.1:9464"
+    )]
+    metrics_addr: Option<String>,
+
+    /// The options of `run`, for running without a subcommand
+    #[command(flatten)]
+    run: RunArgs,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Run the campaign: the trial matrix, or a search or sweep in its place
+    Run(RunArgs),
+    /// Only record the BMC, RAPL and CPU stats until interrupted, e.g. alongside another workload
+    Monitor,
+    /// Read or change the BMC's power cap
+    Bmc {
+        #[command(subcommand)]
+        action: BmcCommand,
+    },
+    /// Summarise the trials of a run from the driver log in the stats directory
+    Analyze {
+        #[arg(long, help = "Test timestamp of the run, e.g. 240101_1200, by default the most recent")]
+        timestamp: Option<String>,
+    },
+    /// Check the executables, BMC, RAPL files, stats directory and caps a run depends on, then exit
+    Check(RunArgs),
+}
+
+/// The options of a run, also taken by `check` to verify them
+#[derive(Args, Debug, Clone)]
+struct RunArgs {
     #[arg(
         long,
         default_value_t = 10,
@@ -129,12 +467,11 @@ struct CLI {
 
     #[arg(
         long,
-        short,
-        default_value = "./stats",
-        name = "stats directory",
-        help = "Directory to store runtime stats in"
+        default_value_t = 500,
+        name = "setup pause milliseconds",
+        help = "Pause after each BMC command setting up a trial's initial cap, for the BMC to take it in"
     )]
-    stats_dir: String,
+    setup_pause_millis: u64,
 
     #[arg(
         long,
@@ -146,9 +483,210 @@ struct CLI {
 
     #[arg(
         long,
-        default_value = "/usr/bin/ipmitool",
-        name = "ipmi path",
-        help = "Path to ipmi executable (relative or absolute)"
+        value_enum,
+        default_value_t = LoadGeneratorKind::Firestarter,
+        name = "load generator",
+        help = "Program used to load the server"
     )]
-    ipmi: String,
+    load_generator: LoadGeneratorKind,
+
+    #[arg(
+        long,
+        default_value = "stress-ng",
+        name = "stress-ng path",
+        help = "Path to stress-ng executable (relative or absolute), for --load-generator stress-ng"
+    )]
+    stress_ng: String,
+
+    #[arg(
+        long,
+        name = "load command template",
+        help = "Load generator command for --load-generator command. The placeholders {load_pct}, {load_period_us}, {n_threads} and {duration_secs} are replaced by each trial's values"
+    )]
+    load_command: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LoadKernel::Float,
+        name = "load kernel",
+        help = "Work done by the threads of --load-generator builtin"
+    )]
+    load_kernel: LoadKernel,
+
+    #[arg(
+        long,
+        default_value = "none",
+        name = "placement",
+        help = "Where the load generator's threads run: none (left to the OS), compact (filling one socket at a time), scatter (round-robin across the sockets), socket:N (only socket N) or cpus:LIST (e.g. cpus:0,2,4-7)"
+    )]
+    placement: Placement,
+
+    #[arg(
+        long,
+        name = "campaign file",
+        help = "TOML file describing the trials to run. Unset keys take the built-in values"
+    )]
+    campaign: Option<String>,
+
+    #[arg(
+        long,
+        help = "Bisect for the load at which capping stops working instead of running the trial matrix"
+    )]
+    search: bool,
+
+    #[arg(
+        long,
+        name = "cap levels",
+        help = "Sweep the cap over many levels instead of the campaign's cap pairs, each with every capping order, and save the compliance curve: LO-HI:STEP (e.g. 300-600:20) or a list of levels in W (e.g. 300,420,550), capping from the highest to each of the others"
+    )]
+    sweep: Option<CapSweep>,
+
+    #[arg(
+        long,
+        help = "Resume the most recent campaign in the stats directory: its trials are read from the campaign journal and those already completed are skipped"
+    )]
+    resume: bool,
+
+    #[arg(
+        long,
+        help = "Print the trials that would be run, with their load generator and BMC commands, and the estimated wall time, then exit without touching the BMC or starting any load"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        name = "repetitions",
+        help = "Number of times each trial configuration is run, overriding the campaign's"
+    )]
+    repetitions: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Run the trials in a random order. The seed is recorded in the driver log"
+    )]
+    shuffle: bool,
+
+    #[arg(
+        long,
+        name = "seed",
+        help = "Shuffle the trials with this seed, to reproduce the order of an earlier campaign"
+    )]
+    seed: Option<u64>,
+}
+
+impl Default for RunArgs {
+    /// The defaults of the run options, for the commands that don't take them
+    fn default() -> Self {
+        let matches = RunArgs::augment_args(clap::Command::new("run")).get_matches_from(["run"]);
+        RunArgs::from_arg_matches(&matches).expect("Failed to parse the default run options")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configuration_from_args() {
+        let config = Configuration::try_from_args(["capping", "-H", "bmc", "-U", "user", "-P", "secret", "run", "--warmup", "5"]).unwrap();
+        assert_eq!((config.bmc_hostname.as_str(), config.warmup_secs), ("bmc", 5));
+        assert_eq!(config.test_time_secs, 15);
+        assert_eq!(config.command, Command::Run);
+
+        let config = Configuration::for_bmc("bmc", "user", "secret");
+        assert_eq!((config.cap_low_watts, config.cap_high_watts), (400, 580));
+        assert_eq!(config.bmc_stats_filename_prefix, BMC_STATS_FILENAME_PREFIX);
+
+        // the BMC credentials are required
+        assert!(Configuration::try_from_args(["capping", "-H", "bmc", "run"]).is_err());
+        assert!(Configuration::try_from_args(["capping", "-H", "bmc", "-U", "user", "-P", "secret", "run", "--warmup", "soon"]).is_err());
+        // the poll intervals must leave time for the readings, wherever they're set
+        let bmc = ["capping", "-H", "bmc", "-U", "user", "-P", "secret"];
+        for (option, millis) in [("--bmc-poll-millis", "0"), ("--rapl-poll-millis", "0"), ("--cpu-poll-millis", "0"), ("--bmc-inter-command-millis", "1000")] {
+            assert!(Configuration::try_from_args(bmc.iter().copied().chain([option, millis, "run"])).is_err(), "{option} {millis}");
+        }
+        let env = |var: &str| (var == "CAPPING_RAPL_POLL_MILLIS").then(|| String::from("0"));
+        assert!(Configuration::try_from_sources(bmc.iter().copied().chain(["run"]), env, None).is_err());
+        // a resume needs a journal to carry on from
+        let stats_dir = std::env::temp_dir().join(format!("no_journal_test_{}", std::process::id()));
+        let stats_dir = stats_dir.to_str().unwrap();
+        assert!(Configuration::try_from_args(bmc.iter().copied().chain(["--stats-dir", stats_dir, "run", "--resume"])).is_err());
+        assert!(Configuration::try_from_args(bmc.iter().copied().chain(["--stats-dir", stats_dir, "run"])).is_ok());
+        // run is the default subcommand, taking the run options without it
+        let config = Configuration::try_from_args(["capping", "-H", "bmc", "-U", "user", "-P", "secret", "--warmup", "5"]).unwrap();
+        assert_eq!((config.command, config.warmup_secs), (Command::Run, 5));
+        assert!(Configuration::try_from_args(["capping", "-H", "bmc", "-U", "user", "-P", "secret", "--warmup", "5", "monitor"]).is_err());
+    }
+
+    #[test]
+    fn test_subcommands() {
+        // the global options can be given before or after the subcommand
+        let config = Configuration::try_from_args(["capping", "monitor", "-H", "bmc", "-U", "user", "-P", "secret", "--stats-dir", "/tmp/stats"]).unwrap();
+        assert_eq!(config.command, Command::Monitor);
+        assert_eq!(config.stats_dir, "/tmp/stats");
+        // with the run options' defaults
+        assert_eq!(config.warmup_secs, 10);
+
+        let config = Configuration::try_from_args(["capping", "-H", "bmc", "-U", "user", "-P", "secret", "bmc", "set", "450"]).unwrap();
+        assert_eq!(config.command, Command::Bmc(BmcCommand::Set { watts: 450 }));
+
+        let config = Configuration::try_from_args(["capping", "-H", "bmc", "-U", "user", "-P", "secret", "check", "--load-generator", "builtin"]).unwrap();
+        assert_eq!(config.command, Command::Check);
+        assert_eq!(config.load_generator, LoadGeneratorKind::Builtin);
+
+        // only analyze does without the BMC
+        let config = Configuration::try_from_args(["capping", "analyze", "--timestamp", "240101_1200"]).unwrap();
+        assert_eq!(config.command, Command::Analyze { timestamp: Some(String::from("240101_1200")) });
+        assert!(Configuration::try_from_args(["capping", "monitor"]).is_err());
+        // the run options are only taken by run and check
+        assert!(Configuration::try_from_args(["capping", "-H", "bmc", "-U", "user", "-P", "secret", "monitor", "--warmup", "5"]).is_err());
+    }
+
+    #[test]
+    fn test_layered_settings() {
+        let path = std::env::temp_dir().join(format!("capping_config_{}.toml", std::process::id()));
+        std::fs::write(&path, r#"
+            stats_dir = "/data/capping"
+            warmup = 20
+            setup_pause_millis = 250
+
+            [profiles.node12]
+            bmc_hostname = "node12-bmc"
+            bmc_username = "admin"
+            bmc_password = "secret"
+            warmup = 30
+            "#).unwrap();
+        let config_file = path.to_str().unwrap();
+        let no_env = |_: &str| None;
+
+        // the BMC credentials from the host's profile
+        let config = Configuration::try_from_sources(["capping", "--config", config_file, "run"], no_env, Some("node12")).unwrap();
+        assert_eq!((config.bmc_hostname.as_str(), config.stats_dir.as_str()), ("node12-bmc", "/data/capping"));
+        assert_eq!((config.warmup_secs, config.setup_pause_millis, config.test_time_secs), (30, 250, 15));
+        assert_eq!(config.profile.as_deref(), Some("node12"));
+        let warmup = config.settings.iter().find(|s| s.key == "warmup").unwrap();
+        assert_eq!((warmup.value.as_str(), &warmup.origin), ("30", &settings::Origin::Profile(String::from("node12"))));
+
+        // then the environment, then the command line
+        let env = |var: &str| match var {
+            "CAPPING_CONFIG" => Some(String::from(config_file)),
+            "CAPPING_PROFILE" => Some(String::from("node12")),
+            "CAPPING_WARMUP" => Some(String::from("40")),
+            "CAPPING_SEARCH" => Some(String::from("true")),
+            _ => None,
+        };
+        let config = Configuration::try_from_sources(["capping", "run"], env, None).unwrap();
+        assert_eq!((config.warmup_secs, config.search), (40, true));
+        let config = Configuration::try_from_sources(["capping", "run", "--warmup", "5", "--setup-pause-millis", "100"], env, None).unwrap();
+        assert_eq!((config.warmup_secs, config.setup_pause_millis), (5, 100));
+        let warmup = config.settings.iter().find(|s| s.key == "warmup").unwrap();
+        assert_eq!(warmup.origin, settings::Origin::CommandLine);
+
+        // without the profile, the credentials are missing
+        assert!(Configuration::try_from_sources(["capping", "--config", config_file, "run"], no_env, None).is_err());
+        assert!(Configuration::try_from_sources(["capping", "--config", config_file, "--profile", "node13", "run"], no_env, None).is_err());
+        assert!(Configuration::try_from_sources(["capping", "--config", "/nonexistent/capping.toml", "analyze"], no_env, None).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
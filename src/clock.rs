@@ -0,0 +1,190 @@
+use chrono::{DateTime, Local, NaiveDateTime};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+// The BMC reports its timestamps with a resolution of one second, truncated.
+// On average, the true BMC time is half a second later than the reported one.
+const BMC_TIMESTAMP_RESOLUTION_SECS: f64 = 1.0;
+
+/*
+    The reference point for monotonic timestamps, captured on first use
+    along with the wall-clock time at that instant, unless an earlier one is
+    resumed (see resume_epoch). Unlike Local::now(), the monotonic clock never
+    steps (e.g. under NTP), so differences between monotonic timestamps are
+    reliable latency measurements.
+*/
+static EPOCH: OnceLock<(Instant, DateTime<Local>)> = OnceLock::new();
+
+fn epoch() -> &'static (Instant, DateTime<Local>) {
+    EPOCH.get_or_init(|| (Instant::now(), Local::now()))
+}
+
+/// Microseconds elapsed on the monotonic clock since the clock epoch
+#[must_use]
+pub fn monotonic_micros() -> u64 {
+    u64::try_from(epoch().0.elapsed().as_micros()).unwrap_or(u64::MAX)
+}
+
+/// The wall-clock time corresponding to a monotonic timestamp of zero
+#[must_use]
+pub fn epoch_wallclock() -> DateTime<Local> {
+    epoch().1
+}
+
+/// Fixes the clock epoch at an earlier wall-clock time, that of the run being resumed,
+/// so that monotonic timestamps carry on from the ones it recorded. The time since is
+/// measured on the wall clock, so a step of the wall clock in between shows up as a
+/// gap in the monotonic timestamps.
+///
+/// # Errors
+/// If the clock has already been read, or the epoch is in the future or before the
+/// monotonic clock's origin (typically, the host has rebooted since)
+pub fn resume_epoch(wallclock: DateTime<Local>) -> Result<(), String> {
+    let epoch = epoch_at(wallclock, Instant::now(), Local::now())?;
+    EPOCH.set(epoch).map_err(|_| String::from("the clock epoch is already fixed"))
+}
+
+/// The monotonic instant corresponding to `wallclock`, given a pair of readings of
+/// both clocks
+fn epoch_at(
+    wallclock: DateTime<Local>,
+    now: Instant,
+    now_wallclock: DateTime<Local>,
+) -> Result<(Instant, DateTime<Local>), String> {
+    let elapsed = (now_wallclock - wallclock)
+        .to_std()
+        .map_err(|_| format!("clock epoch {} is in the future", wallclock.to_rfc3339()))?;
+    let instant = now
+        .checked_sub(elapsed)
+        .ok_or_else(|| format!("clock epoch {} is before the monotonic clock's origin", wallclock.to_rfc3339()))?;
+    Ok((instant, wallclock))
+}
+
+/// Estimates the offset and drift of the BMC clock relative to the host clock.
+///
+/// Each sample pairs the BMC's `IPMI timestamp` with the host's wall-clock time at the
+/// midpoint of the ipmitool command that returned it. The offset is the mean of
+/// (BMC time - host time) over all samples; the drift is the least-squares slope of the
+/// offset against the host's monotonic clock, in parts per million.
+#[derive(Debug, Default)]
+pub struct ClockOffsetEstimator {
+    /// (host monotonic seconds, BMC - host offset seconds)
+    samples: Vec<(f64, f64)>,
+}
+
+impl ClockOffsetEstimator {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a sample. BMC timestamps that failed to parse (`NaiveDateTime::MIN`)
+    /// are ignored.
+    ///
+    /// # Arguments
+    /// * `monotonic_us` - host monotonic timestamp at the midpoint of the command
+    /// * `host_time` - host wall-clock time at the midpoint of the command
+    /// * `bmc_time` - the timestamp reported by the BMC
+    pub fn add_sample(&mut self, monotonic_us: u64, host_time: NaiveDateTime, bmc_time: NaiveDateTime) {
+        if bmc_time == NaiveDateTime::MIN {
+            return;
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let offset_secs = (bmc_time - host_time).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6
+            + BMC_TIMESTAMP_RESOLUTION_SECS / 2.0;
+        #[allow(clippy::cast_precision_loss)]
+        let monotonic_secs = monotonic_us as f64 / 1e6;
+        self.samples.push((monotonic_secs, offset_secs));
+    }
+
+    #[must_use]
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Mean offset of the BMC clock from the host clock in seconds, positive when
+    /// the BMC is ahead. `None` if there are no samples.
+    #[must_use]
+    pub fn offset_secs(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let n = self.samples.len() as f64;
+        Some(self.samples.iter().map(|(_, offset)| offset).sum::<f64>() / n)
+    }
+
+    /// Drift of the BMC clock relative to the host clock in parts per million, positive
+    /// when the BMC clock runs fast. `None` if there are fewer than two samples or they
+    /// were all taken at the same instant.
+    #[must_use]
+    pub fn drift_ppm(&self) -> Option<f64> {
+        if self.samples.len() < 2 {
+            return None;
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let n = self.samples.len() as f64;
+        let mean_x = self.samples.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = self.samples.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let (covariance, variance) = self.samples.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+            (cov + (x - mean_x) * (y - mean_y), var + (x - mean_x).powi(2))
+        });
+
+        if variance == 0.0 {
+            None
+        } else {
+            Some(covariance / variance * 1e6)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, NaiveDate, Timelike};
+
+    #[test]
+    fn test_monotonic_micros_increases() {
+        let t0 = monotonic_micros();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert!(monotonic_micros() >= t0 + 2000);
+    }
+
+    #[test]
+    fn test_epoch_at() {
+        let (now, now_wallclock) = (Instant::now(), Local::now());
+        let (instant, wallclock) = epoch_at(now_wallclock - Duration::seconds(5), now, now_wallclock).unwrap();
+        assert_eq!(wallclock, now_wallclock - Duration::seconds(5));
+        assert_eq!(now - instant, std::time::Duration::from_secs(5));
+        assert!(epoch_at(now_wallclock + Duration::seconds(5), now, now_wallclock).is_err());
+    }
+
+    #[test]
+    fn test_clock_offset_and_drift() {
+        let host_start = NaiveDate::from_ymd_opt(2023, 5, 9).unwrap().and_hms_opt(14, 24, 36).unwrap();
+        let mut estimator = ClockOffsetEstimator::new();
+        assert!(estimator.offset_secs().is_none());
+
+        // BMC starts 2.5s ahead of the host and gains 1ms every second (1000ppm).
+        // Its timestamps are truncated to the second, so sample at varying fractions
+        // of a second, as happens in practice.
+        for i in 0..100 {
+            let elapsed_ms = i * 10_000 + (i * 370) % 1000;
+            let host_time = host_start + Duration::milliseconds(elapsed_ms);
+            let bmc_time = host_time + Duration::milliseconds(2500 + elapsed_ms / 1000);
+            let reported = bmc_time.with_nanosecond(0).unwrap();
+            #[allow(clippy::cast_sign_loss)]
+            estimator.add_sample(elapsed_ms as u64 * 1000, host_time, reported);
+        }
+
+        // ignored: the BMC timestamp wasn't parsed
+        estimator.add_sample(0, host_start, NaiveDateTime::MIN);
+
+        assert_eq!(estimator.sample_count(), 100);
+        assert!((estimator.offset_secs().unwrap() - 3.0).abs() < 0.05);
+        assert!((estimator.drift_ppm().unwrap() - 1000.0).abs() < 50.0);
+    }
+}
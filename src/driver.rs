@@ -1,28 +1,100 @@
+pub mod builtin_load;
+pub mod compliance;
 pub mod firestarter;
+pub mod journal;
+pub mod load_command;
+pub mod load_generator;
+pub mod load_profile;
+pub mod search;
+pub mod steady_state;
+pub mod stress_ng;
+pub mod sweep;
 mod trial;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::{self, Display};
-use crate::cli::CONFIGURATION;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+use std::path::{Path, PathBuf};
+use crate::bmc::{BMC, BMC_Action};
+use crate::campaign::{Campaign, TrialSpec};
+use crate::cli::Configuration;
+use crate::clock;
+use crate::driver::compliance::Compliance;
+use crate::driver::journal::Journal;
+use crate::driver::load_profile::LoadProfile;
+use crate::core_count;
+use crate::monitor::MonitorMessage;
+use crate::shutdown;
+use log::{info, warn};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+/// How the capping operation under test is reached. The first three are single
+/// transitions; the others are sequences of BMC commands, `interval_millis` apart, to
+/// expose BMC state machine bugs that a single transition can't. In a campaign file,
+/// the sequences are given with their parameters, e.g.
+/// `{ RepeatedToggle = { cycles = 3, interval_millis = 2000 } }`.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CappingOrder {
     LevelBeforeActivate,
     LevelAfterActivate,
     LevelToLevel,
+    /// With the level set to `cap_to`, toggle the cap from the state opposite to the
+    /// operation's and back `cycles` times, then apply the operation
+    RepeatedToggle { cycles: u64, interval_millis: u64 },
+    /// With the cap active at `cap_from`, change the level in `steps` equal steps
+    /// to `cap_to`
+    MultiLevel { steps: u64, interval_millis: u64 },
+    /// With the cap active at `cap_from`, set a level below the platform minimum,
+    /// which the BMC should reject, keeping `cap_from`
+    BelowMinimum { watts: u64 },
+}
+
+impl CappingOrder {
+    /// False for the operations that make no sense with the order: deactivating
+    /// after setting the level, or while changing or setting an invalid level
+    #[must_use]
+    pub fn runs_with(&self, capping_operation: CappingOperation) -> bool {
+        capping_operation == CappingOperation::Activate
+            || !matches!(self, Self::LevelAfterActivate | Self::MultiLevel { .. } | Self::BelowMinimum { .. })
+    }
+
+    /// The time between the BMC commands of a sequence
+    #[must_use]
+    pub fn interval(&self) -> Duration {
+        match self {
+            Self::RepeatedToggle { interval_millis, .. } | Self::MultiLevel { interval_millis, .. } => {
+                Duration::from_millis(*interval_millis)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// # Errors
+    /// If a sequence has no cycles or steps
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::RepeatedToggle { cycles: 0, .. } => Err(format!("{self}: cycles must be greater than 0")),
+            Self::MultiLevel { steps: 0, .. } => Err(format!("{self}: steps must be greater than 0")),
+            _ => Ok(()),
+        }
+    }
 }
 
 impl Display for CappingOrder {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}",
-            match self {
-                Self::LevelBeforeActivate => "LevelBeforeActivate",
-                Self::LevelAfterActivate => "LevelAfterActivate",
-                Self::LevelToLevel => "LevelToLevel",
-            }
-        )
+        match self {
+            Self::LevelBeforeActivate => write!(f, "LevelBeforeActivate"),
+            Self::LevelAfterActivate => write!(f, "LevelAfterActivate"),
+            Self::LevelToLevel => write!(f, "LevelToLevel"),
+            Self::RepeatedToggle { cycles, interval_millis } => write!(f, "RepeatedToggle:{cycles}x{interval_millis}ms"),
+            Self::MultiLevel { steps, interval_millis } => write!(f, "MultiLevel:{steps}x{interval_millis}ms"),
+            Self::BelowMinimum { watts } => write!(f, "BelowMinimum:{watts}W"),
+        }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CappingOperation {
     Activate,
     Deactivate,
@@ -41,51 +113,212 @@ impl Display for CappingOperation {
 
 
 pub struct Driver {
-    cap_high_watts: u64,
-    cap_low_watts: u64,
-}
-
-impl Default for Driver {
-    fn default() -> Self {
-        Self::new()
-    }
+    campaign: Campaign,
+    config: Arc<Configuration>,
+    /// Sending end of the monitor channel, used to signal trial events
+    monitor_tx: Sender<MonitorMessage>,
 }
 
 impl Driver {
     #[must_use]
-    pub fn new() -> Self {
+    pub fn new(campaign: Campaign, config: Arc<Configuration>, monitor_tx: Sender<MonitorMessage>) -> Self {
         Self {
-            cap_high_watts: CONFIGURATION.cap_high_watts,
-            cap_low_watts: CONFIGURATION.cap_low_watts,
+            campaign,
+            config,
+            monitor_tx,
         }
     }
 
-    /// Runs through the possible permutations of capping:
-    /// * high power => low power
-    /// * low power => high power
-    /// * set cap level before activating capping
-    /// * set cap level after activating capping
+    /// Expands the campaign (see `campaign.rs`) into its list of trials and runs
+    /// each of them in turn. The trial id, unique for the campaign, is stamped on
+    /// the driver log and on the monitor streams.
+    ///
+    /// The trials and the completion of each one are recorded in the campaign journal
+    /// (see `journal.rs`). When resuming, the trials are read back from the journal
+    /// and only those that haven't completed are run.
+    ///
+    /// A cap sweep (see `sweep.rs`) then saves the compliance curve of all the
+    /// campaign's completed trials.
     pub fn run(&self) {
-        for (cap_from, cap_to) in [
-            (self.cap_low_watts, self.cap_high_watts),
-            (self.cap_high_watts, self.cap_low_watts),
-        ] {
-            for capping_order in [
-                CappingOrder::LevelBeforeActivate,
-                CappingOrder::LevelAfterActivate,
-                CappingOrder::LevelToLevel,
-            ] {
-                for operation in [CappingOperation::Activate, CappingOperation::Deactivate] {
-                    // No sense in running Level after Activate when operation is Deactivate
-                    if !(capping_order == CappingOrder::LevelAfterActivate
-                        && operation == CappingOperation::Deactivate)
-                    {
-                        let mut trial =
-                            trial::Trial::new(cap_from, cap_to, capping_order, operation);
-                        trial.run();
-                    }
-                }
+        let journal_path = journal_path(&self.config);
+        let (mut journal, trials, completed) = self.open_journal(&journal_path, false);
+
+        for spec in trials.into_iter().filter(|spec| !completed.contains_key(&spec.trial_id)) {
+            if shutdown::requested() {
+                info!("Shutdown requested, the campaign can be resumed with --resume");
+                break;
+            }
+            let trial_id = spec.trial_id;
+            let mut trial = trial::Trial::new(spec, Arc::clone(&self.config), self.monitor_tx.clone());
+            trial.run();
+            if trial.was_interrupted() {
+                continue;
+            }
+            journal
+                .record_completed(trial_id, trial.compliance())
+                .expect("Failed to record trial in campaign journal");
+        }
+
+        // a resumed sweep is one whether or not --sweep is given again
+        let state = Journal::read_state(&journal_path).expect("Failed to read campaign journal");
+        if state.sweep || self.campaign.sweep.is_some() {
+            let points = sweep::compliance_curve(&state.trials, &state.completed);
+            let path = sweep::save_compliance_curve(&self.config, &points).expect("Failed to save compliance curve");
+            info!("Compliance curve of {} points saved to {path:?}", points.len());
+        }
+    }
+
+    /// Runs the adaptive search (see `search.rs`) instead of the campaign's trial
+    /// matrix and saves its results. The verdict of each trial is journaled, as for a
+    /// campaign; when resuming, the search is replayed with the journaled verdicts
+    /// and carries on from the first trial without one.
+    pub fn search(&self) {
+        let (journal, _, completed) = self.open_journal(&journal_path(&self.config), true);
+        let results = search::Search::new(&self.campaign, Arc::clone(&self.config), self.monitor_tx.clone(), journal, completed)
+            .run();
+        search::save_search_results(&self.config, &results).expect("Failed to save search results");
+    }
+
+    /// Creates the campaign journal or, when resuming, reads it back, dropping the
+    /// driver log rows of any trial it doesn't record as completed. Returns the journal,
+    /// the trials to run and the compliance of those already completed.
+    fn open_journal(&self, journal_path: &Path, search: bool) -> (Journal, Vec<TrialSpec>, HashMap<u64, Compliance>) {
+        if self.config.resume {
+            let (journal, state) = Journal::resume(journal_path).expect("Failed to read campaign journal");
+            assert!(
+                state.search == search,
+                "The campaign journal is {}a search's, resume it {} --search",
+                if state.search { "" } else { "not " },
+                if state.search { "with" } else { "without" },
+            );
+            let dropped = trial::drop_unjournaled_rows(&trial::driver_log_path(&self.config), &state.completed)
+                .expect("Failed to rewrite driver log");
+            if dropped > 0 {
+                info!("Dropped {dropped} driver log rows of trials that didn't complete");
             }
+            if search {
+                info!("Resuming search {}: {} trials already completed", self.config.test_timestamp, state.completed.len());
+            } else {
+                info!(
+                    "Resuming campaign {}: {} of {} trials already completed",
+                    self.config.test_timestamp,
+                    state.completed.len(),
+                    state.trials.len()
+                );
+            }
+            (journal, state.trials, state.completed)
+        } else if search {
+            let journal = Journal::create_search(journal_path).expect("Failed to create campaign journal");
+            (journal, Vec::new(), HashMap::new())
+        } else {
+            let trials = self.campaign.trials(core_count());
+            let journal = Journal::create(journal_path, &trials, self.campaign.sweep.is_some())
+                .expect("Failed to create campaign journal");
+            info!("Campaign of {} trials", trials.len());
+            (journal, trials, HashMap::new())
+        }
+    }
+}
+
+/// Fixes the monotonic clock's epoch at that of the campaign being resumed, so that
+/// the resumed run's monotonic timestamps carry on from those already in the stats
+/// files. Has to be called before the clock is first read.
+pub fn resume_clock(config: &Configuration) {
+    let state = Journal::read_state(&journal_path(config)).expect("Failed to read campaign journal");
+    let resumed = match state.epoch {
+        Some(epoch) => clock::resume_epoch(epoch),
+        None => Err(String::from("the campaign journal has no clock epoch")),
+    };
+    if let Err(e) = resumed {
+        warn!("Can't resume the monotonic clock, its timestamps restart from 0: {e}");
+    }
+}
+
+/// Prints the trials `Driver::run` would run, in order, with the load generator command
+/// line and BMC commands of each and the estimated wall time of the campaign, without
+/// touching the BMC or starting any load. With `--resume`, only the trials still to
+/// run are listed.
+pub fn dry_run(campaign: &Campaign, config: &Configuration) {
+    let (trials, completed) = if config.resume {
+        let state = Journal::read_state(&journal_path(config)).expect("Failed to read campaign journal");
+        (state.trials, state.completed)
+    } else {
+        (campaign.trials(core_count()), HashMap::new())
+    };
+
+    let bmc = BMC::from_config(config);
+    let bmc_command = |action: &BMC_Action| format!("{} {bmc:?} {}", bmc.ipmi, action.command());
+    let mut total = Duration::ZERO;
+    let mut n_trials = 0;
+    for spec in trials.iter().filter(|spec| !completed.contains_key(&spec.trial_id)) {
+        let plan = trial::TrialPlan::new(spec, config);
+        println!(
+            "Trial {} (repetition {}): cap {} W -> {} W, {} {}, load {}%, period {} µs, threads {}",
+            spec.trial_id,
+            spec.repetition,
+            spec.cap_from,
+            spec.cap_to,
+            spec.capping_order,
+            spec.capping_operation,
+            spec.load_pct,
+            spec.load_period_us,
+            spec.n_threads,
+        );
+        for action in &plan.setup {
+            println!("  setup: {}", bmc_command(action));
         }
+        println!("  load:  {}", plan.load);
+        let (cap, sequence) = plan.cap.split_last().expect("Capping order without a cap action");
+        for action in sequence {
+            println!("  then:  {} ({} ms before the next)", bmc_command(action), spec.capping_order.interval().as_millis());
+        }
+        println!("  cap:   {} (after {} s warmup)", bmc_command(cap), spec.warmup_secs);
+        if spec.profile != LoadProfile::Constant {
+            println!("  then:  load profile {}", spec.profile);
+        }
+        println!("  time:  {}", format_duration(plan.estimated_duration));
+        total += plan.estimated_duration;
+        n_trials += 1;
+    }
+    if !completed.is_empty() {
+        println!("{} trials already completed", completed.len());
+    }
+    if let Some(seed) = trials.first().and_then(|spec| spec.seed) {
+        println!("Trials shuffled with seed {seed}");
+    }
+    println!("{n_trials} trials, estimated wall time {}", format_duration(total));
+}
+
+fn journal_path(config: &Configuration) -> PathBuf {
+    Journal::path(
+        &config.stats_dir,
+        &config.journal_filename_prefix,
+        &config.test_timestamp,
+    )
+}
+
+/// Formats a duration as hours, minutes and seconds, e.g. "1h 02m 03.5s"
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let tenths = duration.subsec_millis() / 100;
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    let frac = if tenths == 0 { String::new() } else { format!(".{tenths}") };
+    match (h, m) {
+        (0, 0) => format!("{s}{frac}s"),
+        (0, _) => format!("{m}m {s:02}{frac}s"),
+        _ => format!("{h}h {m:02}m {s:02}{frac}s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+        assert_eq!(format_duration(Duration::from_millis(28_500)), "28.5s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m 05s");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h 02m 03s");
     }
 }
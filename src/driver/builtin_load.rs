@@ -0,0 +1,281 @@
+//! A load generator that needs no external program: spins one thread per requested
+//! CPU, each pinned to its own core (or to the CPUs given by the placement, see
+//! `topology.rs`), with the same duty cycle as firestarter: busy for `load_pct` of
+//! every `load_period_us`, idle for the rest. The load can be changed while running,
+//! taking effect from the next period.
+
+use crate::core_count;
+use crate::topology::format_cpu_list;
+use core_affinity::CoreId;
+use crate::driver::load_generator::{LoadGenerator, LoadSpec, LoadStatus};
+use crate::shutdown;
+use crate::ResultType;
+use clap::ValueEnum;
+use log::{trace, warn};
+use std::fmt::{self, Display, Formatter};
+use std::hint::black_box;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Period used when the trial's period is 0, as for firestarter
+const DEFAULT_PERIOD_US: u64 = 100_000;
+/// Size of the memory kernel's buffers, shared out among the threads, well beyond
+/// the caches
+const MEMORY_KERNEL_BYTES: usize = 256 * 1024 * 1024;
+
+/// The work done while busy
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum LoadKernel {
+    /// Integer arithmetic in registers
+    Integer,
+    /// Floating point multiply-adds over a small array, which the compiler vectorises
+    Float,
+    /// Streaming reads and writes over a buffer larger than the caches
+    Memory,
+}
+
+impl Display for LoadKernel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Self::Integer => "integer",
+            Self::Float => "float",
+            Self::Memory => "memory",
+        })
+    }
+}
+
+/// A kernel's working state, owned by its thread
+enum KernelState {
+    Integer(u64),
+    Float(Vec<f64>),
+    Memory(Vec<u64>),
+}
+
+impl KernelState {
+    /// The state of one of `n_threads` threads
+    fn new(kernel: LoadKernel, n_threads: usize) -> Self {
+        match kernel {
+            LoadKernel::Integer => Self::Integer(0x9E37_79B9_7F4A_7C15),
+            LoadKernel::Float => Self::Float(vec![1.0; 1024]),
+            LoadKernel::Memory => Self::Memory(vec![1; MEMORY_KERNEL_BYTES / 8 / n_threads.max(1)]),
+        }
+    }
+
+    /// Does a short burst of work, some microseconds long, so that the end of the
+    /// busy time is noticed promptly. The memory kernel works through its buffer a
+    /// slice at a time.
+    fn run_burst(&mut self, burst: &mut usize) {
+        match self {
+            Self::Integer(x) => {
+                for _ in 0..4096 {
+                    // xorshift
+                    *x ^= *x << 13;
+                    *x ^= *x >> 7;
+                    *x ^= *x << 17;
+                }
+                black_box(*x);
+            }
+            Self::Float(values) => {
+                for _ in 0..8 {
+                    for v in values.iter_mut() {
+                        *v = *v * 0.999_999 + 1e-6;
+                    }
+                }
+                black_box(values);
+            }
+            Self::Memory(buffer) => {
+                const SLICE: usize = 16 * 1024;
+                let start = (*burst * SLICE) % buffer.len();
+                let end = (start + SLICE).min(buffer.len());
+                for v in &mut buffer[start..end] {
+                    *v = v.wrapping_mul(3).wrapping_add(1);
+                }
+                black_box(&buffer[start]);
+            }
+        }
+        *burst += 1;
+    }
+}
+
+pub struct BuiltinLoad {
+    kernel: LoadKernel,
+    /// Read by the threads at the start of every period
+    load_pct: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl BuiltinLoad {
+    #[must_use]
+    pub fn new(kernel: LoadKernel) -> Self {
+        Self {
+            kernel,
+            load_pct: Arc::new(AtomicU64::new(100)),
+            stop: Arc::new(AtomicBool::new(false)),
+            threads: Vec::new(),
+        }
+    }
+}
+
+/// The busy part of each period
+fn busy_time(load_pct: u64, period: Duration) -> Duration {
+    period * u32::try_from(load_pct).unwrap_or(100) / 100
+}
+
+/// Runs the duty cycle on the current thread, one of `n_threads`, until `end`, or
+/// until stopped
+fn run_duty_cycle(
+    kernel: LoadKernel,
+    n_threads: usize,
+    load_pct: &AtomicU64,
+    load_period_us: u64,
+    end: Instant,
+    stop: &AtomicBool,
+) {
+    let period_us = if load_period_us == 0 { DEFAULT_PERIOD_US } else { load_period_us };
+    let period = Duration::from_micros(period_us);
+    let mut state = KernelState::new(kernel, n_threads);
+    let mut burst = 0;
+
+    let mut period_start = Instant::now();
+    while period_start < end && !stop.load(Ordering::Relaxed) && !shutdown::requested() {
+        let busy = busy_time(load_pct.load(Ordering::Relaxed), period);
+        let busy_end = (period_start + busy).min(end);
+        while Instant::now() < busy_end {
+            state.run_burst(&mut burst);
+        }
+        let period_end = (period_start + period).min(end);
+        thread::sleep(period_end.saturating_duration_since(Instant::now()));
+        period_start = period_end;
+    }
+}
+
+impl LoadGenerator for BuiltinLoad {
+    /// The threads have no output of their own
+    fn start(&mut self, load: &LoadSpec, _output: Option<&Path>) -> ResultType<()> {
+        // the placement's CPUs, or else one thread per core, in order
+        let core_ids: Vec<CoreId> = match &load.cpus {
+            Some(cpus) => cpus.iter().map(|id| CoreId { id: *id }).collect(),
+            None => core_affinity::get_core_ids().unwrap_or_default(),
+        };
+        let n_threads = match load.effective_threads() {
+            0 => core_count(),
+            n_threads => n_threads,
+        };
+        if core_ids.is_empty() {
+            warn!("BUILTIN LOAD can't read the core ids, threads won't be pinned");
+        }
+        trace!("BUILTIN LOAD LAUNCHING: {}", self.describe(load));
+
+        self.stop.store(false, Ordering::Relaxed);
+        self.load_pct.store(load.load_pct, Ordering::Relaxed);
+        let end = Instant::now() + Duration::from_secs(load.duration_secs);
+        let thread_count = usize::try_from(n_threads).unwrap_or(usize::MAX);
+        for i in 0..n_threads {
+            let core_id = usize::try_from(i).ok().and_then(|i| core_ids.get(i % core_ids.len().max(1)).copied());
+            let (kernel, load_pct, load_period_us, stop) =
+                (self.kernel, Arc::clone(&self.load_pct), load.load_period_us, Arc::clone(&self.stop));
+            let thread = thread::Builder::new()
+                .name(format!("load-{i}"))
+                .spawn(move || {
+                    if let Some(core_id) = core_id {
+                        if !core_affinity::set_for_current(core_id) {
+                            warn!("BUILTIN LOAD failed to pin thread {i} to cpu {}", core_id.id);
+                        }
+                    }
+                    run_duty_cycle(kernel, thread_count, &load_pct, load_period_us, end, &stop);
+                })
+                .map_err(|e| format!("Failed to start load thread: {e}"))?;
+            self.threads.push(thread);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    fn wait(&mut self) -> LoadStatus {
+        let n_panicked = self.threads.drain(..).filter_map(|thread| thread.join().err()).count();
+        trace!("BUILTIN LOAD exited");
+        if n_panicked > 0 {
+            warn!("BUILTIN LOAD {n_panicked} threads panicked");
+            return LoadStatus::Failed(format!("{n_panicked} threads panicked"));
+        }
+        LoadStatus::Ok
+    }
+
+    /// Only the load is changed, the threads keep running
+    fn set_load(&mut self, load: &LoadSpec, _output: Option<&Path>) -> ResultType<LoadStatus> {
+        self.load_pct.store(load.load_pct, Ordering::Relaxed);
+        Ok(LoadStatus::Ok)
+    }
+
+    fn changes_load_in_place(&self) -> bool {
+        true
+    }
+
+    fn describe(&self, load: &LoadSpec) -> String {
+        format!(
+            "builtin {} kernel: load {}% over {} µs, threads on {} for {} s",
+            self.kernel,
+            load.load_pct,
+            if load.load_period_us == 0 { DEFAULT_PERIOD_US } else { load.load_period_us },
+            match (&load.cpus, load.n_threads) {
+                (Some(cpus), _) => format!("cpus {}", format_cpu_list(cpus, ",")),
+                (None, 0) => String::from("all"),
+                (None, n_threads) => n_threads.to_string(),
+            },
+            load.duration_secs,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_busy_time() {
+        assert_eq!(busy_time(90, Duration::from_micros(10_000)), Duration::from_micros(9000));
+        assert_eq!(busy_time(100, Duration::from_micros(10_000)), Duration::from_micros(10_000));
+    }
+
+    #[test]
+    fn test_kernels_run() {
+        for kernel in [LoadKernel::Integer, LoadKernel::Float, LoadKernel::Memory] {
+            let mut state = KernelState::new(kernel, 4);
+            let mut burst = 0;
+            for _ in 0..3 {
+                state.run_burst(&mut burst);
+            }
+            assert_eq!(burst, 3);
+        }
+    }
+
+    #[test]
+    fn test_builtin_load() {
+        let mut generator = BuiltinLoad::new(LoadKernel::Integer);
+        let load = LoadSpec::new(50, 10_000, 2, 1).unwrap();
+        assert_eq!(generator.describe(&load), "builtin integer kernel: load 50% over 10000 µs, threads on 2 for 1 s");
+
+        // runs for its duration...
+        let start = Instant::now();
+        generator.start(&load, None).unwrap();
+        assert_eq!(generator.wait(), LoadStatus::Ok);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(990) && elapsed < Duration::from_secs(3), "{elapsed:?}");
+
+        // ...unless stopped
+        let start = Instant::now();
+        generator.start(&LoadSpec::new(50, 10_000, 2, 30).unwrap(), None).unwrap();
+        generator.set_load(&LoadSpec::new(20, 10_000, 2, 30).unwrap(), None).unwrap();
+        assert_eq!(generator.load_pct.load(Ordering::Relaxed), 20);
+        generator.stop();
+        assert_eq!(generator.wait(), LoadStatus::Ok);
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,341 @@
+use crate::driver::{CappingOperation, CappingOrder};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+// Power readings within this percentage of a limit are considered to respect it -
+// the BMC regulates around the limit, not strictly below it.
+const COMPLIANCE_TOLERANCE_PCT: u64 = 2;
+// Number of consecutive readings that must respect the expectation, up to the end
+// of the test window, for the power to be considered settled.
+const SETTLE_SAMPLES: usize = 3;
+// Number of readings taken just before the cap request that characterise the
+// uncapped (or previously capped) power.
+const BASELINE_SAMPLES: usize = 3;
+
+/// The outcome of a trial's capping operation
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    /// Power settled as expected within the test window
+    Pass,
+    /// Power did not settle as expected within the test window
+    Fail,
+    /// Can't tell: no readings, or the load was not high enough for the cap to matter
+    Inconclusive,
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}",
+            match self {
+                Self::Pass => "pass",
+                Self::Fail => "fail",
+                Self::Inconclusive => "inconclusive",
+            }
+        )
+    }
+}
+
+/// What the power should do once the capping operation has been applied
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Expectation {
+    /// Power should settle at or below the limit
+    Capped { limit: u64 },
+    /// The cap has been lifted, or raised: power held at the previous limit should rise
+    /// above it
+    Uncapped { previous_limit: u64 },
+    /// The BMC should reject the request, keeping the cap it had: power should stay at
+    /// or below the limit
+    Rejected { limit: u64 },
+}
+
+impl Expectation {
+    /// Derives the expectation from the capping scenario, mirroring
+    /// `Trial::set_initial_conditions()` and `Trial::do_cap_operation()`
+    #[must_use]
+    pub fn for_scenario(
+        capping_order: CappingOrder,
+        capping_operation: CappingOperation,
+        cap_from: u64,
+        cap_to: u64,
+    ) -> Self {
+        match (capping_order, capping_operation) {
+            (CappingOrder::LevelBeforeActivate | CappingOrder::RepeatedToggle { .. }, CappingOperation::Deactivate) => {
+                Self::Uncapped { previous_limit: cap_to }
+            }
+            (CappingOrder::BelowMinimum { .. }, _) => Self::Rejected { limit: cap_from },
+            // the power is held at cap_from through the warmup, so a raised cap can
+            // only be seen to take effect by the power rising above it
+            (CappingOrder::LevelToLevel | CappingOrder::LevelAfterActivate | CappingOrder::MultiLevel { .. }, _)
+                if cap_to > cap_from =>
+            {
+                Self::Uncapped { previous_limit: cap_from }
+            }
+            _ => Self::Capped { limit: cap_to },
+        }
+    }
+}
+
+/// The verdict and, for a pass, the time from the cap request until the power settled
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Compliance {
+    pub verdict: Verdict,
+    pub time_to_compliance: Option<Duration>,
+    /// Mean of the last readings of the test window, the power the cap held the
+    /// server at, if there were any readings
+    pub achieved_power: Option<u64>,
+}
+
+/// Decides whether the power settled as expected after a capping operation, from the
+/// power readings taken before (baseline) and after the cap request.
+#[derive(Debug)]
+pub struct ComplianceDetector {
+    expectation: Expectation,
+    baseline: Vec<u64>,
+    /// (time since cap request, power)
+    samples: Vec<(Duration, u64)>,
+    /// False if the BMC rejected the cap request
+    cap_accepted: bool,
+}
+
+impl ComplianceDetector {
+    #[must_use]
+    pub fn new(expectation: Expectation) -> Self {
+        Self {
+            expectation,
+            baseline: Vec::new(),
+            samples: Vec::new(),
+            cap_accepted: true,
+        }
+    }
+
+    /// Records whether the BMC accepted the cap request, which it should only have
+    /// if the expectation isn't `Rejected`
+    pub fn set_cap_accepted(&mut self, cap_accepted: bool) {
+        self.cap_accepted = cap_accepted;
+    }
+
+    /// Records a power reading taken before the cap request. Only the most recent
+    /// readings are kept.
+    pub fn add_baseline(&mut self, power: u64) {
+        self.baseline.push(power);
+        if self.baseline.len() > BASELINE_SAMPLES {
+            self.baseline.remove(0);
+        }
+    }
+
+    /// Records a power reading taken `since_request` after the cap request
+    pub fn add_sample(&mut self, since_request: Duration, power: u64) {
+        self.samples.push((since_request, power));
+    }
+
+    /// Mean of the baseline readings, if any
+    #[must_use]
+    pub fn baseline_power(&self) -> Option<u64> {
+        if self.baseline.is_empty() {
+            None
+        } else {
+            Some(self.baseline.iter().sum::<u64>() / self.baseline.len() as u64)
+        }
+    }
+
+    /// Mean of the last `SETTLE_SAMPLES` readings after the cap request, if any
+    #[must_use]
+    pub fn achieved_power(&self) -> Option<u64> {
+        let last = &self.samples[self.samples.len().saturating_sub(SETTLE_SAMPLES)..];
+        if last.is_empty() {
+            None
+        } else {
+            Some(last.iter().map(|(_, power)| power).sum::<u64>() / last.len() as u64)
+        }
+    }
+
+    #[must_use]
+    pub fn compliance(&self) -> Compliance {
+        let achieved_power = self.achieved_power();
+        let inconclusive = Compliance { verdict: Verdict::Inconclusive, time_to_compliance: None, achieved_power };
+        let failed = Compliance { verdict: Verdict::Fail, time_to_compliance: None, achieved_power };
+        if matches!(self.expectation, Expectation::Rejected { .. }) && self.cap_accepted {
+            return failed;
+        }
+        if self.samples.is_empty() {
+            return inconclusive;
+        }
+
+        let (reference, as_expected): (u64, Box<dyn Fn(u64) -> bool>) = match self.expectation {
+            Expectation::Capped { limit } | Expectation::Rejected { limit } => {
+                let threshold = limit + limit * COMPLIANCE_TOLERANCE_PCT / 100;
+                (limit, Box::new(move |power| power <= threshold))
+            }
+            Expectation::Uncapped { previous_limit } => {
+                let threshold = previous_limit + previous_limit * COMPLIANCE_TOLERANCE_PCT / 100;
+                (previous_limit, Box::new(move |power| power > threshold))
+            }
+        };
+
+        // If the power before the request was already below the (previous) limit the load
+        // wasn't high enough for the cap to have any effect, so there's nothing to observe.
+        // A rejected request should leave the power where the cap already held it.
+        let tolerance = reference * COMPLIANCE_TOLERANCE_PCT / 100;
+        if let Some(baseline) = self.baseline_power() {
+            let cap_irrelevant = match self.expectation {
+                Expectation::Capped { .. } => baseline <= reference + tolerance,
+                Expectation::Uncapped { .. } => baseline + tolerance < reference,
+                Expectation::Rejected { .. } => false,
+            };
+            if cap_irrelevant {
+                return inconclusive;
+            }
+        }
+
+        // Find the start of the run of readings that are as expected right up to the
+        // end of the test window
+        let settled_from = self
+            .samples
+            .iter()
+            .rposition(|(_, power)| !as_expected(*power))
+            .map_or(0, |last_unexpected| last_unexpected + 1);
+
+        if self.samples.len() - settled_from >= SETTLE_SAMPLES {
+            Compliance {
+                verdict: Verdict::Pass,
+                time_to_compliance: Some(self.samples[settled_from].0),
+                achieved_power,
+            }
+        } else {
+            failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(expectation: Expectation, baseline: &[u64], samples: &[u64]) -> ComplianceDetector {
+        let mut detector = ComplianceDetector::new(expectation);
+        for power in baseline {
+            detector.add_baseline(*power);
+        }
+        for (i, power) in samples.iter().enumerate() {
+            detector.add_sample(Duration::from_secs(i as u64 + 1), *power);
+        }
+        detector
+    }
+
+    #[test]
+    fn test_expectation_for_scenario() {
+        assert_eq!(
+            Expectation::for_scenario(CappingOrder::LevelBeforeActivate, CappingOperation::Deactivate, 580, 400),
+            Expectation::Uncapped { previous_limit: 400 }
+        );
+        assert_eq!(
+            Expectation::for_scenario(CappingOrder::LevelToLevel, CappingOperation::Deactivate, 580, 400),
+            Expectation::Capped { limit: 400 }
+        );
+        // raising the cap
+        assert_eq!(
+            Expectation::for_scenario(CappingOrder::LevelToLevel, CappingOperation::Activate, 400, 580),
+            Expectation::Uncapped { previous_limit: 400 }
+        );
+        assert_eq!(
+            Expectation::for_scenario(CappingOrder::LevelAfterActivate, CappingOperation::Activate, 400, 580),
+            Expectation::Uncapped { previous_limit: 400 }
+        );
+        let toggle = CappingOrder::RepeatedToggle { cycles: 3, interval_millis: 2000 };
+        assert_eq!(
+            Expectation::for_scenario(toggle, CappingOperation::Deactivate, 580, 400),
+            Expectation::Uncapped { previous_limit: 400 }
+        );
+        assert_eq!(
+            Expectation::for_scenario(CappingOrder::BelowMinimum { watts: 10 }, CappingOperation::Activate, 580, 400),
+            Expectation::Rejected { limit: 580 }
+        );
+        let multi = CappingOrder::MultiLevel { steps: 3, interval_millis: 500 };
+        assert_eq!(
+            Expectation::for_scenario(multi, CappingOperation::Activate, 580, 400),
+            Expectation::Capped { limit: 400 }
+        );
+        assert_eq!(
+            Expectation::for_scenario(multi, CappingOperation::Activate, 400, 580),
+            Expectation::Uncapped { previous_limit: 400 }
+        );
+    }
+
+    #[test]
+    fn test_capped_pass() {
+        let capped = Expectation::Capped { limit: 400 };
+        let compliance = detector(capped, &[600, 590, 610], &[600, 550, 405, 398, 402]).compliance();
+        assert_eq!(compliance.verdict, Verdict::Pass);
+        assert_eq!(compliance.time_to_compliance, Some(Duration::from_secs(3)));
+        assert_eq!(compliance.achieved_power, Some(401));
+    }
+
+    #[test]
+    fn test_capped_fail() {
+        let capped = Expectation::Capped { limit: 400 };
+        // settles too late
+        let compliance = detector(capped, &[600], &[600, 550, 500, 400, 400]).compliance();
+        assert_eq!(compliance.verdict, Verdict::Fail);
+        assert_eq!(compliance.time_to_compliance, None);
+
+        // under the limit, then escapes
+        let compliance = detector(capped, &[600], &[400, 400, 400, 400, 450]).compliance();
+        assert_eq!(compliance.verdict, Verdict::Fail);
+    }
+
+    #[test]
+    fn test_capped_inconclusive() {
+        let capped = Expectation::Capped { limit: 400 };
+        assert_eq!(detector(capped, &[350, 360, 355], &[350, 350, 350]).compliance().verdict, Verdict::Inconclusive);
+        assert_eq!(detector(capped, &[600], &[]).compliance().verdict, Verdict::Inconclusive);
+        assert_eq!(detector(capped, &[600], &[]).compliance().achieved_power, None);
+    }
+
+    #[test]
+    fn test_uncapped() {
+        let uncapped = Expectation::Uncapped { previous_limit: 400 };
+        let compliance = detector(uncapped, &[400, 401], &[400, 500, 560, 580]).compliance();
+        assert_eq!(compliance.verdict, Verdict::Pass);
+        assert_eq!(compliance.time_to_compliance, Some(Duration::from_secs(2)));
+
+        assert_eq!(detector(uncapped, &[400], &[400, 401, 399, 400]).compliance().verdict, Verdict::Fail);
+        assert_eq!(detector(uncapped, &[300], &[300, 300, 300]).compliance().verdict, Verdict::Inconclusive);
+    }
+
+    #[test]
+    fn test_raised_cap() {
+        // held at the old cap through the warmup, then free to rise to the new one
+        let raised = Expectation::for_scenario(CappingOrder::LevelToLevel, CappingOperation::Activate, 400, 580);
+        let compliance = detector(raised, &[399, 401, 400], &[400, 480, 560, 575, 578]).compliance();
+        assert_eq!(compliance.verdict, Verdict::Pass);
+        assert_eq!(compliance.time_to_compliance, Some(Duration::from_secs(2)));
+        assert_eq!(detector(raised, &[399, 401, 400], &[400, 401, 402, 400]).compliance().verdict, Verdict::Fail);
+    }
+
+    #[test]
+    fn test_rejected_cap() {
+        let rejected = Expectation::Rejected { limit: 580 };
+        // held at the cap it had throughout
+        let mut held = detector(rejected, &[578, 580, 579], &[580, 577, 581, 579]);
+        held.set_cap_accepted(false);
+        assert_eq!(held.compliance().verdict, Verdict::Pass);
+
+        // a BMC that takes the level below the minimum fails, whatever the power did
+        held.set_cap_accepted(true);
+        assert_eq!(held.compliance().verdict, Verdict::Fail);
+        let mut escaped = detector(rejected, &[580], &[580, 620, 650, 650]);
+        escaped.set_cap_accepted(false);
+        assert_eq!(escaped.compliance().verdict, Verdict::Fail);
+    }
+
+    #[test]
+    fn test_baseline_keeps_most_recent() {
+        let mut detector = ComplianceDetector::new(Expectation::Capped { limit: 400 });
+        for power in [100, 200, 300, 400, 500] {
+            detector.add_baseline(power);
+        }
+        assert_eq!(detector.baseline_power(), Some(400));
+    }
+}
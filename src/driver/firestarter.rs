@@ -1,65 +1,58 @@
-use log::{error, trace};
-use std::fmt::{self, Display, Formatter};
-use std::process::Command;
-use crate::cli::CONFIGURATION;
+use crate::driver::load_generator::{LoadCommand, LoadSpec};
+use crate::topology::format_cpu_list;
 
 #[derive(Debug)]
-/// Hold the firestarter configuration
+/// Builds the firestarter command line for a trial's load (see `load_generator.rs`)
 pub struct Firestarter {
     path: String,
-    runtime_secs: u64,
-    load_pct: u64,
-    load_period_us: u64,
-    n_threads: u64,
 }
 
 impl Firestarter {
     #[must_use]
-    /// Creates a new firestarter instance ready to run performing basic validation which may cause...
-    /// # Panics
-    pub fn new(runtime_secs: u64, load_pct: u64, load_period_us: u64, n_threads: u64) -> Self {
-        assert!(load_pct > 0 && load_pct <= 100);
-        assert!(load_period_us == 0 || load_pct <= load_period_us);
-        Self {
-            path: (*CONFIGURATION.firestarter).to_string(),
-            runtime_secs,
-            load_pct,
-            load_period_us,
-            n_threads,
-        }
+    pub fn new(path: &str) -> Self {
+        Self { path: String::from(path) }
     }
+}
 
-    /// Launches firestarter. This is done on a separate thread.
-    // TODO: Might be pertinent to bind threads to processors to see if there's
-    //       uneven capping across domains.
-    pub fn run(&self) {
-        trace!("FIRESTARTER LAUNCHING:\n{self}");
-        let firestarter = Command::new(&self.path)
-            .arg("--quiet")
-            .arg("--timeout")
-            .arg(self.runtime_secs.to_string())
-            .arg("--load")
-            .arg(self.load_pct.to_string())
-            .arg("--period")
-            .arg(self.load_period_us.to_string())
-            .arg("--threads")
-            .arg(self.n_threads.to_string())
-            .spawn()
-            .expect("Firestarter failed to launch");
-
-        match firestarter.wait_with_output() {
-            Ok(_) => trace!("FIRESTARTER exited successfully"),
-            Err(e) => error!("FIRESTARTER failed: {e:?}"),
+impl LoadCommand for Firestarter {
+    /// Bound threads are given to firestarter as its `--bind` CPU list, which
+    /// replaces `--threads`
+    fn command_line(&self, load: &LoadSpec) -> Vec<String> {
+        let mut command_line = vec![
+            self.path.clone(),
+            String::from("--timeout"),
+            load.duration_secs.to_string(),
+            String::from("--load"),
+            load.load_pct.to_string(),
+            String::from("--period"),
+            load.load_period_us.to_string(),
+        ];
+        match &load.cpus {
+            Some(cpus) => command_line.extend([String::from("--bind"), format_cpu_list(cpus, ",")]),
+            None => command_line.extend([String::from("--threads"), load.n_threads.to_string()]),
         }
+        command_line
+    }
+
+    fn program(&self) -> &str {
+        &self.path
     }
 }
 
-impl Display for Firestarter {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{} --timeout {} --load {} --period {} --threads {}",
-            self.path, self.runtime_secs, self.load_pct, self.load_period_us, self.n_threads
-        )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line() {
+        let firestarter = Firestarter::new("/opt/firestarter");
+        assert_eq!(
+            firestarter.command_line(&LoadSpec::new(90, 10_000, 0, 25).unwrap()).join(" "),
+            "/opt/firestarter --timeout 25 --load 90 --period 10000 --threads 0"
+        );
+        assert_eq!(
+            firestarter.command_line(&LoadSpec::new(90, 10_000, 0, 25).unwrap().with_cpus(Some(vec![0, 1, 2, 8]))).join(" "),
+            "/opt/firestarter --timeout 25 --load 90 --period 10000 --bind 0-2,8"
+        );
     }
 }
@@ -0,0 +1,275 @@
+//! The campaign journal: a JSON-lines file in the stats directory that records the
+//! expanded list of trials when a campaign starts, then one line per completed trial.
+//! An interrupted campaign can be resumed from it (see `--resume`), skipping the trials
+//! that have already completed. Each trial's compliance is kept, for the results that
+//! are drawn from the whole campaign (see `sweep.rs`), the journal recording whether
+//! the campaign is a sweep for a resumed run to draw them without `--sweep`.
+//!
+//! A search (see `search.rs`) chooses its trials as it goes, so its journal lists
+//! none up front; resuming it replays the search with the verdicts journaled so far.
+//! The journal also keeps the monotonic clock's epoch, for a resumed run's monotonic
+//! timestamps to carry on from the first run's (see `clock.rs`).
+
+use crate::campaign::TrialSpec;
+use crate::clock;
+use crate::driver::compliance::{Compliance, Verdict};
+use crate::ResultType;
+use chrono::{DateTime, Local};
+use glob::glob;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum JournalRecord {
+    /// Always the first line: the trials of the campaign, in the order they are run
+    Campaign {
+        trials: Vec<TrialSpec>,
+        #[serde(default)]
+        search: bool,
+        #[serde(default)]
+        sweep: bool,
+        /// The monotonic clock's epoch, RFC 3339
+        #[serde(default)]
+        epoch: Option<String>,
+    },
+    Completed {
+        trial_id: u64,
+        verdict: Verdict,
+        time_to_compliance_millis: Option<u64>,
+        #[serde(default)]
+        achieved_power: Option<u64>,
+    },
+}
+
+/// The state of a campaign, as read back from its journal
+#[derive(Debug, PartialEq)]
+pub struct JournalState {
+    pub trials: Vec<TrialSpec>,
+    /// True if the journal is a search's
+    pub search: bool,
+    /// True if the campaign is a cap sweep, whose compliance curve is drawn from the
+    /// journal
+    pub sweep: bool,
+    /// The monotonic clock's epoch, if journaled
+    pub epoch: Option<DateTime<Local>>,
+    /// The compliance of each completed trial, by trial id
+    pub completed: HashMap<u64, Compliance>,
+}
+
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Path of the journal for the run with the given test timestamp
+    #[must_use]
+    pub fn path(stats_dir: &str, prefix: &str, test_timestamp: &str) -> PathBuf {
+        Path::new(stats_dir).join(format!("{prefix}_{test_timestamp}.jsonl"))
+    }
+
+    /// The test timestamp of the most recent journal in `stats_dir`, if any. Test
+    /// timestamps sort chronologically.
+    #[must_use]
+    pub fn latest_timestamp(stats_dir: &str, prefix: &str) -> Option<String> {
+        let pattern = Journal::path(stats_dir, prefix, "*");
+        glob(pattern.to_str()?)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|path| {
+                let stem = path.file_stem()?.to_str()?;
+                Some(String::from(stem.strip_prefix(prefix)?.strip_prefix('_')?))
+            })
+            .max()
+    }
+
+    /// Starts a new journal, recording the campaign's trials and whether it's a sweep
+    ///
+    /// # Errors
+    /// If the journal already exists or can't be written
+    pub fn create(path: &Path, trials: &[TrialSpec], sweep: bool) -> ResultType<Self> {
+        Journal::create_with(path, JournalRecord::Campaign {
+            trials: trials.to_vec(),
+            search: false,
+            sweep,
+            epoch: Journal::epoch(),
+        })
+    }
+
+    /// Starts a new journal for a search, whose trials aren't known up front
+    ///
+    /// # Errors
+    /// If the journal already exists or can't be written
+    pub fn create_search(path: &Path) -> ResultType<Self> {
+        Journal::create_with(path, JournalRecord::Campaign {
+            trials: Vec::new(),
+            search: true,
+            sweep: false,
+            epoch: Journal::epoch(),
+        })
+    }
+
+    fn create_with(path: &Path, campaign: JournalRecord) -> ResultType<Self> {
+        let file = OpenOptions::new().create_new(true).append(true).open(path)?;
+        let mut journal = Self { file };
+        journal.append(&campaign)?;
+        Ok(journal)
+    }
+
+    fn epoch() -> Option<String> {
+        Some(clock::epoch_wallclock().to_rfc3339())
+    }
+
+    /// Reads an existing journal and opens it to record further trials
+    ///
+    /// # Errors
+    /// If the journal can't be read or doesn't start with the campaign's trials
+    pub fn resume(path: &Path) -> ResultType<(Self, JournalState)> {
+        let contents = fs::read_to_string(path)?;
+        let state = Journal::read(&contents)?;
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        // terminate any partly written record, so as not to corrupt the next one
+        if !contents.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+        Ok((Self { file }, state))
+    }
+
+    /// Reads an existing journal without opening it for writing, e.g. for a dry run
+    ///
+    /// # Errors
+    /// If the journal can't be read or doesn't start with the campaign's trials
+    pub fn read_state(path: &Path) -> ResultType<JournalState> {
+        Journal::read(&fs::read_to_string(path)?)
+    }
+
+    /// Records a completed trial. The record is flushed to disk before returning so
+    /// that it survives the harness being killed.
+    ///
+    /// # Errors
+    /// If the record can't be written
+    pub fn record_completed(&mut self, trial_id: u64, compliance: Compliance) -> ResultType<()> {
+        self.append(&JournalRecord::Completed {
+            trial_id,
+            verdict: compliance.verdict,
+            time_to_compliance_millis: compliance
+                .time_to_compliance
+                .map(|t| u64::try_from(t.as_millis()).unwrap_or(u64::MAX)),
+            achieved_power: compliance.achieved_power,
+        })
+    }
+
+    fn append(&mut self, record: &JournalRecord) -> ResultType<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Parses the journal contents. A line that can't be parsed, typically the last
+    /// one if the harness was killed while writing it, is skipped.
+    fn read(contents: &str) -> ResultType<JournalState> {
+        let mut lines = contents.lines();
+        let Some(JournalRecord::Campaign { trials, search, sweep, epoch }) =
+            lines.next().and_then(|line| serde_json::from_str(line).ok())
+        else {
+            return Err("journal doesn't start with the campaign's trials".into());
+        };
+        let epoch = epoch
+            .map(|epoch| DateTime::parse_from_rfc3339(&epoch).map(|epoch| epoch.with_timezone(&Local)))
+            .transpose()
+            .map_err(|e| format!("journal has an invalid clock epoch: {e}"))?;
+
+        let mut completed = HashMap::new();
+        for line in lines {
+            match serde_json::from_str(line) {
+                Ok(JournalRecord::Completed { trial_id, verdict, time_to_compliance_millis, achieved_power }) => {
+                    completed.insert(trial_id, Compliance {
+                        verdict,
+                        time_to_compliance: time_to_compliance_millis.map(Duration::from_millis),
+                        achieved_power,
+                    });
+                }
+                Ok(JournalRecord::Campaign { .. }) => return Err("journal contains more than one campaign".into()),
+                Err(e) => warn!("Skipping unreadable journal entry {line:?}: {e}"),
+            }
+        }
+        Ok(JournalState { trials, search, sweep, epoch, completed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::campaign::Campaign;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_journal_round_trip() {
+        let dir = std::env::temp_dir().join(format!("journal_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let stats_dir = dir.to_str().unwrap();
+        assert_eq!(Journal::latest_timestamp(stats_dir, "journal"), None);
+
+        let trials = Campaign::builtin(400, 580, 10, 15).trials(4);
+        let path = Journal::path(stats_dir, "journal", "230601_0930");
+        let mut journal = Journal::create(&path, &trials, true).unwrap();
+        let passed = Compliance { verdict: Verdict::Pass, time_to_compliance: Some(Duration::from_millis(2500)), achieved_power: Some(398) };
+        journal.record_completed(0, passed).unwrap();
+        journal.record_completed(1, Compliance { verdict: Verdict::Fail, time_to_compliance: None, achieved_power: Some(450) }).unwrap();
+        drop(journal);
+
+        // as if killed while writing the third record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"record\":\"completed\",\"tri").unwrap();
+        drop(file);
+        File::create(Journal::path(stats_dir, "journal", "230531_2359")).unwrap();
+
+        assert_eq!(Journal::latest_timestamp(stats_dir, "journal"), Some(String::from("230601_0930")));
+        let (mut journal, state) = Journal::resume(&path).unwrap();
+        assert_eq!(state.trials, trials);
+        assert!(!state.search);
+        assert!(state.sweep);
+        assert_eq!(state.epoch, Some(clock::epoch_wallclock()));
+        assert_eq!(state.completed.keys().copied().collect::<HashSet<u64>>(), HashSet::from([0, 1]));
+        assert_eq!(state.completed[&0], passed);
+        journal.record_completed(2, Compliance { verdict: Verdict::Inconclusive, time_to_compliance: None, achieved_power: None }).unwrap();
+        drop(journal);
+        let completed = Journal::resume(&path).unwrap().1.completed;
+        assert_eq!(completed.keys().copied().collect::<HashSet<u64>>(), HashSet::from([0, 1, 2]));
+
+        // an existing journal is never overwritten
+        assert!(Journal::create(&path, &trials, false).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_search_journal() {
+        let path = std::env::temp_dir().join(format!("search_journal_test_{}.jsonl", std::process::id()));
+        let mut journal = Journal::create_search(&path).unwrap();
+        journal.record_completed(0, Compliance { verdict: Verdict::Pass, time_to_compliance: None, achieved_power: None }).unwrap();
+        drop(journal);
+        let state = Journal::read_state(&path).unwrap();
+        assert!(state.search);
+        assert!(state.trials.is_empty());
+        assert_eq!(state.completed.len(), 1);
+        fs::remove_file(path).unwrap();
+
+        // journals from before the search flag and clock epoch were recorded
+        let state = Journal::read("{\"record\":\"campaign\",\"trials\":[]}").unwrap();
+        assert!(!state.search && !state.sweep);
+        assert_eq!(state.epoch, None);
+    }
+
+    #[test]
+    fn test_journal_without_campaign() {
+        assert!(Journal::read("").is_err());
+        assert!(Journal::read("{\"record\":\"completed\",\"trial_id\":0,\"verdict\":\"pass\",\"time_to_compliance_millis\":null}").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::driver::load_generator::{LoadCommand, LoadSpec};
+use crate::topology::format_cpu_list;
+use crate::ResultType;
+
+/// The placeholders that can appear in a command template, each replaced by the
+/// trial's value
+const PLACEHOLDERS: [&str; 4] = ["{load_pct}", "{load_period_us}", "{n_threads}", "{duration_secs}"];
+
+#[derive(Debug)]
+/// A user supplied load generator command (see `--load-command`), for example
+/// `my-load --busy {load_pct} --period {load_period_us} -j {n_threads} -t {duration_secs}`.
+/// The template is split on whitespace; quoting isn't supported. If the threads are
+/// bound to CPUs, the command is run under `taskset -c`.
+pub struct LoadCommandTemplate {
+    words: Vec<String>,
+}
+
+impl LoadCommandTemplate {
+    /// # Errors
+    /// If the template is empty, or has a placeholder that isn't known
+    pub fn parse(template: &str) -> ResultType<Self> {
+        let words: Vec<String> = template.split_whitespace().map(String::from).collect();
+        if words.is_empty() {
+            return Err("the load command template is empty".into());
+        }
+        for word in &words {
+            let rest = PLACEHOLDERS.iter().fold(word.clone(), |word, placeholder| word.replace(placeholder, ""));
+            if rest.contains('{') && rest.contains('}') {
+                return Err(format!(
+                    "unknown placeholder in {word:?} in the load command template, expected one of {}",
+                    PLACEHOLDERS.join(", ")
+                ).into());
+            }
+        }
+        Ok(Self { words })
+    }
+}
+
+impl LoadCommand for LoadCommandTemplate {
+    fn command_line(&self, load: &LoadSpec) -> Vec<String> {
+        let values = [load.load_pct, load.load_period_us, load.effective_threads(), load.duration_secs];
+        let taskset = match &load.cpus {
+            Some(cpus) => vec![String::from("taskset"), String::from("-c"), format_cpu_list(cpus, ",")],
+            None => Vec::new(),
+        };
+        taskset
+            .into_iter()
+            .chain(self.words.iter().map(|word| {
+                PLACEHOLDERS
+                    .iter()
+                    .zip(values)
+                    .fold(word.clone(), |word, (placeholder, value)| word.replace(placeholder, &value.to_string()))
+            }))
+            .collect()
+    }
+
+    fn program(&self) -> &str {
+        &self.words[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_template() {
+        let template = LoadCommandTemplate::parse("my-load --busy={load_pct} -p {load_period_us}  -j {n_threads} -t {duration_secs}s").unwrap();
+        assert_eq!(template.program(), "my-load");
+        assert_eq!(
+            template.command_line(&LoadSpec::new(90, 10_000, 4, 25).unwrap()).join(" "),
+            "my-load --busy=90 -p 10000 -j 4 -t 25s"
+        );
+
+        assert_eq!(
+            template.command_line(&LoadSpec::new(90, 10_000, 0, 25).unwrap().with_cpus(Some(vec![1, 3]))).join(" "),
+            "taskset -c 1,3 my-load --busy=90 -p 10000 -j 2 -t 25s"
+        );
+
+        assert!(LoadCommandTemplate::parse("  ").is_err());
+        assert!(LoadCommandTemplate::parse("my-load --busy {load}").is_err());
+    }
+}
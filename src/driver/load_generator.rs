@@ -0,0 +1,397 @@
+//! Load generators: the programs that load the server during a trial. Firestarter is
+//! the default; stress-ng, a user supplied command template or the built-in generator
+//! (see `builtin_load.rs`) can be used instead (see `--load-generator`), for systems
+//! without firestarter or to compare load shapes.
+//!
+//! An external generator's output goes to a log file per trial, and its exit status
+//! and runtime are checked against the load asked for, so that a generator that
+//! failed, or ended early, shows in the trial's `load_status` rather than as a
+//! trial that was mysteriously idle.
+
+use crate::cli::Configuration;
+use crate::driver::builtin_load::BuiltinLoad;
+use crate::driver::firestarter::Firestarter;
+use crate::driver::load_command::LoadCommandTemplate;
+use crate::driver::stress_ng::StressNg;
+use crate::shutdown;
+use crate::ResultType;
+use clap::ValueEnum;
+use log::{error, trace, warn};
+use std::fmt::{self, Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often to check whether the load generator has exited, or should be killed
+const EXIT_POLL_MILLIS: u64 = 100;
+// How much sooner than its duration a generator may exit by itself and still count
+// as having run for it, allowing for its own timer
+const EARLY_EXIT_TOLERANCE_MILLIS: u64 = 1000;
+/// The shortest load period, in µs, that the generators can run a duty cycle over
+pub const MIN_LOAD_PERIOD_US: u64 = 10;
+
+/// The load for a trial
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadSpec {
+    /// 1..=100
+    pub load_pct: u64,
+    /// Period over which the load is averaged, 0 = the generator's default
+    pub load_period_us: u64,
+    /// 0 = all available threads
+    pub n_threads: u64,
+    /// CPUs to bind the threads to, one thread per CPU, overriding `n_threads`
+    /// (see `topology.rs`)
+    pub cpus: Option<Vec<usize>>,
+    /// The generator stops by itself after this long, should the harness fail to stop it
+    pub duration_secs: u64,
+}
+
+impl LoadSpec {
+    /// # Errors
+    /// If the load isn't a percentage, or the period is too short to run it over
+    pub fn new(load_pct: u64, load_period_us: u64, n_threads: u64, duration_secs: u64) -> ResultType<Self> {
+        check_load_pct(load_pct)?;
+        check_load_period(load_period_us)?;
+        Ok(Self { load_pct, load_period_us, n_threads, cpus: None, duration_secs })
+    }
+
+    /// The number of threads to run: one per bound CPU, if bound, else `n_threads`
+    #[must_use]
+    pub fn effective_threads(&self) -> u64 {
+        self.cpus.as_ref().map_or(self.n_threads, |cpus| cpus.len() as u64)
+    }
+
+    #[must_use]
+    pub fn with_cpus(self, cpus: Option<Vec<usize>>) -> Self {
+        Self { cpus, ..self }
+    }
+}
+
+/// Checks that a load is a percentage, 1..=100
+///
+/// # Errors
+/// If it isn't
+pub fn check_load_pct(load_pct: u64) -> Result<(), String> {
+    if load_pct == 0 || load_pct > 100 {
+        return Err(format!("load_pct {load_pct} is not in 1..=100"));
+    }
+    Ok(())
+}
+
+/// Checks that a load period is either 0, the generator's default, or at least
+/// `MIN_LOAD_PERIOD_US`
+///
+/// # Errors
+/// If the period is too short
+pub fn check_load_period(load_period_us: u64) -> Result<(), String> {
+    if load_period_us != 0 && load_period_us < MIN_LOAD_PERIOD_US {
+        return Err(format!("load_period_us {load_period_us} is shorter than {MIN_LOAD_PERIOD_US} µs"));
+    }
+    Ok(())
+}
+
+/// How the load generator's run went, for the driver log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadStatus {
+    /// Ran until stopped, or for its full duration
+    Ok,
+    /// Exited by itself, successfully, after the given time, before its duration
+    /// was up or it was stopped
+    EndedEarly(Duration),
+    /// Couldn't be started, or exited with an error
+    Failed(String),
+    /// Killed on a shutdown request
+    Killed,
+}
+
+impl Display for LoadStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Self::Ok => "ok",
+            Self::EndedEarly(_) => "ended_early",
+            Self::Failed(_) => "failed",
+            Self::Killed => "killed",
+        })
+    }
+}
+
+pub trait LoadGenerator: Send {
+    /// Starts generating the load, returning once it has started. Any output of the
+    /// generator is appended to `output`, if given.
+    ///
+    /// # Errors
+    /// If the load can't be started
+    fn start(&mut self, load: &LoadSpec, output: Option<&Path>) -> ResultType<()>;
+
+    /// Stops the load before its duration is up. Does nothing if it isn't running.
+    fn stop(&mut self);
+
+    /// Waits for the load to end, stopping it if a shutdown is requested (see
+    /// `shutdown.rs`), and reports how it went
+    fn wait(&mut self) -> LoadStatus;
+
+    /// Changes the load while running, for the load profiles (see `load_profile.rs`),
+    /// returning how the run up to the change went. By default the generator is
+    /// restarted with the new load, as the external programs can't change theirs.
+    ///
+    /// # Errors
+    /// If the load can't be restarted
+    fn set_load(&mut self, load: &LoadSpec, output: Option<&Path>) -> ResultType<LoadStatus> {
+        self.stop();
+        let status = self.wait();
+        self.start(load, output)?;
+        Ok(status)
+    }
+
+    /// True if `set_load` changes the load without restarting the generator, and so
+    /// without a dip in the power, as the ramp and burst profiles need
+    fn changes_load_in_place(&self) -> bool {
+        false
+    }
+
+    /// What `start` would run, for logs and dry runs
+    fn describe(&self, load: &LoadSpec) -> String;
+
+    /// The executable the generator runs, if any, for the pre-flight checks
+    fn executable(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// A load generator that's an external program. Implementations build its command
+/// line, `ProcessGenerator` runs it.
+pub trait LoadCommand: Send {
+    /// The program followed by its arguments
+    fn command_line(&self, load: &LoadSpec) -> Vec<String>;
+
+    fn program(&self) -> &str;
+}
+
+/// Runs a `LoadCommand` as a child process
+pub struct ProcessGenerator<C: LoadCommand> {
+    command: C,
+    child: Option<Child>,
+    /// When the child was started, and the duration it was asked to run for
+    started: Instant,
+    duration: Duration,
+    /// True once the child has been killed by `stop`, so that its exit by a signal
+    /// isn't taken for a failure
+    stopped: bool,
+}
+
+impl<C: LoadCommand> ProcessGenerator<C> {
+    #[must_use]
+    pub fn new(command: C) -> Self {
+        Self { command, child: None, started: Instant::now(), duration: Duration::ZERO, stopped: false }
+    }
+}
+
+/// How a load generator's run went, given how it exited, after how long, whether it
+/// was stopped and the duration it was asked to run for
+fn exit_status(status: ExitStatus, runtime: Duration, stopped: bool, duration: Duration) -> LoadStatus {
+    if stopped {
+        LoadStatus::Ok
+    } else if !status.success() {
+        LoadStatus::Failed(status.to_string())
+    } else if runtime + Duration::from_millis(EARLY_EXIT_TOLERANCE_MILLIS) < duration {
+        LoadStatus::EndedEarly(runtime)
+    } else {
+        LoadStatus::Ok
+    }
+}
+
+impl<C: LoadCommand> LoadGenerator for ProcessGenerator<C> {
+    fn start(&mut self, load: &LoadSpec, output: Option<&Path>) -> ResultType<()> {
+        let command_line = self.command.command_line(load);
+        trace!("LOAD GENERATOR LAUNCHING: {}", command_line.join(" "));
+        let (program, args) = command_line.split_first().ok_or("empty load generator command")?;
+        let mut command = Command::new(program);
+        command.args(args);
+        if let Some(path) = output {
+            let mut log = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Failed to open load generator log {}: {e}", path.display()))?;
+            writeln!(log, "# {}", command_line.join(" "))?;
+            command.stdout(Stdio::from(log.try_clone()?)).stderr(Stdio::from(log));
+        }
+        let child = command.spawn().map_err(|e| format!("Failed to launch {program}: {e}"))?;
+        self.child = Some(child);
+        self.started = Instant::now();
+        self.duration = Duration::from_secs(load.duration_secs);
+        self.stopped = false;
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(child) = &mut self.child {
+            match child.try_wait() {
+                // exited by itself, `wait` reports how
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    self.stopped = true;
+                    if let Err(e) = child.kill() {
+                        error!("LOAD GENERATOR failed to kill: {e:?}");
+                    }
+                }
+                Err(e) => error!("LOAD GENERATOR failed: {e:?}"),
+            }
+        }
+    }
+
+    fn wait(&mut self) -> LoadStatus {
+        let Some(mut child) = self.child.take() else {
+            return LoadStatus::Ok;
+        };
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    let runtime = self.started.elapsed();
+                    trace!("LOAD GENERATOR exited after {} ms: {status}", runtime.as_millis());
+                    let load_status = exit_status(status, runtime, self.stopped, self.duration);
+                    match &load_status {
+                        LoadStatus::Failed(reason) => error!("LOAD GENERATOR failed: {reason}"),
+                        LoadStatus::EndedEarly(runtime) => warn!(
+                            "LOAD GENERATOR ended early, after {} ms of {} s",
+                            runtime.as_millis(),
+                            self.duration.as_secs()
+                        ),
+                        _ => {}
+                    }
+                    return load_status;
+                }
+                Ok(None) if shutdown::requested() => {
+                    warn!("LOAD GENERATOR killed: shutdown requested");
+                    if let Err(e) = child.kill().and_then(|()| child.wait()) {
+                        error!("LOAD GENERATOR failed to kill: {e:?}");
+                    }
+                    return LoadStatus::Killed;
+                }
+                Ok(None) => thread::sleep(Duration::from_millis(EXIT_POLL_MILLIS)),
+                Err(e) => {
+                    error!("LOAD GENERATOR failed: {e:?}");
+                    return LoadStatus::Failed(e.to_string());
+                }
+            }
+        }
+    }
+
+    fn describe(&self, load: &LoadSpec) -> String {
+        self.command.command_line(load).join(" ")
+    }
+
+    fn executable(&self) -> Option<&str> {
+        Some(self.command.program())
+    }
+}
+
+/// The load generators that can be selected on the command line
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum LoadGeneratorKind {
+    Firestarter,
+    StressNg,
+    /// The command given by `--load-command`
+    Command,
+    /// In-process threads running the kernel given by `--load-kernel`
+    Builtin,
+}
+
+impl Display for LoadGeneratorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Self::Firestarter => "firestarter",
+            Self::StressNg => "stress-ng",
+            Self::Command => "command",
+            Self::Builtin => "builtin",
+        })
+    }
+}
+
+/// The load generator selected by the configuration
+///
+/// # Errors
+/// If the command generator is selected without a valid command template
+pub fn from_config(config: &Configuration) -> ResultType<Box<dyn LoadGenerator>> {
+    Ok(match config.load_generator {
+        LoadGeneratorKind::Firestarter => Box::new(ProcessGenerator::new(Firestarter::new(&config.firestarter))),
+        LoadGeneratorKind::StressNg => Box::new(ProcessGenerator::new(StressNg::new(&config.stress_ng))),
+        LoadGeneratorKind::Command => {
+            let template = config.load_command.as_deref().ok_or("--load-generator command requires --load-command")?;
+            Box::new(ProcessGenerator::new(LoadCommandTemplate::parse(template)?))
+        }
+        LoadGeneratorKind::Builtin => Box::new(BuiltinLoad::new(config.load_kernel)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_process_generator() {
+        let load = LoadSpec::new(100, 0, 1, 30).unwrap();
+        let mut generator = ProcessGenerator::new(LoadCommandTemplate::parse("sleep {duration_secs}").unwrap());
+        assert_eq!(generator.describe(&load), "sleep 30");
+        assert_eq!(generator.executable(), Some("sleep"));
+
+        let start = Instant::now();
+        generator.start(&load, None).unwrap();
+        // restarted, as sleep can't change its load
+        assert_eq!(generator.set_load(&LoadSpec::new(50, 0, 1, 30).unwrap(), None).unwrap(), LoadStatus::Ok);
+        generator.stop();
+        assert_eq!(generator.wait(), LoadStatus::Ok);
+        assert!(start.elapsed() < Duration::from_secs(30));
+        // stopping or waiting once ended does nothing
+        generator.stop();
+        assert_eq!(generator.wait(), LoadStatus::Ok);
+
+        let mut missing = ProcessGenerator::new(LoadCommandTemplate::parse("/no/such/generator").unwrap());
+        assert!(missing.start(&load, None).is_err());
+    }
+
+    #[test]
+    fn test_process_generator_output() {
+        let path = env::temp_dir().join(format!("capping_load_output_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        // the output is logged, and an exit before the duration is up is caught
+        let load = LoadSpec::new(100, 0, 1, 30).unwrap();
+        let mut generator = ProcessGenerator::new(LoadCommandTemplate::parse("ls {duration_secs}").unwrap());
+        generator.start(&load, Some(&path)).unwrap();
+        assert_eq!(generator.wait(), LoadStatus::Failed(String::from("exit status: 2")));
+        let output = fs::read_to_string(&path).unwrap();
+        assert!(output.starts_with("# ls 30\n"));
+        assert!(output.lines().count() > 1, "{output}");
+
+        let mut generator = ProcessGenerator::new(LoadCommandTemplate::parse("echo {duration_secs}").unwrap());
+        generator.start(&load, Some(&path)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        // exited by itself, so not stopped
+        generator.stop();
+        assert!(matches!(generator.wait(), LoadStatus::EndedEarly(_)));
+        assert!(fs::read_to_string(&path).unwrap().ends_with("# echo 30\n30\n"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let (success, failure, killed) = (ExitStatus::from_raw(0), ExitStatus::from_raw(1 << 8), ExitStatus::from_raw(9));
+        let duration = Duration::from_secs(30);
+        assert_eq!(exit_status(success, Duration::from_millis(29_500), false, duration), LoadStatus::Ok);
+        assert_eq!(
+            exit_status(success, Duration::from_secs(10), false, duration),
+            LoadStatus::EndedEarly(Duration::from_secs(10))
+        );
+        assert_eq!(exit_status(failure, duration, false, duration), LoadStatus::Failed(String::from("exit status: 1")));
+        assert!(matches!(exit_status(killed, Duration::from_secs(10), false, duration), LoadStatus::Failed(_)));
+        // killed by `stop`
+        assert_eq!(exit_status(killed, Duration::from_secs(10), true, duration), LoadStatus::Ok);
+    }
+}
@@ -0,0 +1,185 @@
+//! Load profiles: how a trial's load changes once the cap has been requested, to see
+//! whether the BMC's correction copes with load changes under an active cap. The
+//! trial's `load_pct` is held through the warmup and up to the cap request; the
+//! profile then sets the load over the test time. Only the `builtin` load generator
+//! changes its load in place; the others are restarted for each change, which dips
+//! the power, so they can only take steps. In a campaign file:
+//!
+//! ```toml
+//! [[loads]]
+//! load_pct = [90]
+//! load_period_us = [10000]
+//! profiles = [
+//!     { kind = "constant" },
+//!     { kind = "step", to_pct = 50, after_secs = 5 },                # 90% -> 50% at 5s
+//!     { kind = "ramp", to_pct = 100, after_secs = 2, over_secs = 8 }, # 90% -> 100% from 2s to 10s
+//!     { kind = "burst", low_pct = 10, on_secs = 2, off_secs = 3 },    # 2s at 90%, 3s at 10%, ...
+//! ]
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+// Resolution at which a profile's load changes are scheduled, which sets the
+// granularity of the ramps
+const PROFILE_RESOLUTION_MILLIS: u64 = 100;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum LoadProfile {
+    /// The trial's load throughout
+    #[default]
+    Constant,
+    /// Steps to `to_pct`, `after_secs` after the cap request
+    Step { to_pct: u64, after_secs: u64 },
+    /// Ramps linearly to `to_pct` over `over_secs`, starting `after_secs` after the
+    /// cap request
+    Ramp { to_pct: u64, after_secs: u64, over_secs: u64 },
+    /// Alternates between the trial's load for `on_secs` and `low_pct` for
+    /// `off_secs`, from the cap request
+    Burst { low_pct: u64, on_secs: u64, off_secs: u64 },
+}
+
+impl LoadProfile {
+    /// The load, given the trial's load, `since_cap_request` after the cap request
+    #[must_use]
+    #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn load_at(&self, load_pct: u64, since_cap_request: Duration) -> u64 {
+        match *self {
+            Self::Constant => load_pct,
+            Self::Step { to_pct, after_secs } => {
+                if since_cap_request >= Duration::from_secs(after_secs) { to_pct } else { load_pct }
+            }
+            Self::Ramp { to_pct, after_secs, over_secs } => {
+                let into_ramp = since_cap_request.saturating_sub(Duration::from_secs(after_secs));
+                let fraction = (into_ramp.as_secs_f64() / over_secs as f64).min(1.0);
+                (load_pct as f64 + (to_pct as f64 - load_pct as f64) * fraction).round() as u64
+            }
+            Self::Burst { low_pct, on_secs, off_secs } => {
+                let into_cycle = since_cap_request.as_secs_f64() % (on_secs + off_secs) as f64;
+                if into_cycle < on_secs as f64 { load_pct } else { low_pct }
+            }
+        }
+    }
+
+    /// The changes of load over `duration` from the cap request: the time of each,
+    /// since the cap request, and the load it sets
+    #[must_use]
+    pub fn schedule(&self, load_pct: u64, duration: Duration) -> Vec<(Duration, u64)> {
+        let resolution = Duration::from_millis(PROFILE_RESOLUTION_MILLIS);
+        let mut changes = Vec::new();
+        let mut current = load_pct;
+        let mut at = Duration::ZERO;
+        while at < duration && *self != Self::Constant {
+            let load = self.load_at(load_pct, at);
+            if load != current {
+                changes.push((at, load));
+                current = load;
+            }
+            at += resolution;
+        }
+        changes
+    }
+
+    /// The load levels the profile sets, other than the trial's own
+    #[must_use]
+    pub fn levels(&self) -> Vec<u64> {
+        match *self {
+            Self::Constant => Vec::new(),
+            Self::Step { to_pct, .. } | Self::Ramp { to_pct, .. } => vec![to_pct],
+            Self::Burst { low_pct, .. } => vec![low_pct],
+        }
+    }
+
+    /// True if the profile changes the load more than once, which needs a load
+    /// generator that can change it in place (see `LoadGenerator::changes_load_in_place`)
+    #[must_use]
+    pub fn changes_repeatedly(&self) -> bool {
+        matches!(self, Self::Ramp { .. } | Self::Burst { .. })
+    }
+
+    /// Checks the profile's levels and times
+    ///
+    /// # Errors
+    /// Describing the first problem found
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(level) = self.levels().iter().find(|level| **level == 0 || **level > 100) {
+            return Err(format!("profile {self}: load {level} is not in 1..=100"));
+        }
+        match *self {
+            Self::Ramp { over_secs: 0, .. } => Err(format!("profile {self}: over_secs must be greater than 0")),
+            Self::Burst { on_secs, off_secs, .. } if on_secs == 0 || off_secs == 0 => {
+                Err(format!("profile {self}: on_secs and off_secs must be greater than 0"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Display for LoadProfile {
+    /// Compact, and free of commas, for the CSV files: `constant`, `step:TO@AFTER`,
+    /// `ramp:TO@AFTER+OVER` or `burst:LOW:ON/OFF`, with times in seconds
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Constant => write!(f, "constant"),
+            Self::Step { to_pct, after_secs } => write!(f, "step:{to_pct}@{after_secs}"),
+            Self::Ramp { to_pct, after_secs, over_secs } => write!(f, "ramp:{to_pct}@{after_secs}+{over_secs}"),
+            Self::Burst { low_pct, on_secs, off_secs } => write!(f, "burst:{low_pct}:{on_secs}/{off_secs}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_at() {
+        let secs = Duration::from_secs_f64;
+
+        assert_eq!(LoadProfile::Constant.load_at(90, secs(100.0)), 90);
+
+        let step = LoadProfile::Step { to_pct: 50, after_secs: 5 };
+        assert_eq!([0.0, 4.9, 5.0, 20.0].map(|t| step.load_at(90, secs(t))), [90, 90, 50, 50]);
+
+        let ramp = LoadProfile::Ramp { to_pct: 100, after_secs: 2, over_secs: 8 };
+        assert_eq!([0.0, 2.0, 6.0, 10.0, 30.0].map(|t| ramp.load_at(80, secs(t))), [80, 80, 90, 100, 100]);
+        let ramp_down = LoadProfile::Ramp { to_pct: 50, after_secs: 0, over_secs: 10 };
+        assert_eq!(ramp_down.load_at(100, secs(5.0)), 75);
+
+        let burst = LoadProfile::Burst { low_pct: 10, on_secs: 2, off_secs: 3 };
+        assert_eq!([0.0, 1.9, 2.0, 4.9, 5.0, 7.5].map(|t| burst.load_at(90, secs(t))), [90, 90, 10, 10, 90, 10]);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let secs = Duration::from_secs_f64;
+        assert!(LoadProfile::Constant.schedule(90, secs(15.0)).is_empty());
+        assert_eq!(LoadProfile::Step { to_pct: 50, after_secs: 5 }.schedule(90, secs(15.0)), [(secs(5.0), 50)]);
+        // a step after the test time never happens
+        assert!(LoadProfile::Step { to_pct: 50, after_secs: 20 }.schedule(90, secs(15.0)).is_empty());
+        assert_eq!(
+            LoadProfile::Burst { low_pct: 10, on_secs: 2, off_secs: 3 }.schedule(90, secs(8.0)),
+            [(secs(2.0), 10), (secs(5.0), 90), (secs(7.0), 10)]
+        );
+        // a 1% step every 0.4 s
+        let ramp = LoadProfile::Ramp { to_pct: 100, after_secs: 1, over_secs: 4 }.schedule(90, secs(15.0));
+        assert_eq!(ramp.len(), 10);
+        assert_eq!(ramp.first().unwrap().1, 91);
+        assert_eq!(*ramp.last().unwrap(), (secs(4.8), 100));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(LoadProfile::Constant.validate().is_ok());
+        assert!(LoadProfile::Step { to_pct: 50, after_secs: 0 }.validate().is_ok());
+        assert!(LoadProfile::Step { to_pct: 0, after_secs: 5 }.validate().is_err());
+        assert!(LoadProfile::Ramp { to_pct: 101, after_secs: 0, over_secs: 5 }.validate().is_err());
+        assert!(LoadProfile::Ramp { to_pct: 50, after_secs: 0, over_secs: 0 }.validate().is_err());
+        assert!(LoadProfile::Burst { low_pct: 10, on_secs: 2, off_secs: 0 }.validate().is_err());
+        assert_eq!(LoadProfile::Ramp { to_pct: 50, after_secs: 2, over_secs: 8 }.to_string(), "ramp:50@2+8");
+        assert!(!LoadProfile::Step { to_pct: 50, after_secs: 0 }.changes_repeatedly());
+        assert!(LoadProfile::Burst { low_pct: 10, on_secs: 2, off_secs: 3 }.changes_repeatedly());
+    }
+}
@@ -0,0 +1,441 @@
+//! Adaptive search for the conditions where capping stops working. Rather than run every
+//! load level of the campaign, for each capping scenario bisect one parameter at a time
+//! (load percentage, load period or thread count, the others held fixed) to locate the
+//! boundary between trials where the cap took effect and trials where it didn't. Once
+//! the boundary is narrowed down to the search resolution, the trials either side of it
+//! are repeated to check that it isn't an artefact of noise.
+
+use crate::campaign::{Campaign, SearchSpace, TrialSpec};
+use crate::cli::Configuration;
+use crate::core_count;
+use crate::driver::compliance::{Compliance, Verdict};
+use crate::driver::journal::Journal;
+use crate::driver::load_profile::LoadProfile;
+use crate::driver::trial::Trial;
+use crate::driver::{CappingOperation, CappingOrder};
+use crate::monitor::MonitorMessage;
+use crate::shutdown;
+use crate::ResultType;
+use log::{debug, info};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+/// The trial parameter varied by a search
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum SearchDimension {
+    /// Load percentage, at the search's fixed load period, on all threads
+    LoadPct,
+    /// Load period, at the search's fixed load percentage, on all threads
+    LoadPeriod,
+    /// Number of threads, each at full load
+    Threads,
+}
+
+impl Display for SearchDimension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}",
+            match self {
+                Self::LoadPct => "load_pct",
+                Self::LoadPeriod => "load_period_us",
+                Self::Threads => "n_threads",
+            }
+        )
+    }
+}
+
+/// The outcome of bisecting a range of values
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Bisection {
+    /// Both ends of the range gave the same outcome
+    NoBoundary { fails: bool },
+    /// The outcome changes between `lo` and `hi`, which are no more than the
+    /// resolution apart
+    Boundary { lo: u64, hi: u64, lo_fails: bool },
+    /// The outcome at `at` couldn't be told, so the bisection stopped there
+    Unresolved { at: u64 },
+}
+
+/// Bisects `lo..=hi` for the point at which `fails` changes outcome, assuming it
+/// changes at most once in the range. `fails` returns None if it can't tell, which
+/// stops the bisection.
+pub fn bisect(mut lo: u64, mut hi: u64, resolution: u64, mut fails: impl FnMut(u64) -> Option<bool>) -> Bisection {
+    let Some(lo_fails) = fails(lo) else {
+        return Bisection::Unresolved { at: lo };
+    };
+    match fails(hi) {
+        None => return Bisection::Unresolved { at: hi },
+        Some(hi_fails) if hi_fails == lo_fails => return Bisection::NoBoundary { fails: lo_fails },
+        Some(_) => {}
+    }
+
+    while hi - lo > resolution {
+        let mid = lo + (hi - lo) / 2;
+        match fails(mid) {
+            None => return Bisection::Unresolved { at: mid },
+            Some(mid_fails) if mid_fails == lo_fails => lo = mid,
+            Some(_) => hi = mid,
+        }
+    }
+    Bisection::Boundary { lo, hi, lo_fails }
+}
+
+/// The result of searching one dimension of a capping scenario
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub cap_from: u64,
+    pub cap_to: u64,
+    pub capping_order: CappingOrder,
+    pub capping_operation: CappingOperation,
+    pub dimension: SearchDimension,
+    /// The range searched
+    pub range: (u64, u64),
+    pub bisection: Bisection,
+    /// Number of trials that didn't pass out of `boundary_repeats` either side of the
+    /// boundary
+    pub lo_failures: u64,
+    pub hi_failures: u64,
+    pub boundary_repeats: u64,
+    /// Total number of trials run for the search
+    pub trials: u64,
+}
+
+impl SearchResult {
+    /// True if the repeated trials either side of the boundary still give different
+    /// majority outcomes
+    #[must_use]
+    pub fn is_confirmed(&self) -> bool {
+        match self.bisection {
+            Bisection::NoBoundary { .. } | Bisection::Unresolved { .. } => false,
+            Bisection::Boundary { .. } => {
+                is_majority(self.lo_failures, self.boundary_repeats)
+                    != is_majority(self.hi_failures, self.boundary_repeats)
+            }
+        }
+    }
+
+    fn bisection_summary(&self) -> String {
+        match self.bisection {
+            Bisection::NoBoundary { fails } => format!(
+                "no boundary in {}..={}, capping {}",
+                self.range.0,
+                self.range.1,
+                if fails { "always fails" } else { "always works" }
+            ),
+            Bisection::Boundary { lo, hi, lo_fails } => format!(
+                "capping {} at {lo} and {} at {hi} ({}/{} and {}/{} repeats failed{})",
+                if lo_fails { "fails" } else { "works" },
+                if lo_fails { "works" } else { "fails" },
+                self.lo_failures,
+                self.boundary_repeats,
+                self.hi_failures,
+                self.boundary_repeats,
+                if self.is_confirmed() { "" } else { ", not confirmed" }
+            ),
+            Bisection::Unresolved { at } => format!("unresolved, every trial at {at} was inconclusive"),
+        }
+    }
+}
+
+impl Display for SearchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (outcome, lo, hi) = match self.bisection {
+            Bisection::NoBoundary { fails: true } => ("all_fail", self.range.0, self.range.1),
+            Bisection::NoBoundary { fails: false } => ("all_pass", self.range.0, self.range.1),
+            Bisection::Boundary { lo, hi, .. } => ("boundary", lo, hi),
+            Bisection::Unresolved { at } => ("unresolved", at, at),
+        };
+        write!(f, "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.cap_from,
+            self.cap_to,
+            self.capping_order,
+            self.capping_operation,
+            self.dimension,
+            outcome,
+            lo,
+            hi,
+            self.lo_failures,
+            self.hi_failures,
+            self.boundary_repeats,
+            self.is_confirmed(),
+            self.trials,
+        )
+    }
+}
+
+fn is_majority(count: u64, out_of: u64) -> bool {
+    count * 2 > out_of
+}
+
+/// Runs the search trials, numbering them on from `next_trial_id`. A trial whose
+/// verdict is already journaled, in a resumed search, isn't run again.
+pub(crate) struct Search<'a> {
+    campaign: &'a Campaign,
+    config: Arc<Configuration>,
+    monitor_tx: Sender<MonitorMessage>,
+    journal: Journal,
+    completed: HashMap<u64, Compliance>,
+    next_trial_id: u64,
+}
+
+impl<'a> Search<'a> {
+    pub fn new(
+        campaign: &'a Campaign,
+        config: Arc<Configuration>,
+        monitor_tx: Sender<MonitorMessage>,
+        journal: Journal,
+        completed: HashMap<u64, Compliance>,
+    ) -> Self {
+        Self {
+            campaign,
+            config,
+            monitor_tx,
+            journal,
+            completed,
+            next_trial_id: 0,
+        }
+    }
+
+    /// Searches each dimension of each capping scenario of the campaign
+    pub fn run(&mut self) -> Vec<SearchResult> {
+        let campaign = self.campaign;
+        let mut results = Vec::new();
+        for &(cap_from, cap_to) in &campaign.cap_pairs() {
+            for &capping_order in &campaign.capping_orders {
+                for &capping_operation in &campaign.capping_operations() {
+                    // e.g. no sense in running Level after Activate when operation is Deactivate
+                    if !capping_order.runs_with(capping_operation) {
+                        continue;
+                    }
+                    for &dimension in &campaign.search.dimensions {
+                        let base = TrialSpec {
+                            trial_id: 0,
+                            repetition: 0,
+                            seed: None,
+                            cap_from,
+                            cap_to,
+                            capping_order,
+                            capping_operation,
+                            load_pct: 100,
+                            load_period_us: 0,
+                            n_threads: 0,
+                            profile: LoadProfile::Constant,
+                            warmup_secs: campaign.warmup_secs,
+                            test_time_secs: campaign.test_time_secs,
+                        };
+                        let result = self.search(&base, dimension);
+                        // a search cut short by a shutdown request has no meaningful result
+                        if shutdown::requested() {
+                            info!("Shutdown requested, abandoning search");
+                            return results;
+                        }
+                        if let Some(result) = result {
+                            info!(
+                                "Search {capping_order} {capping_operation} {cap_from} => {cap_to} W, {dimension}: {}",
+                                result.bisection_summary()
+                            );
+                            results.push(result);
+                        }
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    /// Bisects one dimension, then repeats the trials either side of any boundary found.
+    /// Returns None if the dimension can't be searched on this machine.
+    fn search(&mut self, base: &TrialSpec, dimension: SearchDimension) -> Option<SearchResult> {
+        let campaign = self.campaign;
+        let space: &SearchSpace = &campaign.search;
+        let (range, resolution) = match dimension {
+            SearchDimension::LoadPct => (space.load_pct, space.load_pct_resolution),
+            SearchDimension::LoadPeriod => (space.load_period_us, space.load_period_resolution_us),
+            SearchDimension::Threads => {
+                let core_count = core_count();
+                if core_count < 2 {
+                    info!("Can't search thread counts with only one core");
+                    return None;
+                }
+                ((1, core_count), space.threads_resolution)
+            }
+        };
+        let (probe_repeats, boundary_repeats) = (space.probe_repeats, space.boundary_repeats);
+
+        let first_trial_id = self.next_trial_id;
+        // a value whose trials were all inconclusive says nothing about the boundary
+        let bisection = bisect(range.0, range.1, resolution, |value| {
+            let (failures, conclusive) = self.run_trials(base, dimension, value, probe_repeats);
+            (conclusive > 0).then(|| is_majority(failures, probe_repeats))
+        });
+
+        let (lo_failures, hi_failures) = match bisection {
+            Bisection::Boundary { lo, hi, .. } => (
+                self.run_trials(base, dimension, lo, boundary_repeats).0,
+                self.run_trials(base, dimension, hi, boundary_repeats).0,
+            ),
+            Bisection::NoBoundary { .. } | Bisection::Unresolved { .. } => (0, 0),
+        };
+
+        Some(SearchResult {
+            cap_from: base.cap_from,
+            cap_to: base.cap_to,
+            capping_order: base.capping_order,
+            capping_operation: base.capping_operation,
+            dimension,
+            range,
+            bisection,
+            lo_failures,
+            hi_failures,
+            boundary_repeats,
+            trials: self.next_trial_id - first_trial_id,
+        })
+    }
+
+    /// Runs `repeats` trials with the dimension set to `value` and returns the number
+    /// in which capping didn't pass, inconclusive trials included, and the number with
+    /// a conclusive verdict
+    fn run_trials(&mut self, base: &TrialSpec, dimension: SearchDimension, value: u64, repeats: u64) -> (u64, u64) {
+        let space = &self.campaign.search;
+        let mut spec = base.clone();
+        match dimension {
+            SearchDimension::LoadPct => {
+                spec.load_pct = value;
+                spec.load_period_us = space.load_search_period_us;
+            }
+            SearchDimension::LoadPeriod => {
+                spec.load_pct = space.period_search_load_pct;
+                spec.load_period_us = value;
+            }
+            SearchDimension::Threads => spec.n_threads = value,
+        }
+
+        let (mut failures, mut conclusive) = (0, 0);
+        for repetition in 0..repeats {
+            if shutdown::requested() {
+                break;
+            }
+            spec.trial_id = self.next_trial_id;
+            spec.repetition = repetition;
+            self.next_trial_id += 1;
+
+            let verdict = if let Some(compliance) = self.completed.get(&spec.trial_id) {
+                debug!("Trial {} already completed: {}", spec.trial_id, compliance.verdict);
+                compliance.verdict
+            } else {
+                let mut trial = Trial::new(spec.clone(), Arc::clone(&self.config), self.monitor_tx.clone());
+                trial.run();
+                if trial.was_interrupted() {
+                    break;
+                }
+                self.journal
+                    .record_completed(spec.trial_id, trial.compliance())
+                    .expect("Failed to record trial in campaign journal");
+                trial.compliance().verdict
+            };
+            if verdict != Verdict::Pass {
+                failures += 1;
+            }
+            if verdict != Verdict::Inconclusive {
+                conclusive += 1;
+            }
+        }
+        debug!("Search {dimension} = {value}: {failures}/{repeats} trials didn't pass, {conclusive} conclusive");
+        (failures, conclusive)
+    }
+}
+
+/// Writes the search results to CSV file.
+pub(crate) fn save_search_results(config: &Configuration, results: &[SearchResult]) -> ResultType<PathBuf> {
+    let filename = format!("{}_{}.csv",
+        config.search_results_filename_prefix,
+        config.test_timestamp
+    );
+    let filepath = Path::new(&config.stats_dir).join(filename);
+    debug!("Saving search results to: {filepath:?}");
+
+    let handle = File::create(&filepath)?;
+    let mut writer = BufWriter::new(handle);
+    writeln!(writer, "cap_from,cap_to,capping_order,capping_operation,dimension,outcome,lo,hi,lo_failures,hi_failures,boundary_repeats,confirmed,trials")?;
+    for result in results {
+        writeln!(writer, "{result}")?;
+    }
+    Ok(filepath)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bisect_finds_boundary() {
+        // capping fails below 87%
+        let mut probes = Vec::new();
+        let bisection = bisect(50, 100, 1, |load_pct| {
+            probes.push(load_pct);
+            Some(load_pct < 87)
+        });
+        assert_eq!(bisection, Bisection::Boundary { lo: 86, hi: 87, lo_fails: true });
+        // 2 end points + log2(50) bisection steps
+        assert!(probes.len() <= 2 + 6, "{probes:?}");
+
+        // coarser resolution, outcome inverted
+        let bisection = bisect(10_000, 1_000_000, 10_000, |period| Some(period >= 300_000));
+        match bisection {
+            Bisection::Boundary { lo, hi, lo_fails } => {
+                assert!(!lo_fails);
+                assert!(lo < 300_000 && hi >= 300_000 && hi - lo <= 10_000);
+            }
+            Bisection::NoBoundary { .. } | Bisection::Unresolved { .. } => panic!("boundary not found"),
+        }
+    }
+
+    #[test]
+    fn test_bisect_no_boundary() {
+        assert_eq!(bisect(1, 64, 1, |_| Some(false)), Bisection::NoBoundary { fails: false });
+        assert_eq!(bisect(1, 64, 1, |_| Some(true)), Bisection::NoBoundary { fails: true });
+    }
+
+    #[test]
+    fn test_bisect_unresolved() {
+        // nothing can be told below 60%, e.g. the load is too low for the cap to matter
+        let fails = |load_pct| (load_pct >= 60).then_some(load_pct < 87);
+        assert_eq!(bisect(50, 100, 1, fails), Bisection::Unresolved { at: 50 });
+        assert_eq!(bisect(60, 100, 1, fails), Bisection::Boundary { lo: 86, hi: 87, lo_fails: true });
+        // stopped part way, rather than taking the inconclusive value as passing
+        let fails = |load_pct| (load_pct != 75).then_some(load_pct < 87);
+        assert_eq!(bisect(50, 100, 1, fails), Bisection::Unresolved { at: 75 });
+    }
+
+    #[test]
+    fn test_search_result_csv() {
+        let mut result = SearchResult {
+            cap_from: 580,
+            cap_to: 400,
+            capping_order: CappingOrder::LevelToLevel,
+            capping_operation: CappingOperation::Activate,
+            dimension: SearchDimension::LoadPct,
+            range: (50, 100),
+            bisection: Bisection::Boundary { lo: 86, hi: 87, lo_fails: true },
+            lo_failures: 3,
+            hi_failures: 1,
+            boundary_repeats: 3,
+            trials: 14,
+        };
+        assert_eq!(result.to_string(), "580,400,LevelToLevel,Activate,load_pct,boundary,86,87,3,1,3,true,14");
+
+        result.hi_failures = 2;
+        assert!(!result.is_confirmed());
+
+        result.bisection = Bisection::NoBoundary { fails: false };
+        assert_eq!(result.to_string(), "580,400,LevelToLevel,Activate,load_pct,all_pass,50,100,3,2,3,false,14");
+
+        result.bisection = Bisection::Unresolved { at: 75 };
+        assert_eq!(result.to_string(), "580,400,LevelToLevel,Activate,load_pct,unresolved,75,75,3,2,3,false,14");
+    }
+}
@@ -0,0 +1,169 @@
+//! Detects when the power drawn by the server has settled, so that each trial starts
+//! from the same state rather than after a fixed wait, and when the power under load
+//! has reached a plateau, so that the cap is requested as soon as the server is warm.
+
+use std::collections::VecDeque;
+
+/// The power has settled when the last `SETTLE_WINDOW` readings are within
+/// `SETTLE_TOLERANCE_PCT` of their mean...
+pub const SETTLE_WINDOW: usize = 5;
+pub const SETTLE_TOLERANCE_PCT: u64 = 2;
+/// ...or once this long has passed, whether settled or not
+pub const SETTLE_TIMEOUT_SECS: u64 = 60;
+
+/// The power under load has reached a plateau when the standard deviation of the
+/// last `PLATEAU_WINDOW` readings is within `PLATEAU_STDDEV_PCT` of their mean
+pub const PLATEAU_WINDOW: usize = 5;
+pub const PLATEAU_STDDEV_PCT: f64 = 1.0;
+/// ...and their mean is this much above the settled power before the load started, so
+/// that the steady power at idle, before the load generator ramps up, isn't taken for it
+pub const PLATEAU_RISE_PCT: u64 = 5;
+
+#[derive(Debug)]
+pub struct SettleDetector {
+    window: VecDeque<u64>,
+    window_len: usize,
+    tolerance_pct: u64,
+}
+
+impl SettleDetector {
+    #[must_use]
+    pub fn new(window_len: usize, tolerance_pct: u64) -> Self {
+        assert!(window_len > 0);
+        Self { window: VecDeque::with_capacity(window_len), window_len, tolerance_pct }
+    }
+
+    /// Adds a power reading, returning true if the power has now settled
+    pub fn add(&mut self, power: u64) -> bool {
+        if self.window.len() == self.window_len {
+            self.window.pop_front();
+        }
+        self.window.push_back(power);
+        self.is_settled()
+    }
+
+    /// True once the window is full and its spread is within the tolerance of its mean
+    #[must_use]
+    pub fn is_settled(&self) -> bool {
+        let (Some(min), Some(max), Some(mean)) = (self.window.iter().min(), self.window.iter().max(), self.mean()) else {
+            return false;
+        };
+        self.window.len() == self.window_len && (max - min) * 100 <= self.tolerance_pct * mean
+    }
+
+    /// Mean power over the window, if any readings have been added
+    #[must_use]
+    pub fn mean(&self) -> Option<u64> {
+        if self.window.is_empty() {
+            None
+        } else {
+            Some(self.window.iter().sum::<u64>() / self.window.len() as u64)
+        }
+    }
+}
+
+/// Rolling variance of the power readings, compared against a threshold relative to
+/// their mean, which has to exceed a floor
+#[derive(Debug)]
+pub struct PlateauDetector {
+    window: VecDeque<u64>,
+    window_len: usize,
+    stddev_pct: f64,
+    floor: u64,
+}
+
+impl PlateauDetector {
+    #[must_use]
+    pub fn new(window_len: usize, stddev_pct: f64) -> Self {
+        assert!(window_len > 1);
+        Self { window: VecDeque::with_capacity(window_len), window_len, stddev_pct, floor: 0 }
+    }
+
+    /// Only counts a plateau whose mean power is above `floor`
+    #[must_use]
+    pub fn with_floor(self, floor: u64) -> Self {
+        Self { floor, ..self }
+    }
+
+    /// Adds a power reading, returning true if the power has now reached a plateau
+    pub fn add(&mut self, power: u64) -> bool {
+        if self.window.len() == self.window_len {
+            self.window.pop_front();
+        }
+        self.window.push_back(power);
+        self.is_steady()
+    }
+
+    /// True once the window is full, the variance of its readings is below the
+    /// threshold and their mean is above the floor
+    #[must_use]
+    pub fn is_steady(&self) -> bool {
+        if self.window.len() < self.window_len {
+            return false;
+        }
+        let n = self.window.len() as f64;
+        let mean = self.window.iter().map(|p| *p as f64).sum::<f64>() / n;
+        let variance = self.window.iter().map(|p| (*p as f64 - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        let threshold = self.stddev_pct / 100.0 * mean;
+        variance <= threshold * threshold && mean > self.floor as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settles_once_window_is_stable() {
+        let mut detector = SettleDetector::new(3, 2);
+        assert_eq!(detector.mean(), None);
+        assert!(!detector.is_settled());
+
+        // cooling down from the previous trial
+        for power in [600, 520, 450, 400] {
+            assert!(!detector.add(power));
+        }
+        assert!(!detector.add(398));
+        assert!(detector.add(401));
+        assert_eq!(detector.mean(), Some(399));
+
+        // a spike unsettles it again
+        assert!(!detector.add(420));
+    }
+
+    #[test]
+    fn test_needs_a_full_window() {
+        let mut detector = SettleDetector::new(3, 2);
+        assert!(!detector.add(400));
+        assert!(!detector.add(400));
+        assert!(detector.add(400));
+    }
+
+    #[test]
+    fn test_plateau() {
+        let mut detector = PlateauDetector::new(4, 1.0);
+        // firestarter ramping up
+        for power in [300, 420, 510, 545, 552] {
+            assert!(!detector.add(power));
+        }
+        assert!(!detector.add(556));
+        assert!(detector.add(554));
+        assert!(detector.is_steady());
+        // noisy, but within 1% of the mean
+        assert!(detector.add(560));
+        assert!(!detector.add(600));
+    }
+
+    #[test]
+    fn test_plateau_above_floor() {
+        let mut detector = PlateauDetector::new(4, 1.0).with_floor(315);
+        // steady at idle, before firestarter has started loading the cores
+        for power in [300, 301, 300, 299, 300] {
+            assert!(!detector.add(power));
+        }
+        for power in [420, 510, 552, 556, 554, 555] {
+            detector.add(power);
+        }
+        assert!(detector.is_steady());
+    }
+}
@@ -0,0 +1,75 @@
+use crate::driver::load_generator::{LoadCommand, LoadSpec};
+use crate::topology::format_cpu_list;
+use std::cmp::max;
+
+#[derive(Debug)]
+/// Builds the stress-ng command line for a trial's load (see `load_generator.rs`)
+pub struct StressNg {
+    path: String,
+}
+
+impl StressNg {
+    #[must_use]
+    pub fn new(path: &str) -> Self {
+        Self { path: String::from(path) }
+    }
+}
+
+impl LoadCommand for StressNg {
+    /// stress-ng's CPU stressors, where `--cpu 0` means one per online CPU as for
+    /// firestarter. stress-ng has no load period as such: below 100% load, it's
+    /// approximated by the busy time slice, the loaded part of the period, which
+    /// stress-ng takes in whole milliseconds. Bound threads are given as a `--taskset`
+    /// CPU list, with one stressor per CPU.
+    fn command_line(&self, load: &LoadSpec) -> Vec<String> {
+        let mut command_line = vec![
+            self.path.clone(),
+            String::from("--timeout"),
+            load.duration_secs.to_string(),
+            String::from("--cpu"),
+            load.effective_threads().to_string(),
+            String::from("--cpu-load"),
+            load.load_pct.to_string(),
+        ];
+        if let Some(cpus) = &load.cpus {
+            command_line.push(String::from("--taskset"));
+            command_line.push(format_cpu_list(cpus, ","));
+        }
+        if load.load_period_us != 0 && load.load_pct < 100 {
+            let busy_slice_millis = max(1, load.load_period_us * load.load_pct / 100 / 1000);
+            command_line.push(String::from("--cpu-load-slice"));
+            command_line.push(busy_slice_millis.to_string());
+        }
+        command_line
+    }
+
+    fn program(&self) -> &str {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line() {
+        let stress_ng = StressNg::new("stress-ng");
+        assert_eq!(
+            stress_ng.command_line(&LoadSpec::new(90, 100_000, 8, 25).unwrap()).join(" "),
+            "stress-ng --timeout 25 --cpu 8 --cpu-load 90 --cpu-load-slice 90"
+        );
+        assert_eq!(
+            stress_ng.command_line(&LoadSpec::new(99, 1000, 0, 25).unwrap()).join(" "),
+            "stress-ng --timeout 25 --cpu 0 --cpu-load 99 --cpu-load-slice 1"
+        );
+        assert_eq!(
+            stress_ng.command_line(&LoadSpec::new(100, 10_000, 0, 25).unwrap()).join(" "),
+            "stress-ng --timeout 25 --cpu 0 --cpu-load 100"
+        );
+        assert_eq!(
+            stress_ng.command_line(&LoadSpec::new(100, 0, 0, 25).unwrap().with_cpus(Some(vec![4, 5]))).join(" "),
+            "stress-ng --timeout 25 --cpu 2 --cpu-load 100 --taskset 4-5"
+        );
+    }
+}
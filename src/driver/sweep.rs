@@ -0,0 +1,311 @@
+//! Cap sweeps: instead of the campaign's cap pairs, cap from one level to each of a
+//! list or range of levels, with every capping order, to draw the compliance curve of
+//! the power achieved against the limit requested, for each load. Set by `--sweep` or
+//! in a campaign file:
+//!
+//! ```toml
+//! [sweep]
+//! range = [300, 600]                      # 300, 320, ... 600 W
+//! step_watts = 20
+//! # levels = [300, 420, 550]              # or an explicit list
+//! from_watts = 650                        # level capped from, by default the highest
+//! ```
+//!
+//! The sweep caps to each level but the one capped from, so only the `Activate`
+//! operation is run. Once the trials have run, the curve is drawn from the campaign
+//! journal (see `journal.rs`), so that a resumed sweep includes the trials of the runs
+//! before it, whether or not `--sweep` is given again.
+
+use crate::campaign::TrialSpec;
+use crate::cli::Configuration;
+use crate::driver::compliance::{Compliance, Verdict};
+use crate::ResultType;
+use log::debug;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "SweepFile")]
+pub struct CapSweep {
+    /// The levels capped to, in W
+    pub levels: Vec<u64>,
+    /// The level capped from, in W, if not the highest of `levels`
+    pub from_watts: Option<u64>,
+}
+
+/// The `[sweep]` table of a campaign file, as read
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SweepFile {
+    levels: Option<Vec<u64>>,
+    range: Option<(u64, u64)>,
+    step_watts: Option<u64>,
+    from_watts: Option<u64>,
+}
+
+impl TryFrom<SweepFile> for CapSweep {
+    type Error = String;
+
+    fn try_from(file: SweepFile) -> Result<Self, Self::Error> {
+        let sweep = match (file.levels, file.range, file.step_watts) {
+            (Some(levels), None, None) => CapSweep { levels, from_watts: file.from_watts },
+            (None, Some((lo, hi)), Some(step_watts)) => CapSweep::range(lo, hi, step_watts)?.with_from_watts(file.from_watts),
+            _ => return Err(String::from("sweep: give either levels, or range and step_watts")),
+        };
+        sweep.validate()?;
+        Ok(sweep)
+    }
+}
+
+impl CapSweep {
+    /// From `lo` to `hi` W, inclusive, in steps of `step_watts`. `hi` is included
+    /// even if it's not a whole number of steps from `lo`.
+    ///
+    /// # Errors
+    /// If the range isn't increasing or the step is 0
+    pub fn range(lo: u64, hi: u64, step_watts: u64) -> Result<Self, String> {
+        if lo >= hi || step_watts == 0 {
+            return Err(format!("sweep: {lo}-{hi} W in steps of {step_watts} W is not an increasing range"));
+        }
+        let mut levels: Vec<u64> = (lo..=hi).step_by(usize::try_from(step_watts).unwrap_or(usize::MAX)).collect();
+        if levels.last() != Some(&hi) {
+            levels.push(hi);
+        }
+        Ok(CapSweep { levels, from_watts: None })
+    }
+
+    #[must_use]
+    pub fn with_from_watts(self, from_watts: Option<u64>) -> Self {
+        Self { from_watts, ..self }
+    }
+
+    /// The level capped from
+    #[must_use]
+    pub fn from_watts(&self) -> u64 {
+        self.from_watts.unwrap_or_else(|| self.levels.iter().copied().max().unwrap_or(0))
+    }
+
+    /// (`cap_from`, `cap_to`) for each level but the one capped from
+    #[must_use]
+    pub fn cap_pairs(&self) -> Vec<(u64, u64)> {
+        let from_watts = self.from_watts();
+        self.levels.iter().filter(|level| **level != from_watts).map(|level| (from_watts, *level)).collect()
+    }
+
+    /// # Errors
+    /// If there are no levels, a level is 0, or there's no level but the one capped from
+    pub fn validate(&self) -> Result<(), String> {
+        if self.levels.is_empty() {
+            return Err(String::from("sweep: levels must not be empty"));
+        }
+        if self.levels.contains(&0) || self.from_watts == Some(0) {
+            return Err(String::from("sweep: cap levels must be greater than 0"));
+        }
+        if self.cap_pairs().is_empty() {
+            return Err(format!("sweep: no level to cap to from {} W", self.from_watts()));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CapSweep {
+    type Err = String;
+
+    /// "LO-HI:STEP", e.g. "300-600:20", or a list of levels, e.g. "300,420,550"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |watts: &str| watts.trim().parse::<u64>().map_err(|e| format!("invalid sweep level {watts:?}: {e}"));
+        let sweep = match s.split_once(':') {
+            Some((range, step)) => {
+                let (lo, hi) = range.split_once('-').ok_or_else(|| format!("invalid sweep range {range:?}, expected LO-HI"))?;
+                CapSweep::range(parse(lo)?, parse(hi)?, parse(step)?)?
+            }
+            None => CapSweep { levels: s.split(',').map(parse).collect::<Result<_, _>>()?, from_watts: None },
+        };
+        sweep.validate()?;
+        Ok(sweep)
+    }
+}
+
+/// A point of the compliance curve: the trials of one load and capping order capped
+/// to one level
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurvePoint {
+    pub spec: TrialSpec,
+    pub trials: u64,
+    pub passed: u64,
+    pub failed: u64,
+    pub inconclusive: u64,
+    /// The power achieved by each trial that had readings
+    pub achieved_power: Vec<u64>,
+    /// The time to compliance of each trial that passed
+    pub times_to_compliance_millis: Vec<u64>,
+}
+
+impl CurvePoint {
+    fn new(spec: &TrialSpec) -> Self {
+        Self {
+            spec: spec.clone(),
+            trials: 0,
+            passed: 0,
+            failed: 0,
+            inconclusive: 0,
+            achieved_power: Vec::new(),
+            times_to_compliance_millis: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, compliance: &Compliance) {
+        self.trials += 1;
+        match compliance.verdict {
+            Verdict::Pass => self.passed += 1,
+            Verdict::Fail => self.failed += 1,
+            Verdict::Inconclusive => self.inconclusive += 1,
+        }
+        self.achieved_power.extend(compliance.achieved_power);
+        self.times_to_compliance_millis
+            .extend(compliance.time_to_compliance.map(|t| u64::try_from(t.as_millis()).unwrap_or(u64::MAX)));
+    }
+}
+
+fn mean(values: &[u64]) -> String {
+    if values.is_empty() {
+        String::new()
+    } else {
+        (values.iter().sum::<u64>() / values.len() as u64).to_string()
+    }
+}
+
+impl Display for CurvePoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.spec.load_pct,
+            self.spec.load_period_us,
+            self.spec.n_threads,
+            self.spec.profile,
+            self.spec.capping_order,
+            self.spec.cap_from,
+            self.spec.cap_to,
+            self.trials,
+            self.passed,
+            self.failed,
+            self.inconclusive,
+            mean(&self.achieved_power),
+            self.achieved_power.iter().min().map_or(String::new(), u64::to_string),
+            self.achieved_power.iter().max().map_or(String::new(), u64::to_string),
+            mean(&self.times_to_compliance_millis),
+        )
+    }
+}
+
+/// What identifies a point of the curve: load percentage, period, threads, profile,
+/// capping order, cap from and cap to
+type CurveKey = (u64, u64, u64, String, String, u64, u64);
+
+/// The compliance curve of the completed trials: one point per load, capping order
+/// and level, ordered by load, then capping order, then level, so that each curve's
+/// points are consecutive
+#[must_use]
+pub fn compliance_curve(trials: &[TrialSpec], completed: &HashMap<u64, Compliance>) -> Vec<CurvePoint> {
+    let mut points: BTreeMap<CurveKey, CurvePoint> = BTreeMap::new();
+    for spec in trials {
+        if let Some(compliance) = completed.get(&spec.trial_id) {
+            let key = (
+                spec.load_pct,
+                spec.load_period_us,
+                spec.n_threads,
+                spec.profile.to_string(),
+                spec.capping_order.to_string(),
+                spec.cap_from,
+                spec.cap_to,
+            );
+            points.entry(key).or_insert_with(|| CurvePoint::new(spec)).add(compliance);
+        }
+    }
+    points.into_values().collect()
+}
+
+/// Writes the compliance curve to CSV file, replacing any earlier one of the campaign
+pub(crate) fn save_compliance_curve(config: &Configuration, points: &[CurvePoint]) -> ResultType<PathBuf> {
+    let filename = format!("{}_{}.csv",
+        config.compliance_curve_filename_prefix,
+        config.test_timestamp
+    );
+    let filepath = Path::new(&config.stats_dir).join(filename);
+    debug!("Saving compliance curve to: {filepath:?}");
+
+    let handle = File::create(&filepath)?;
+    let mut writer = BufWriter::new(handle);
+    writeln!(writer, "load_pct,load_period_us,n_threads,load_profile,capping_order,cap_from,requested_limit,trials,passed,failed,inconclusive,mean_achieved_power,min_achieved_power,max_achieved_power,mean_time_to_compliance_millis")?;
+    for point in points {
+        writeln!(writer, "{point}")?;
+    }
+    Ok(filepath)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::campaign::Campaign;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_sweep() {
+        assert_eq!("300-400:50".parse::<CapSweep>().unwrap().levels, [300, 350, 400]);
+        assert_eq!("300-390:40".parse::<CapSweep>().unwrap().levels, [300, 340, 380, 390]);
+        let sweep = "420,300,550".parse::<CapSweep>().unwrap();
+        // the highest level is capped from, not to
+        assert_eq!(sweep.cap_pairs(), [(550, 420), (550, 300)]);
+        assert_eq!(sweep.with_from_watts(Some(600)).cap_pairs(), [(600, 420), (600, 300), (600, 550)]);
+
+        assert!("400-300:20".parse::<CapSweep>().is_err());
+        assert!("300-400:0".parse::<CapSweep>().is_err());
+        assert!("300,0".parse::<CapSweep>().is_err());
+        assert!("300".parse::<CapSweep>().is_err());
+        assert!("300-400".parse::<CapSweep>().is_err());
+    }
+
+    #[test]
+    fn test_compliance_curve() {
+        let campaign = Campaign::from_toml(r#"
+            repetitions = 2
+            capping_orders = ["LevelToLevel"]
+
+            [[loads]]
+            load_pct = [100, 90]
+            load_period_us = [0]
+
+            [sweep]
+            range = [300, 400]
+            step_watts = 100
+            from_watts = 500
+            "#,
+            Campaign::builtin(400, 580, 10, 15),
+        ).unwrap();
+        let trials = campaign.trials(8);
+        // 2 levels * 2 loads * 2 repetitions, Activate only
+        assert_eq!(trials.len(), 8);
+
+        let compliance = |verdict, achieved_power| Compliance {
+            verdict,
+            time_to_compliance: (verdict == Verdict::Pass).then(|| Duration::from_millis(3000)),
+            achieved_power: Some(achieved_power),
+        };
+        // every trial but the last completed
+        let completed: HashMap<u64, Compliance> = trials[..7]
+            .iter()
+            .map(|t| (t.trial_id, if t.cap_to == 300 { compliance(Verdict::Fail, 310 + t.repetition * 10) } else { compliance(Verdict::Pass, 396) }))
+            .collect();
+        let rows: Vec<String> = compliance_curve(&trials, &completed).iter().map(ToString::to_string).collect();
+        assert_eq!(rows, [
+            "90,0,0,constant,LevelToLevel,500,300,2,0,2,0,315,310,320,",
+            "90,0,0,constant,LevelToLevel,500,400,1,1,0,0,396,396,396,3000",
+            "100,0,0,constant,LevelToLevel,500,300,2,0,2,0,315,310,320,",
+            "100,0,0,constant,LevelToLevel,500,400,2,2,0,0,396,396,396,3000",
+        ]);
+    }
+}
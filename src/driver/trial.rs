@@ -1,230 +1,446 @@
-use crate::bmc::BMC;
-use crate::cli::CONFIGURATION;
+use crate::bmc::{BMC, BMC_Action};
+use crate::campaign::TrialSpec;
+use crate::cli::Configuration;
+use crate::clock;
 use crate::core_count;
-use crate::driver::firestarter::Firestarter;
+use crate::driver::compliance::{Compliance, ComplianceDetector, Expectation, Verdict};
+use crate::driver::load_generator::{self, LoadGenerator, LoadSpec, LoadStatus};
+use crate::driver::load_profile::LoadProfile;
+use crate::driver::steady_state::{
+    PlateauDetector, SettleDetector, PLATEAU_RISE_PCT, PLATEAU_STDDEV_PCT, PLATEAU_WINDOW, SETTLE_TIMEOUT_SECS, SETTLE_TOLERANCE_PCT,
+    SETTLE_WINDOW,
+};
 use crate::driver::{CappingOperation, CappingOrder};
+use crate::metrics::{TrialMetrics, METRICS};
+use crate::monitor::{MonitorMessage, TrialEvent, TrialEventKind};
+use crate::proc_stat::{LoadSummary, ProcStat};
+use crate::shutdown;
+use crate::topology::format_cpu_list;
+use crate::ResultType;
 use chrono::{self, DateTime, Local, SecondsFormat};
-use log::{trace, info};
-use std::cmp::max;
-use std::fs::OpenOptions;
+use log::{error, trace, info, warn};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-
-const INTER_TRIAL_WAIT_SECS: u64 = 3; // pause between each trial - cool-down.
-const SETUP_PAUSE_MILLIS: u64 = 500;    // Pause between issuing bmc commands in set_initial_conditions()
-
-/// The test driver - for any given test configuration from `../driver.rs` launch a
-/// campaign of tests with diminishing load. In parallel the bmc and rapl monitors
-/// log the energy/power behaviour of the system under test. For each test configuration
-/// three separate mechanisms of load reduction are used:
-/// * Increase the period for which the load is averaged. For example for a load running
-///   at 99%, calculate 99% over 100µs (a 1µs idle time per 100µs), 1000µs (10ms idle period
-///   every millisecond), and 10,000µs (100µs idle period every 10ms).
-/// * Decrease the average load: 99%, 98%, 97%...
-/// * Run at 100% load over a diminishing number of threads (starting with all available).
+use std::time::{Duration, Instant};
+
+// Time allowed for each BMC command leading up to the cap request, on top of the
+// sequence's intervals, when working out how long the load must run for. ipmitool
+// commands typically take well under this, but the load must not end before the test
+// time does.
+const BMC_COMMAND_ALLOWANCE_SECS: u64 = 2;
+
+/// A single trial of the campaign (see `campaign.rs`): set up the capping conditions,
+/// start the load generator (see `load_generator.rs`) with the trial's load, apply the capping operation on the BMC once
+/// warmed up and check whether it took effect. In parallel the bmc and rapl monitors
+/// log the energy/power behaviour of the system under test.
 pub struct Trial {
+    config: Arc<Configuration>,
     bmc: BMC,
+    load_generator: Box<dyn LoadGenerator>,
+    /// Id of the current test scenario, unique for the campaign
+    trial_id: u64,
+    /// Index of this run of the trial's configuration
+    repetition: u64,
+    /// Seed the campaign's trials were shuffled with, if they were
+    seed: Option<u64>,
+    /// Sending end of the monitor channel, for signalling trial events
+    events: Sender<MonitorMessage>,
     cap_from: u64,
     cap_to: u64,
     capping_order: CappingOrder,
     capping_operation: CappingOperation,
+    /// Duration of the load: the warmup, any BMC command sequence leading up to the
+    /// cap request and the test time
     total_runtime_secs: u64,
+    test_time_secs: u64,
+    /// Upper bound on the warmup, which ends as soon as the power reaches a plateau
     warmup_secs: u64,
+    /// Time from starting the load to the power reaching a plateau, or `warmup_secs`
+    /// if it didn't
+    warmup_time: Duration,
+    /// True if the power reached a plateau before `warmup_secs`
+    warmup_steady: bool,
     load_pct: u64,
     load_period_us: u64,
     n_threads: u64,
+    /// How the load changes after the cap request
+    profile: LoadProfile,
+    /// The CPUs the load generator's threads are bound to by the placement (see
+    /// `topology.rs`), if they are
+    bound_cpus: Option<Vec<usize>>,
     start_time: DateTime<Local>,
     end_time: DateTime<Local>,
     cap_request_time: DateTime<Local>,
+    /// Monotonic timestamp (see `clock.rs`) of the cap request, in µs
+    cap_request_monotonic_us: u64,
+    /// True if the power settled as expected after the capping operation
     capping_thread_did_complete: bool,
-    time_to_cap: chrono::Duration,
+    /// Time taken for the BMC to acknowledge the capping command, measured
+    /// on the monotonic clock. None if the trial never got as far as the cap request
+    time_to_cap: Option<Duration>,
+    /// False if the BMC rejected the cap request, as it should a `BelowMinimum` level
+    cap_accepted: bool,
+    /// Whether, and how quickly, the power settled as expected after the cap request
+    compliance: Compliance,
+    /// Mean BMC power just before the cap request
+    baseline_power: Option<u64>,
+    achieved_load: LoadSummary,
+    /// Time waited for the power to settle before starting the load
+    settle_time: Duration,
+    /// False if the power hadn't settled by `SETTLE_TIMEOUT_SECS`
+    settled: bool,
+    /// Mean BMC power once settled (or on timing out), before starting the load
+    starting_power: Option<u64>,
+    /// How the load generator's run went
+    load_status: LoadStatus,
+    /// True if the trial was cut short by a shutdown request
+    interrupted: bool,
 }
 
 impl Trial {
-    pub fn new(
-        cap_from: u64,
-        cap_to: u64,
-        capping_order: CappingOrder,
-        capping_operation: CappingOperation,
-    ) -> Self {
+    pub fn new(spec: TrialSpec, config: Arc<Configuration>, events: Sender<MonitorMessage>) -> Self {
         Self {
-            cap_from,
-            cap_to,
-            capping_order,
-            capping_operation,
-            bmc: BMC::new(
-                &CONFIGURATION.bmc_hostname,
-                &CONFIGURATION.bmc_username,
-                &CONFIGURATION.bmc_password,
-            ),
-            total_runtime_secs: CONFIGURATION.test_time_secs + CONFIGURATION.warmup_secs,
-            warmup_secs: CONFIGURATION.warmup_secs,
-            load_pct: 0,
-            load_period_us: 0,
-            n_threads: 0,
+            trial_id: spec.trial_id,
+            repetition: spec.repetition,
+            seed: spec.seed,
+            events,
+            cap_from: spec.cap_from,
+            cap_to: spec.cap_to,
+            capping_order: spec.capping_order,
+            capping_operation: spec.capping_operation,
+            bmc: BMC::from_config(&config),
+            load_generator: load_generator::from_config(&config).expect("Failed to set up the load generator"),
+            total_runtime_secs: load_duration_secs(&spec),
+            test_time_secs: spec.test_time_secs,
+            warmup_secs: spec.warmup_secs,
+            warmup_time: Duration::ZERO,
+            warmup_steady: false,
+            load_pct: spec.load_pct,
+            load_period_us: spec.load_period_us,
+            n_threads: spec.n_threads,
+            profile: spec.profile,
+            bound_cpus: config.placement.place(spec.n_threads).expect("Failed to place the load generator's threads"),
             start_time: DateTime::from(DateTime::<Local>::MIN_UTC),
             end_time: DateTime::from(DateTime::<Local>::MIN_UTC),
             cap_request_time: DateTime::from(DateTime::<Local>::MIN_UTC),
+            cap_request_monotonic_us: 0,
             capping_thread_did_complete: false,
-            time_to_cap: chrono::Duration::max_value(),
+            time_to_cap: None,
+            cap_accepted: false,
+            compliance: Compliance { verdict: Verdict::Inconclusive, time_to_compliance: None, achieved_power: None },
+            baseline_power: None,
+            achieved_load: LoadSummary::default(),
+            settle_time: Duration::ZERO,
+            settled: false,
+            starting_power: None,
+            load_status: LoadStatus::Ok,
+            interrupted: false,
+            config,
         }
     }
 
+    /// Sets up the capping conditions, waits for the power to settle, so that every
+    /// trial starts from the same state, and runs the test scenario
     pub fn run(&mut self) {
-        self.run_decreasing_load();
-        self.run_decreasing_threads();
+        self.set_initial_conditions();
+        self.wait_for_power_to_settle();
+        if shutdown::requested() {
+            self.interrupted = true;
+            warn!("Trial {} interrupted before starting, no results recorded", self.trial_id);
+            return;
+        }
+        self.run_test_scenario();
     }
 
-    /// Setup the target system's capping configuration, ready for testing
-    /// # Arguments
-    /// * - sleep_secs: give some time for the capping conditions to be applied
-    ///     prior to launching the test
-    // There is an assumption here that the server is under low load and these
-    // prepatory operations will succeed. Should be checked?
+    /// True if the trial was cut short by a shutdown request (see `shutdown.rs`), in
+    /// which case it has no results
+    #[must_use]
+    pub fn was_interrupted(&self) -> bool {
+        self.interrupted
+    }
 
-    fn set_initial_conditions(&self) {
-        match self.capping_order {
-            CappingOrder::LevelBeforeActivate => {
-                // Set the level to the "cap_to" value, and the
-                // capping activation to the opposite of the test
-                //
-                // Reapeat the commands as they don't always seem to be taken into account
-
-                self.bmc.set_cap_power_level(self.cap_to);
-                thread::sleep(Duration::from_secs(SETUP_PAUSE_MILLIS));
-
-                match self.capping_operation {
-                    CappingOperation::Activate => self.bmc.deactivate_power_cap(),
-                    CappingOperation::Deactivate => self.bmc.activate_power_cap(),
-                };
-            }
-            CappingOrder::LevelAfterActivate => {
-                // set the capping level to the "cap_from" value
-                // and the capping activation to the value for the test
-                self.bmc.set_cap_power_level(self.cap_from);
-                thread::sleep(Duration::from_secs(SETUP_PAUSE_MILLIS));
-
-                match self.capping_operation {
-                    CappingOperation::Activate => self.bmc.activate_power_cap(),
-                    CappingOperation::Deactivate => self.bmc.deactivate_power_cap(),
-                }
-            }
-            CappingOrder::LevelToLevel => {
-                // set cap level and activate capping
-                self.bmc.set_cap_power_level(self.cap_from);
-                thread::sleep(Duration::from_secs(SETUP_PAUSE_MILLIS));
-                self.bmc.activate_power_cap();
-            }
-        };
-        thread::sleep(Duration::from_secs(SETUP_PAUSE_MILLIS));
+    /// Whether, and how quickly, the power settled as expected once the trial has run
+    #[must_use]
+    pub fn compliance(&self) -> Compliance {
+        self.compliance
     }
 
-    /// Combines two of the diminishing load techniques: decrease average load and
-    /// increase the time period over which the average is calculated (indirectly increasing
-    /// the wall-clock idle period).
-    fn run_decreasing_load(&mut self) {
-        trace!("Running decreasing load");
-
-        // because Rust doesn't have decreasing ranges, have to jump through hoops...
-        let n_threads = 0; // firestarter will use all available threads
-        for idle_pct in 0..=15 {
-            let load_pct = 100 - idle_pct;
-            for load_period_us in [10_000, 100_000, 1_000_000] {
-                self.set_initial_conditions();
-                self.run_test_scenario(load_pct, load_period_us, n_threads);
-                thread::sleep(Duration::from_secs(INTER_TRIAL_WAIT_SECS));
-            }
+    /// Stamps a trial event into the monitor streams. The monitor is not essential
+    /// to the running of the trial, so failure to send is only logged.
+    fn signal(&self, kind: TrialEventKind, timestamp: DateTime<Local>, monotonic_us: u64) {
+        let event = TrialEvent::new(self.trial_id, kind, timestamp, monotonic_us);
+        if let Err(e) = self.events.send(MonitorMessage::Event(event)) {
+            warn!("Failed to signal trial event {kind} to monitor: {e}");
         }
     }
 
-    /// Decrease the number of active threads. Each active thread runs at full load.
-    fn run_decreasing_threads(&mut self) {
-        trace!("Running decreasing threads");
-
-        let load_pct = 100;
-        let load_period = 0;
-        let core_count = core_count();
-        assert!(core_count > 0);
-        // As rust _still_ doesn't have decreasing ranges, jump through more hoops
-        if core_count > 1 {
-            // TODO: Could probably have a better algorithm here - if you've got 128
-            //       cores, then maybe don't have to run this 14 times to get a result.
-            //       but then again, maybe you do.
-
-            let max_idle_threads = max(1, core_count / 10);
-            for idle_threads in 0..=max_idle_threads {
-                self.set_initial_conditions();
-                self.run_test_scenario(load_pct, load_period, core_count - idle_threads);
-                thread::sleep(Duration::from_secs(INTER_TRIAL_WAIT_SECS));
+    /// Setup the target system's capping configuration, ready for testing, pausing
+    /// between commands to give each time to be applied
+    // There is an assumption here that the server is under low load and these
+    // prepatory operations will succeed. Should be checked?
+    fn set_initial_conditions(&self) {
+        for (i, action) in setup_actions(self.capping_order, self.capping_operation, self.cap_from, self.cap_to)
+            .into_iter()
+            .enumerate()
+        {
+            if i > 0 {
+                thread::sleep(Duration::from_millis(self.config.setup_pause_millis));
             }
-        } else {
-            info!("Can't run decreasing cores with only one core");
+            self.bmc.apply(action);
+        }
+    }
+
+    /// Reads the BMC power until it has settled (see `steady_state.rs`), or for at most
+    /// `SETTLE_TIMEOUT_SECS`, recording how long that took and the power reached
+    fn wait_for_power_to_settle(&mut self) {
+        let start = Instant::now();
+        let mut detector = SettleDetector::new(SETTLE_WINDOW, SETTLE_TOLERANCE_PCT);
+        self.settled = self.watch_power_until(start + Duration::from_secs(SETTLE_TIMEOUT_SECS), |power| detector.add(power));
+        self.settle_time = start.elapsed();
+        self.starting_power = detector.mean();
+        if self.settled {
+            info!(
+                "Power settled in {} ms at {} W",
+                self.settle_time.as_millis(),
+                self.starting_power.map_or(String::from("-"), |p| p.to_string()),
+            );
+        } else if !shutdown::requested() {
+            warn!(
+                "Power didn't settle within {SETTLE_TIMEOUT_SECS} s, starting at {} W",
+                self.starting_power.map_or(String::from("-"), |p| p.to_string()),
+            );
         }
     }
 
-    /// For each configuration of firestarter as established by `run_decreasing_load()`
-    /// and `run_decreasing_threads()` launch firestarter on its own thread, wait for
-    /// the warm uptime then apply the capping action on the BMC. Check to see if the capping action
-    /// completed inside the test time. For each test run, save results to log file.
-    fn run_test_scenario(&mut self, load_pct: u64, load_period_us: u64, n_threads: u64) {
-        self.load_pct = load_pct;
-        self.load_period_us = load_period_us;
-        self.n_threads = n_threads;
+    /// Start the load generator, wait for the power to reach a plateau (see
+    /// `steady_state.rs`), for at most the warmup time, then apply the capping action on
+    /// the BMC. The BMC power is read throughout the warmup, for a pre-cap baseline,
+    /// and throughout the test time to check whether the power settled as expected
+    /// after the capping action, while the load follows the trial's load profile (see
+    /// `load_profile.rs`). The CPU utilisation over the test time is
+    /// read from `/proc/stat` to record the load that was actually achieved.
+    /// Save the results to the driver log.
+    fn run_test_scenario(&mut self) {
+        let (load_pct, load_period_us, n_threads) = (self.load_pct, self.load_period_us, self.n_threads);
         info!("\
-            Test scenario: load: {load_pct}, \
+            Test scenario {}: load: {load_pct}, \
             load period µs: {load_period_us}, \
             n_threads: {n_threads}, \
+            profile: {}, \
             cap_from: {}, \
             cap_to: {}, \
             capping_order: {}, \
             capping_operation: {}",
+            self.trial_id,
+            self.profile,
             self.cap_from,
             self.cap_to,
             self.capping_order,
             self.capping_operation
         );
+        METRICS.update_trial(TrialMetrics {
+            trial_id: self.trial_id,
+            load_pct,
+            load_period_us,
+            n_threads,
+            cap_from: self.cap_from,
+            cap_to: self.cap_to,
+            capping_order: self.capping_order.to_string(),
+            capping_operation: self.capping_operation.to_string(),
+        });
         self.start_time = Local::now();
-        let firestarter =
-            Firestarter::new(self.total_runtime_secs, load_pct, load_period_us, n_threads);
-        let fire_starter_thread = thread::spawn(move || firestarter.run());
-        thread::sleep(Duration::from_secs(self.warmup_secs));
+        self.signal(TrialEventKind::WarmupStart, self.start_time, clock::monotonic_micros());
+        let output_path = self.make_load_output_path();
+        let started = LoadSpec::new(load_pct, load_period_us, n_threads, self.total_runtime_secs)
+            .and_then(|load| {
+                let load = load.with_cpus(self.bound_cpus.clone());
+                info!("Starting load: {}", self.load_generator.describe(&load));
+                self.load_generator.start(&load, Some(&output_path))
+            });
+        if let Err(e) = started {
+            // recorded as the trial's result, there being no load to cap
+            error!("Trial {} failed to start the load generator: {e}", self.trial_id);
+            self.load_status = LoadStatus::Failed(e.to_string());
+            self.end_time = Local::now();
+            self.signal(TrialEventKind::TrialEnd, self.end_time, clock::monotonic_micros());
+            self.log_results().expect("Failed to write driver log entry");
+            return;
+        }
+
+        let mut detector = ComplianceDetector::new(
+            Expectation::for_scenario(self.capping_order, self.capping_operation, self.cap_from, self.cap_to));
+        let warmup_start = Instant::now();
+        // not a plateau at idle, before the load has ramped up
+        let mut plateau = PlateauDetector::new(PLATEAU_WINDOW, PLATEAU_STDDEV_PCT)
+            .with_floor(self.starting_power.map_or(0, |power| power + power * PLATEAU_RISE_PCT / 100));
+        self.warmup_steady = self.watch_power_until(warmup_start + Duration::from_secs(self.warmup_secs), |power| {
+            detector.add_baseline(power);
+            plateau.add(power)
+        });
+        let warmup_end = Instant::now();
+        self.warmup_time = warmup_end - warmup_start;
+        info!(
+            "Warmup {} after {} ms",
+            if self.warmup_steady { "reached a plateau" } else { "ended without a plateau" },
+            self.warmup_time.as_millis(),
+        );
+        if shutdown::requested() {
+            self.abandon();
+            return;
+        }
+
+        // A sequence of BMC commands leads up to the last one, the cap request
+        let mut actions = cap_actions(self.capping_order, self.capping_operation, self.cap_from, self.cap_to);
+        let cap = actions.pop().expect("Capping order without a cap action");
+        for action in actions {
+            self.bmc.apply(action);
+            self.watch_power(Instant::now() + self.capping_order.interval(), |_| {});
+            if shutdown::requested() {
+                self.abandon();
+                return;
+            }
+        }
 
+        let test_start = Instant::now();
+        let load_at_cap_request = ProcStat::read();
         self.cap_request_time = Local::now();
-        self.do_cap_operation();
-        self.time_to_cap =  Local::now() - self.cap_request_time;
+        self.cap_request_monotonic_us = clock::monotonic_micros();
+        self.signal(TrialEventKind::CapRequest, self.cap_request_time, self.cap_request_monotonic_us);
+        self.do_cap_operation(cap);
+        detector.set_cap_accepted(self.cap_accepted);
+        let cap_acknowledged_monotonic_us = clock::monotonic_micros();
+        self.signal(TrialEventKind::CapAcknowledged, Local::now(), cap_acknowledged_monotonic_us);
+        self.time_to_cap = Some(Duration::from_micros(
+            cap_acknowledged_monotonic_us - self.cap_request_monotonic_us));
+
+        // The test time is counted from the cap request. The load generator's duration
+        // allows for the full warmup, so it's stopped at the end of the test time.
+        // The load profile's changes split the test time into segments.
+        let test_end = test_start + Duration::from_secs(self.test_time_secs);
+        let cap_request_monotonic_us = self.cap_request_monotonic_us;
+        let mut on_reading = |power| {
+            let since_request = clock::monotonic_micros().saturating_sub(cap_request_monotonic_us);
+            detector.add_sample(Duration::from_micros(since_request), power);
+        };
+        for (at, to_pct) in self.profile.schedule(load_pct, test_end - test_start) {
+            self.watch_power(test_start + at, &mut on_reading);
+            if shutdown::requested() {
+                break;
+            }
+            self.change_load(to_pct, test_end);
+        }
+        self.watch_power(test_end, &mut on_reading);
+        if shutdown::requested() {
+            self.abandon();
+            return;
+        }
+        self.load_generator.stop();
+        let status = self.load_generator.wait();
+        if self.load_status == LoadStatus::Ok {
+            self.load_status = status;
+        }
+
+        self.baseline_power = detector.baseline_power();
+        self.compliance = detector.compliance();
+        // Without the load the power says nothing about the cap: an idle server
+        // respects any limit
+        if self.load_status != LoadStatus::Ok && self.compliance.verdict != Verdict::Inconclusive {
+            warn!("Load generator {}, so the cap compliance is inconclusive", self.load_status);
+            self.compliance.verdict = Verdict::Inconclusive;
+            self.compliance.time_to_compliance = None;
+        }
+        self.capping_thread_did_complete = self.compliance.verdict == Verdict::Pass;
+        info!(
+            "Cap compliance: {} in {} ms (power before cap request: {} W)",
+            self.compliance.verdict,
+            self.compliance.time_to_compliance.map_or(String::from("-"), |t| t.as_millis().to_string()),
+            self.baseline_power.map_or(String::from("-"), |p| p.to_string()),
+        );
 
-        // wait for firestarter to exit
-        fire_starter_thread.join().unwrap();
         self.end_time = Local::now();
+        self.signal(TrialEventKind::TrialEnd, self.end_time, clock::monotonic_micros());
+        self.achieved_load = ProcStat::read().load_since(&load_at_cap_request);
+        info!(
+            "Achieved load: {:.1}% on {} active cpus (requested {load_pct}% on {} threads)",
+            self.achieved_load.mean_busy_pct,
+            self.achieved_load.active_cpus,
+            if n_threads == 0 { core_count() } else { n_threads },
+        );
 
         self.log_results().expect("Failed to write driver log entry");
     }
 
-    /// Perform the capping action
-    fn do_cap_operation(&self)  {
-        let capping_order = self.capping_order;
-        let capping_operation = self.capping_operation;
-
-        match capping_order {
-            CappingOrder::LevelBeforeActivate => {
-                // The capping level is set by set_initial_conditions
-                // just need to perform the operation
-                match capping_operation {
-                    CappingOperation::Activate => self.bmc.activate_power_cap(),
-                    CappingOperation::Deactivate => self.bmc.deactivate_power_cap(),
-                }
-            }
-            CappingOrder::LevelAfterActivate | CappingOrder::LevelToLevel => {
-                self.bmc.set_cap_power_level(self.cap_to);
-            }
+    /// Changes the load, for the load profile, keeping the first problem the load
+    /// generator had in the trial's `load_status`
+    fn change_load(&mut self, load_pct: u64, test_end: Instant) {
+        let remaining_secs = test_end.saturating_duration_since(Instant::now()).as_secs() + 1;
+        let output_path = self.make_load_output_path();
+        let status = LoadSpec::new(load_pct, self.load_period_us, self.n_threads, remaining_secs)
+            .and_then(|load| {
+                let load = load.with_cpus(self.bound_cpus.clone());
+                self.load_generator.set_load(&load, Some(&output_path))
+            })
+            .unwrap_or_else(|e| {
+                error!("Trial {} failed to change the load: {e}", self.trial_id);
+                LoadStatus::Failed(e.to_string())
+            });
+        if self.load_status == LoadStatus::Ok {
+            self.load_status = status;
         }
+        self.signal(TrialEventKind::LoadChange, Local::now(), clock::monotonic_micros());
+        info!("Load changed to {load_pct}%");
+    }
+
+    /// Gives up on the trial once a shutdown has been requested: stops the load and
+    /// doesn't log any results
+    fn abandon(&mut self) {
+        self.load_generator.stop();
+        self.load_status = self.load_generator.wait();
+        self.interrupted = true;
+        self.end_time = Local::now();
+        self.signal(TrialEventKind::TrialEnd, self.end_time, clock::monotonic_micros());
+        warn!("Trial {} interrupted, no results recorded", self.trial_id);
+    }
+
+    /// Reads the BMC power every BMC poll interval until `deadline`, or until a
+    /// shutdown is requested, handing each reading to `on_reading`
+    fn watch_power(&self, deadline: Instant, mut on_reading: impl FnMut(u64)) {
+        self.watch_power_until(deadline, |power| {
+            on_reading(power);
+            false
+        });
+    }
+
+    /// As `watch_power`, but stops early once `on_reading` returns true, in which case
+    /// returns true
+    fn watch_power_until(&self, deadline: Instant, on_reading: impl FnMut(u64) -> bool) -> bool {
+        watch_readings(
+            deadline,
+            Duration::from_millis(self.config.bmc_poll_interval_millis),
+            || self.bmc.try_current_power_reading().map(|reading| reading.instant),
+            on_reading,
+        )
+    }
+
+    /// Perform the capping action, recording whether the BMC accepted it
+    fn do_cap_operation(&mut self, action: BMC_Action) {
+        self.cap_accepted = match self.bmc.try_apply(action) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("BMC rejected the cap request: {e}");
+                false
+            }
+        };
     }
 
     fn log_results(&self) -> Result<(), std::io::Error>  {
         // Build log-file path, create the file and write a csv header
-        let log_file_path = Trial::make_csv_logfile_path();
+        let log_file_path = self.make_csv_logfile_path();
         trace!("Writing results to: {log_file_path:?}");
         if log_file_path.exists() {
             trace!("Log file exists...");
@@ -242,19 +458,38 @@ impl Trial {
             .expect("Failed to open driver log file");
         writeln!(
             log_file,
-            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.trial_id,
             self.start_time.to_rfc3339_opts(SecondsFormat::Secs, false),
             self.end_time.to_rfc3339_opts(SecondsFormat::Secs, false),
             self.cap_request_time.to_rfc3339_opts(SecondsFormat::Secs, false),
+            self.cap_request_monotonic_us,
             self.capping_thread_did_complete,
-            self.time_to_cap.num_milliseconds(),
+            self.time_to_cap.map_or(String::new(), |t| t.as_millis().to_string()),
             self.load_pct,
             self.load_period_us,
             self.n_threads,
             self.capping_order,
             self.capping_operation,
             self.cap_from,
-            self.cap_to
+            self.cap_to,
+            self.achieved_load,
+            self.compliance.verdict,
+            self.compliance.time_to_compliance.map_or(String::new(), |t| t.as_millis().to_string()),
+            self.baseline_power.map_or(String::new(), |p| p.to_string()),
+            self.repetition,
+            self.seed.map_or(String::new(), |seed| seed.to_string()),
+            self.settle_time.as_millis(),
+            self.settled,
+            self.starting_power.map_or(String::new(), |p| p.to_string()),
+            self.warmup_time.as_millis(),
+            self.warmup_steady,
+            self.config.placement.policy(),
+            self.bound_cpus.as_ref().map_or(String::new(), |cpus| format_cpu_list(cpus, ";")),
+            self.load_status,
+            self.profile,
+            self.compliance.achieved_power.map_or(String::new(), |p| p.to_string()),
+            self.cap_accepted,
         )?;
         Ok(())
     }
@@ -269,9 +504,11 @@ impl Trial {
         // write csv header
         writeln!(
             log_file,
-            "start_time,\
+            "trial_id,\
+            start_time,\
             end_time,\
             cap_request_time,\
+            cap_request_monotonic_us,\
             cap_did_complete,\
             cap_complete_time_millis,\
             load_pct,\
@@ -280,7 +517,25 @@ impl Trial {
             capping_order,\
             capping_operation,\
             cap_from,\
-            cap_to"
+            cap_to,\
+            achieved_load_pct,\
+            active_cpus,\
+            compliance_verdict,\
+            time_to_compliance_millis,\
+            baseline_power,\
+            repetition,\
+            seed,\
+            settle_millis,\
+            settled,\
+            starting_power,\
+            detected_warmup_millis,\
+            warmup_steady,\
+            placement,\
+            bound_cpus,\
+            load_status,\
+            load_profile,\
+            achieved_power,\
+            cap_accepted"
         )?;
 
         Ok(())
@@ -288,11 +543,338 @@ impl Trial {
     }
 
     /// Build the filename - append a timestamp and ".csv"
-    fn make_csv_logfile_path() -> PathBuf {
-        let save_filename = format!("{}_{}.csv",
-            CONFIGURATION.driver_log_filename_prefix,
-            CONFIGURATION.test_timestamp);
+    fn make_csv_logfile_path(&self) -> PathBuf {
+        driver_log_path(&self.config)
+    }
+
+    /// The load generator's output log for a trial, e.g. `load_output_240101_1200_trial_3.log`
+    fn make_load_output_path(&self) -> PathBuf {
+        let save_filename = format!("{}_{}_trial_{}.log",
+            self.config.load_output_filename_prefix,
+            self.config.test_timestamp,
+            self.trial_id);
+
+        Path::new(&self.config.stats_dir).join(save_filename)
+    }
+}
+
+/// Reads the power with `read` every `interval` until `deadline`, or until a shutdown
+/// is requested, handing each reading to `on_reading` until it returns true, in which
+/// case returns true. A failed read is logged and skipped: taken for 0 W, it would
+/// pass for a power under any cap, or for a settled one.
+fn watch_readings(
+    deadline: Instant,
+    interval: Duration,
+    mut read: impl FnMut() -> ResultType<u64>,
+    mut on_reading: impl FnMut(u64) -> bool,
+) -> bool {
+    while Instant::now() < deadline && !shutdown::requested() {
+        let read_start = Instant::now();
+        match read() {
+            Ok(power) => {
+                if on_reading(power) {
+                    return true;
+                }
+            }
+            Err(e) => warn!("Skipping failed BMC power reading: {e}"),
+        }
+        let next_read = (read_start + interval).min(deadline);
+        thread::sleep(next_read.saturating_duration_since(Instant::now()));
+    }
+    false
+}
 
-        Path::new(&CONFIGURATION.stats_dir).join(save_filename)
+/// The driver log of the run, e.g. `driver_log_240101_1200.csv`
+pub(crate) fn driver_log_path(config: &Configuration) -> PathBuf {
+    let save_filename = format!("{}_{}.csv",
+        config.driver_log_filename_prefix,
+        config.test_timestamp);
+
+    Path::new(&config.stats_dir).join(save_filename)
+}
+
+/// Removes the driver log rows of the trials the campaign journal doesn't record as
+/// completed: a trial killed after it was logged but before it was journaled is run
+/// again when resuming, and would otherwise be logged twice. Returns the number of
+/// rows removed.
+///
+/// # Errors
+/// If the driver log can't be read or rewritten
+pub(crate) fn drop_unjournaled_rows(path: &Path, completed: &HashMap<u64, Compliance>) -> ResultType<usize> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents.lines();
+    let mut kept: Vec<&str> = lines.next().into_iter().collect();
+    let mut dropped = 0;
+    for line in lines {
+        let trial_id = line.split(',').next().and_then(|id| id.parse::<u64>().ok());
+        if trial_id.is_some_and(|trial_id| completed.contains_key(&trial_id)) {
+            kept.push(line);
+        } else {
+            dropped += 1;
+        }
+    }
+    if dropped > 0 {
+        // replaced in one step, so that a kill while rewriting can't lose the log
+        let rewritten = path.with_extension("csv.tmp");
+        fs::write(&rewritten, kept.iter().map(|line| format!("{line}\n")).collect::<String>())?;
+        fs::rename(rewritten, path)?;
+    }
+    Ok(dropped)
+}
+
+/// The BMC commands that set up the capping conditions before the load is started
+fn setup_actions(
+    capping_order: CappingOrder,
+    capping_operation: CappingOperation,
+    cap_from: u64,
+    cap_to: u64,
+) -> Vec<BMC_Action> {
+    match capping_order {
+        // Set the level to the "cap_to" value, and the capping activation to the
+        // opposite of the test
+        CappingOrder::LevelBeforeActivate => vec![
+            BMC_Action::SetLevel(cap_to),
+            match capping_operation {
+                CappingOperation::Activate => BMC_Action::Deactivate,
+                CappingOperation::Deactivate => BMC_Action::Activate,
+            },
+        ],
+        // set the capping level to the "cap_from" value and the capping activation
+        // to the value for the test
+        CappingOrder::LevelAfterActivate => vec![
+            BMC_Action::SetLevel(cap_from),
+            match capping_operation {
+                CappingOperation::Activate => BMC_Action::Activate,
+                CappingOperation::Deactivate => BMC_Action::Deactivate,
+            },
+        ],
+        // set cap level and activate capping
+        CappingOrder::LevelToLevel | CappingOrder::MultiLevel { .. } | CappingOrder::BelowMinimum { .. } => {
+            vec![BMC_Action::SetLevel(cap_from), BMC_Action::Activate]
+        }
+        // as for LevelBeforeActivate
+        CappingOrder::RepeatedToggle { .. } => vec![BMC_Action::SetLevel(cap_to), toggle_action(capping_operation, true)],
+    }
+}
+
+/// The action that applies the capping operation, or if `opposite` undoes it
+fn toggle_action(capping_operation: CappingOperation, opposite: bool) -> BMC_Action {
+    match (capping_operation, opposite) {
+        (CappingOperation::Activate, false) | (CappingOperation::Deactivate, true) => BMC_Action::Activate,
+        (CappingOperation::Activate, true) | (CappingOperation::Deactivate, false) => BMC_Action::Deactivate,
+    }
+}
+
+/// The BMC commands applied once the load has warmed up, `CappingOrder::interval`
+/// apart. The last is the one under test.
+fn cap_actions(
+    capping_order: CappingOrder,
+    capping_operation: CappingOperation,
+    cap_from: u64,
+    cap_to: u64,
+) -> Vec<BMC_Action> {
+    match capping_order {
+        // The capping level is set by set_initial_conditions, just need to perform
+        // the operation
+        CappingOrder::LevelBeforeActivate => vec![toggle_action(capping_operation, false)],
+        CappingOrder::LevelAfterActivate | CappingOrder::LevelToLevel => vec![BMC_Action::SetLevel(cap_to)],
+        // set up in the opposite state: apply the operation and undo it, each cycle,
+        // then apply it for good
+        CappingOrder::RepeatedToggle { cycles, .. } => (0..cycles)
+            .flat_map(|_| [toggle_action(capping_operation, false), toggle_action(capping_operation, true)])
+            .chain([toggle_action(capping_operation, false)])
+            .collect(),
+        #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+        CappingOrder::MultiLevel { steps, .. } => (1..=steps)
+            .map(|step| {
+                let (from, to) = (cap_from as i64, cap_to as i64);
+                BMC_Action::SetLevel((from + (to - from) * step as i64 / steps as i64) as u64)
+            })
+            .collect(),
+        CappingOrder::BelowMinimum { watts } => vec![BMC_Action::SetLevel(watts)],
+    }
+}
+
+/// How long the BMC commands leading up to the cap request take
+fn sequence_duration(capping_order: CappingOrder, capping_operation: CappingOperation, cap_from: u64, cap_to: u64) -> Duration {
+    let n_actions = cap_actions(capping_order, capping_operation, cap_from, cap_to).len();
+    capping_order.interval() * u32::try_from(n_actions.saturating_sub(1)).unwrap_or(u32::MAX)
+}
+
+/// How long the load must run for: the full warmup, the command sequence, rounded up
+/// and with an allowance for each command, and the test time, with a second to spare
+fn load_duration_secs(spec: &TrialSpec) -> u64 {
+    let n_actions = cap_actions(spec.capping_order, spec.capping_operation, spec.cap_from, spec.cap_to).len() as u64;
+    let sequence = sequence_duration(spec.capping_order, spec.capping_operation, spec.cap_from, spec.cap_to);
+    let sequence_secs = sequence.as_secs() + u64::from(sequence.subsec_nanos() > 0);
+    spec.warmup_secs + sequence_secs + n_actions * BMC_COMMAND_ALLOWANCE_SECS + spec.test_time_secs + 1
+}
+
+/// How long a trial is expected to take: setup pauses, the shortest possible wait for
+/// the power to settle, the full warmup, any command sequence and the test time. The
+/// time taken by the BMC commands themselves, longer settling and shorter warmups
+/// aren't known ahead so aren't accounted for.
+fn estimated_duration(spec: &TrialSpec, setup_pause: Duration, bmc_poll_interval: Duration) -> Duration {
+    let n_setup_actions = setup_actions(spec.capping_order, spec.capping_operation, spec.cap_from, spec.cap_to).len();
+    let n_pauses = u32::try_from(n_setup_actions.saturating_sub(1)).unwrap_or(u32::MAX);
+    // settling takes a full window of readings, the first of which is immediate
+    let n_settle_intervals = u32::try_from(SETTLE_WINDOW - 1).unwrap_or(u32::MAX);
+    setup_pause * n_pauses
+        + bmc_poll_interval * n_settle_intervals
+        + sequence_duration(spec.capping_order, spec.capping_operation, spec.cap_from, spec.cap_to)
+        + Duration::from_secs(spec.warmup_secs + spec.test_time_secs)
+}
+
+/// What a trial would do, for a dry run (see `Driver::dry_run`)
+pub struct TrialPlan {
+    pub setup: Vec<BMC_Action>,
+    /// The load generator's command line
+    pub load: String,
+    /// The commands applied once warmed up, the last being the one under test
+    pub cap: Vec<BMC_Action>,
+    pub estimated_duration: Duration,
+}
+
+impl TrialPlan {
+    #[must_use]
+    pub fn new(spec: &TrialSpec, config: &Configuration) -> Self {
+        Self {
+            setup: setup_actions(spec.capping_order, spec.capping_operation, spec.cap_from, spec.cap_to),
+            load: load_generator::from_config(config)
+                .expect("Failed to set up the load generator")
+                .describe(&LoadSpec::new(
+                    spec.load_pct,
+                    spec.load_period_us,
+                    spec.n_threads,
+                    load_duration_secs(spec),
+                ).expect("The campaign's load is invalid").with_cpus(
+                    config.placement.place(spec.n_threads).expect("Failed to place the load generator's threads"),
+                )),
+            cap: cap_actions(spec.capping_order, spec.capping_operation, spec.cap_from, spec.cap_to),
+            estimated_duration: estimated_duration(
+                spec,
+                Duration::from_millis(config.setup_pause_millis),
+                Duration::from_millis(config.bmc_poll_interval_millis),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::campaign::Campaign;
+
+    #[test]
+    fn test_bmc_actions() {
+        use CappingOperation::{Activate, Deactivate};
+        use CappingOrder::{BelowMinimum, LevelAfterActivate, LevelBeforeActivate, LevelToLevel, MultiLevel, RepeatedToggle};
+
+        assert_eq!(setup_actions(LevelBeforeActivate, Activate, 580, 400), vec![BMC_Action::SetLevel(400), BMC_Action::Deactivate]);
+        assert_eq!(cap_actions(LevelBeforeActivate, Activate, 580, 400), vec![BMC_Action::Activate]);
+        assert_eq!(setup_actions(LevelBeforeActivate, Deactivate, 580, 400), vec![BMC_Action::SetLevel(400), BMC_Action::Activate]);
+        assert_eq!(cap_actions(LevelBeforeActivate, Deactivate, 580, 400), vec![BMC_Action::Deactivate]);
+        assert_eq!(setup_actions(LevelAfterActivate, Activate, 580, 400), vec![BMC_Action::SetLevel(580), BMC_Action::Activate]);
+        assert_eq!(cap_actions(LevelAfterActivate, Activate, 580, 400), vec![BMC_Action::SetLevel(400)]);
+        assert_eq!(setup_actions(LevelToLevel, Deactivate, 580, 400), vec![BMC_Action::SetLevel(580), BMC_Action::Activate]);
+        assert_eq!(cap_actions(LevelToLevel, Deactivate, 580, 400), vec![BMC_Action::SetLevel(400)]);
+
+        let toggle = RepeatedToggle { cycles: 2, interval_millis: 1000 };
+        assert_eq!(setup_actions(toggle, Activate, 580, 400), vec![BMC_Action::SetLevel(400), BMC_Action::Deactivate]);
+        assert_eq!(cap_actions(toggle, Activate, 580, 400), vec![
+            BMC_Action::Activate, BMC_Action::Deactivate, BMC_Action::Activate, BMC_Action::Deactivate, BMC_Action::Activate,
+        ]);
+        assert_eq!(cap_actions(toggle, Deactivate, 580, 400).last(), Some(&BMC_Action::Deactivate));
+        assert_eq!(sequence_duration(toggle, Activate, 580, 400), Duration::from_secs(4));
+
+        let multi = MultiLevel { steps: 3, interval_millis: 500 };
+        assert_eq!(setup_actions(multi, Activate, 580, 400), vec![BMC_Action::SetLevel(580), BMC_Action::Activate]);
+        assert_eq!(cap_actions(multi, Activate, 580, 400), vec![BMC_Action::SetLevel(520), BMC_Action::SetLevel(460), BMC_Action::SetLevel(400)]);
+        assert_eq!(cap_actions(multi, Activate, 400, 580), vec![BMC_Action::SetLevel(460), BMC_Action::SetLevel(520), BMC_Action::SetLevel(580)]);
+        assert_eq!(sequence_duration(multi, Activate, 580, 400), Duration::from_secs(1));
+
+        let below = BelowMinimum { watts: 10 };
+        assert_eq!(setup_actions(below, Activate, 580, 400), vec![BMC_Action::SetLevel(580), BMC_Action::Activate]);
+        assert_eq!(cap_actions(below, Activate, 580, 400), vec![BMC_Action::SetLevel(10)]);
+        assert_eq!(sequence_duration(below, Activate, 580, 400), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_failed_reads_skipped() {
+        let deadline = || Instant::now() + Duration::from_millis(20);
+        let interval = Duration::from_millis(1);
+        let mut detector = ComplianceDetector::new(Expectation::Capped { limit: 400 });
+        detector.set_cap_accepted(true);
+        for power in [600, 590, 610] {
+            detector.add_baseline(power);
+        }
+        // a BMC that stops answering once the cap is requested
+        let stopped = watch_readings(deadline(), interval, || Err("ipmitool timed out".into()), |power| {
+            detector.add_sample(Duration::from_secs(1), power);
+            false
+        });
+        assert!(!stopped);
+        let compliance = detector.compliance();
+        assert_eq!(compliance.verdict, Verdict::Inconclusive);
+        assert_eq!((compliance.time_to_compliance, compliance.achieved_power), (None, None));
+    }
+
+    #[test]
+    fn test_detectors_skip_failed_reads() {
+        let deadline = || Instant::now() + Duration::from_millis(50);
+        let reads = |powers: Vec<Option<u64>>| {
+            let mut powers = powers.into_iter();
+            move || powers.next().flatten().ok_or_else(|| "ipmitool timed out".into())
+        };
+
+        // a run of failed reads isn't a settled power...
+        let mut settle = SettleDetector::new(3, SETTLE_TOLERANCE_PCT);
+        let dropped = vec![None; 6];
+        assert!(!watch_readings(deadline(), Duration::ZERO, reads(dropped.clone()), |power| settle.add(power)));
+        assert_eq!(settle.mean(), None);
+        // ...nor does it count towards one
+        let mut settle = SettleDetector::new(3, SETTLE_TOLERANCE_PCT);
+        let powers = [None, None, Some(400), None, Some(401), None, Some(399)];
+        assert!(watch_readings(deadline(), Duration::ZERO, reads(powers.to_vec()), |power| settle.add(power)));
+        assert_eq!(settle.mean(), Some(400));
+
+        // nor a plateau, before the load has ramped up
+        let mut plateau = PlateauDetector::new(PLATEAU_WINDOW, PLATEAU_STDDEV_PCT).with_floor(315);
+        assert!(!watch_readings(deadline(), Duration::ZERO, reads(dropped), |power| plateau.add(power)));
+    }
+
+    #[test]
+    fn test_drop_unjournaled_rows() {
+        let path = std::env::temp_dir().join(format!("driver_log_test_{}.csv", std::process::id()));
+        assert_eq!(drop_unjournaled_rows(&path, &HashMap::new()).unwrap(), 0);
+
+        fs::write(&path, "trial_id,start_time\n0,2024-01-01T12:00:00+00:00\n1,2024-01-01T12:01:00+00:00\n").unwrap();
+        let passed = Compliance { verdict: Verdict::Pass, time_to_compliance: None, achieved_power: None };
+        // trial 1 was logged, but killed before it was journaled
+        assert_eq!(drop_unjournaled_rows(&path, &HashMap::from([(0, passed)])).unwrap(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "trial_id,start_time\n0,2024-01-01T12:00:00+00:00\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_duration() {
+        let mut spec = Campaign::builtin(400, 580, 10, 15).trials(4)[0].clone();
+        spec.capping_order = CappingOrder::LevelToLevel;
+        assert_eq!(load_duration_secs(&spec), 10 + 2 + 15 + 1);
+        // 1.5 s of intervals, rounded up, and 4 commands
+        spec.capping_order = CappingOrder::MultiLevel { steps: 4, interval_millis: 500 };
+        assert_eq!(load_duration_secs(&spec), 10 + 2 + 4 * 2 + 15 + 1);
+    }
+
+    #[test]
+    fn test_estimated_duration() {
+        let spec = &Campaign::builtin(400, 580, 10, 15).trials(4)[0];
+        assert_eq!(
+            estimated_duration(spec, Duration::from_millis(500), Duration::from_secs(1)),
+            Duration::from_millis(500 + 4000 + 25_000),
+        );
     }
 }
@@ -1,5 +1,5 @@
 //! The application parses the command-line arguments (see below) creates the stats directory,
-//! launches a monitor thread that drives BMC and RAPL monitors (see `monitor.rs`)
+//! launches a monitor thread that drives BMC, RAPL and CPU monitors (see `monitor.rs`)
 //! before launching the primary load driver (see `driver.rs`) which runs through the
 //! test permutations.
 //!
@@ -7,13 +7,25 @@
 //! and save their results. The main threads waits for these child threads to complete
 //! before exiting itself.
 
+pub mod analyze;
 pub mod bmc;
+pub mod campaign;
+pub mod check;
 pub mod cli;
+pub mod clock;
 pub mod driver;
+pub mod metrics;
 pub mod monitor;
+pub mod proc_stat;
 pub mod rapl;
+pub mod settings;
+pub mod shutdown;
+pub mod topology;
 
 use log::debug;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
 use sysconf::{self, SysconfVariable};
 
 /// Generic result type - any error can be moved to `std::error::Error` type
@@ -32,6 +44,24 @@ pub fn core_count() -> u64 {
     cores
 }
 
+///`append_csv`
+///
+/// Opens a csv file for appending, writing `header` first if the file is new. A resumed
+/// campaign reuses the test timestamp of the run it resumes, so carries on writing to
+/// the same stats files.
+///
+/// # Errors
+/// If the file can't be opened or the header can't be written
+pub fn append_csv(path: &Path, header: &str) -> ResultType<BufWriter<File>> {
+    let is_new = !path.exists();
+    let handle = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(handle);
+    if is_new {
+        writeln!(writer, "{header}")?;
+    }
+    Ok(writer)
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -42,4 +72,15 @@ mod tests {
         let core_count = core_count();
         assert!(core_count > 0);
     }
+
+    #[test]
+    fn test_append_csv_writes_header_once() {
+        let path = std::env::temp_dir().join(format!("append_csv_{}.csv", std::process::id()));
+        for row in ["1,2", "3,4"] {
+            let mut writer = append_csv(&path, "a,b").unwrap();
+            writeln!(writer, "{row}").unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a,b\n1,2\n3,4\n");
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -1,34 +1,195 @@
 use crate::driver::Driver;
-use log::{debug, info};
+use log::{debug, error, info};
 use simple_logger::SimpleLogger;
-use std::{fs, sync::mpsc, thread};
+use std::{fs, panic::{self, AssertUnwindSafe}, process, sync::{mpsc::{self, Sender}, Arc}, thread::{self, JoinHandle}, time::Duration};
 
-use capping::cli::CONFIGURATION;
-use capping::{driver, monitor};
+use capping::bmc::{BMC, BMC_Action};
+use capping::campaign::Campaign;
+use capping::driver::load_generator;
+use capping::cli::{BmcCommand, Command, Configuration};
+use capping::{analyze, check, clock, core_count, driver, metrics, monitor, settings, shutdown, ResultType};
+use capping::monitor::MonitorMessage;
+use capping::topology::Placement;
+
+// How often the monitor command checks whether it's been interrupted
+const MONITOR_ONLY_POLL_MILLIS: u64 = 200;
 
 
 /// `main()` - entry point
 ///
-/// The application parses the command-line arguments (see below) creates the stats directory,
-/// launches a monitor thread that drives BMC and RAPL monitors (see `monitor.rs`)
-/// before launching the primary load driver (see `driver.rs`) which runs through the
-/// test permutations.
+/// The application parses the command-line arguments, over any config file and
+/// environment variables (see `cli.rs` and `settings.rs`), and runs the subcommand:
 ///
-/// Once the load driver has completed, signal the monitor threads that they should exit
-/// and save their results. The main threads waits for these child threads to complete
-/// before exiting itself.
-
+/// `run` creates the stats directory, launches a monitor thread that drives BMC, RAPL
+/// and CPU monitors (see `monitor.rs`) before launching the primary load driver (see
+/// `driver.rs`) which runs through the test permutations. Once the load driver has
+/// completed, signal the monitor threads that they should exit and save their results.
+/// The main threads waits for these child threads to complete and restores the BMC's
+/// original cap settings before exiting itself. `--dry-run` prints the trials the
+/// driver would run, and exits.
+///
+/// `monitor` runs the monitors alone, until interrupted, to record the power of a
+/// workload run by other means. Both save the effective configuration, with where
+/// each setting came from, in the stats directory.
+///
+/// `bmc` reads or changes the BMC's cap, `analyze` summarises the driver log of an
+/// earlier run (see `analyze.rs`) and `check` runs the pre-flight checks (see
+/// `check.rs`). Each exits with a non-zero status if it failed.
+///
+/// Ctrl-C / SIGTERM stops the driver early (see `shutdown.rs`), after which the monitors
+/// are stopped and the cap settings restored as for a normal exit. So they are if the
+/// driver panics, before the panic is passed on.
 fn main() {
     SimpleLogger::new().env().init().unwrap();
 
-    // Beware, here be fairies! There's a sort of magic around the lazy initialisation
-    // of the configuration. At the first access, the configuration is loaded which in
-    // turn sets off the parsing of the command-line arguments, so we appear to get a
-    // value without having ever initialised it.
-    debug!("Runtime config\n{:#?}", *CONFIGURATION);
+    // The configuration is shared with the driver and the monitor threads
+    let config = Arc::new(Configuration::from_args());
+    debug!("Runtime config\n{config:#?}");
+
+    match &config.command {
+        Command::Run => run(&config),
+        Command::Monitor => monitor_only(&config),
+        Command::Bmc(action) => exit_on_error(bmc_command(&config, *action)),
+        Command::Analyze { timestamp } => exit_on_error(analyze_run(&config, timestamp.as_deref())),
+        Command::Check => {
+            let report = check::run_checks(&config);
+            println!("{report}");
+            process::exit(i32::from(!report.passed()));
+        }
+    }
+}
+
+/// Prints the output of a command that succeeded, or the error and exits with a
+/// non-zero status
+fn exit_on_error(result: ResultType<String>) {
+    match result {
+        Ok(output) => println!("{output}"),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs the campaign, the search or the sweep
+fn run(config: &Arc<Configuration>) {
+    let builtin_campaign = Campaign::builtin(
+        config.cap_low_watts,
+        config.cap_high_watts,
+        config.warmup_secs,
+        config.test_time_secs,
+    );
+    let mut campaign = match &config.campaign {
+        Some(path) => Campaign::load(path, builtin_campaign).expect("Failed to load campaign"),
+        None => builtin_campaign,
+    };
+    if let Some(repetitions) = config.repetitions {
+        campaign.repetitions = repetitions;
+    }
+    if config.sweep.is_some() {
+        campaign.sweep.clone_from(&config.sweep);
+    }
+    if config.seed.is_some() {
+        campaign.seed = config.seed;
+        campaign.seed_is_random = false;
+    } else if config.shuffle && campaign.seed.is_none() {
+        campaign.seed = Some(Campaign::random_seed());
+        campaign.seed_is_random = true;
+    }
+    campaign.validate().expect("Invalid campaign");
+    let generator = load_generator::from_config(config).expect("Failed to set up the load generator");
+    campaign.validate_profiles(generator.changes_load_in_place()).expect("Invalid campaign");
+    if config.placement != Placement::None {
+        info!("Load generator threads placed: {}", config.placement);
+        for spec in campaign.trials(core_count()) {
+            config.placement.place(spec.n_threads).expect("Failed to place the load generator's threads");
+        }
+    }
+    if let Some(seed) = campaign.seed {
+        info!("Trials shuffled with seed {seed}");
+    }
+    debug!("Campaign\n{campaign:#?}");
+
+    if config.dry_run {
+        if config.search {
+            println!("The search picks each trial from the results of the last, so can't be listed ahead");
+        } else {
+            driver::dry_run(&campaign, config);
+            // a resumed campaign's order is the journal's
+            if let (Some(seed), true, false) = (campaign.seed, campaign.seed_is_random, config.resume) {
+                println!("The seed was chosen at random, run with --seed {seed} for the trials to run in this order");
+            }
+        }
+        return;
+    }
+
+    // A resumed run's monotonic timestamps carry on from the first run's
+    if config.resume {
+        driver::resume_clock(config);
+    }
+
+    // Capture the cap settings, to leave the server as we found it
+    let bmc = BMC::from_config(config);
+    let initial_cap_settings = bmc.current_cap_settings();
+    info!("Initial BMC cap settings: {initial_cap_settings:?}");
+
+    let (monitor_tx, monitor_thread) = start_monitor(config);
+    info!("Launching driver");
+    let driver = Driver::new(campaign, Arc::clone(config), monitor_tx.clone());
+    // a driver or monitor panic still saves the stats and restores the cap, before it's
+    // propagated
+    let driven = panic::catch_unwind(AssertUnwindSafe(|| {
+        if config.search {
+            driver.search();
+        } else {
+            driver.run();
+        }
+    }));
+    if driven.is_ok() {
+        info!("Driver exited");
+    } else {
+        error!("Driver panicked");
+    }
+    let monitored = stop_monitor(&monitor_tx, monitor_thread);
+
+    info!("Restoring BMC cap settings: {initial_cap_settings:?}");
+    bmc.apply_cap_settings(&initial_cap_settings);
+    if let Err(e) = driven.and(monitored) {
+        panic::resume_unwind(e);
+    }
+}
+
+/// Records the BMC, RAPL and CPU stats until interrupted, without touching the cap
+fn monitor_only(config: &Arc<Configuration>) {
+    let (monitor_tx, monitor_thread) = start_monitor(config);
+    info!("Monitoring until interrupted");
+    while !shutdown::requested() {
+        thread::sleep(Duration::from_millis(MONITOR_ONLY_POLL_MILLIS));
+    }
+    if let Err(e) = stop_monitor(&monitor_tx, monitor_thread) {
+        panic::resume_unwind(e);
+    }
+}
+
+/// Creates the stats directory and launches the monitor thread, returning the sending
+/// end of its channel
+fn start_monitor(config: &Arc<Configuration>) -> (Sender<MonitorMessage>, JoinHandle<()>) {
+    // Fix the monotonic clock's epoch before any samples are taken. The
+    // monotonic_us columns in the stats files are relative to this instant.
+    info!("Monotonic clock epoch: {}", clock::epoch_wallclock().to_rfc3339());
 
     // create the stats directory
-    fs::create_dir_all(&CONFIGURATION.stats_dir).expect("Failed to create stats directory");
+    fs::create_dir_all(&config.stats_dir).expect("Failed to create stats directory");
+    if let Some(profile) = &config.profile {
+        info!("Config file {} profile {profile} applied", config.config_file.as_deref().unwrap_or_default());
+    }
+    let effective_config = settings::save_effective(config).expect("Failed to save the effective configuration");
+    info!("Effective configuration saved to {}", effective_config.display());
+
+    if let Some(metrics_addr) = &config.metrics_addr {
+        metrics::start_exporter(metrics_addr).expect("Failed to start Prometheus exporter");
+    }
+    shutdown::install_handler().expect("Failed to install signal handler");
 
     // create channel + sender & receiver for the monitor thread
     // mpsc = multi-producer, single consumer
@@ -36,16 +197,59 @@ fn main() {
 
     info!("Launching monitor");
     // the "move" here gives ownership of the monitor_rx channel to the thread
-    let monitor_thread = thread::spawn(move || monitor::monitor(&monitor_rx));
-    info!("Launching driver");
-    let driver = Driver::new();
-    driver.run();
-    info!("Driver exited");
+    let monitor_config = Arc::clone(config);
+    let monitor_thread = thread::spawn(move || monitor::monitor(&monitor_rx, &monitor_config));
+    (monitor_tx, monitor_thread)
+}
 
-    // Signal monitor to shutdown
-    monitor_tx.send(()).unwrap();
+/// Signals the monitor to shutdown and waits for it to exit - the child threads have
+/// to write their stats. Returns the monitor's panic, if it had one, for the caller to
+/// propagate once it's tidied up.
+fn stop_monitor(monitor_tx: &Sender<MonitorMessage>, monitor_thread: JoinHandle<()>) -> thread::Result<()> {
+    // the send only fails if the monitor has already gone, which the join reports
+    if monitor_tx.send(MonitorMessage::Shutdown).is_err() {
+        error!("Monitor exited before it was signalled to shutdown");
+    }
+    let monitored = monitor_thread.join();
+    if monitored.is_ok() {
+        info!("Monitor ended");
+    } else {
+        error!("Monitor panicked");
+    }
+    monitored
+}
+
+/// Carries out the `bmc` subcommand, returning what to print
+fn bmc_command(config: &Configuration, action: BmcCommand) -> ResultType<String> {
+    let bmc = BMC::from_config(config);
+    Ok(match action {
+        BmcCommand::Get => {
+            let settings = bmc.try_current_cap_settings()?;
+            format!("limit {} W, {}", settings.power_limit, if settings.is_active { "active" } else { "inactive" })
+        }
+        BmcCommand::Set { watts } => {
+            bmc.try_apply(BMC_Action::SetLevel(watts))?;
+            format!("limit set to {watts} W")
+        }
+        BmcCommand::Activate => {
+            bmc.try_apply(BMC_Action::Activate)?;
+            String::from("cap activated")
+        }
+        BmcCommand::Deactivate => {
+            bmc.try_apply(BMC_Action::Deactivate)?;
+            String::from("cap deactivated")
+        }
+        BmcCommand::Power => {
+            let reading = bmc.try_current_power_reading()?;
+            format!("{} W at {} (min {} W, max {} W, average {} W)",
+                reading.instant, reading.timestamp, reading.minimum, reading.maximum, reading.average)
+        }
+    })
+}
 
-    // Wait for monitor to exit - the child threads have to write their stats
-    monitor_thread.join().unwrap();
-    info!("Monitor ended");
+/// Summarises an earlier run and saves the summary, returning it
+fn analyze_run(config: &Configuration, timestamp: Option<&str>) -> ResultType<String> {
+    let analysis = analyze::analyze(config, timestamp)?;
+    let path = analyze::save_summary(config, &analysis)?;
+    Ok(format!("{analysis}\nSummary saved to {}", path.display()))
 }
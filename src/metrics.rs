@@ -0,0 +1,265 @@
+//! A minimal Prometheus exporter. The monitor threads (and the driver, for the trial
+//! parameters) publish their latest readings to `METRICS`; when enabled, an HTTP
+//! listener renders them in the Prometheus text exposition format on `/metrics`.
+
+use crate::bmc::BMC_CapSetting;
+use crate::rapl::RAPL_Reading;
+use crate::ResultType;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const METRICS_PATH: &str = "/metrics";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const MAX_REQUEST_BYTES: usize = 8192;
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+lazy_static! {
+    /// The latest readings, shared between the monitors, the driver and the exporter.
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// The parameters of the trial in progress
+#[derive(Debug, Clone, Default)]
+pub struct TrialMetrics {
+    pub trial_id: u64,
+    pub load_pct: u64,
+    pub load_period_us: u64,
+    pub n_threads: u64,
+    pub cap_from: u64,
+    pub cap_to: u64,
+    pub capping_order: String,
+    pub capping_operation: String,
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    bmc_power_watts: Option<u64>,
+    cap_limit_watts: Option<u64>,
+    cap_is_active: Option<bool>,
+    /// domain => power in mW
+    rapl_power_mw: BTreeMap<String, u64>,
+    trial: Option<TrialMetrics>,
+    last_trial_event: Option<String>,
+}
+
+/// Thread-safe store of the latest readings
+#[derive(Debug, Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+impl Metrics {
+    pub fn update_bmc_power(&self, power_watts: u64) {
+        self.state.lock().unwrap().bmc_power_watts = Some(power_watts);
+    }
+
+    pub fn update_cap_settings(&self, cap_settings: &BMC_CapSetting) {
+        let mut state = self.state.lock().unwrap();
+        state.cap_limit_watts = Some(cap_settings.power_limit);
+        state.cap_is_active = Some(cap_settings.is_active);
+    }
+
+    /// Records the power (in mW) of each RAPL domain
+    pub fn update_rapl_power(&self, readings: &[RAPL_Reading]) {
+        let mut state = self.state.lock().unwrap();
+        for reading in readings {
+            state.rapl_power_mw.insert(reading.domain.clone(), reading.reading);
+        }
+    }
+
+    pub fn update_trial(&self, trial: TrialMetrics) {
+        self.state.lock().unwrap().trial = Some(trial);
+    }
+
+    pub fn update_trial_event(&self, event: &str) {
+        self.state.lock().unwrap().last_trial_event = Some(String::from(event));
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format. Readings that
+    /// haven't been taken yet are omitted.
+    #[must_use]
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        let mut gauge = |name: &str, help: &str, samples: &[(String, u64)]| {
+            if samples.is_empty() {
+                return;
+            }
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        let unlabelled = |value: Option<u64>| value.map(|v| (String::new(), v)).into_iter().collect::<Vec<_>>();
+
+        gauge("capping_bmc_power_watts", "Instantaneous power reported by the BMC",
+            &unlabelled(state.bmc_power_watts));
+        gauge("capping_bmc_cap_limit_watts", "Power cap limit configured on the BMC",
+            &unlabelled(state.cap_limit_watts));
+        gauge("capping_bmc_cap_active", "1 if the BMC power cap is active",
+            &unlabelled(state.cap_is_active.map(u64::from)));
+
+        let rapl: Vec<(String, u64)> = state.rapl_power_mw
+            .iter()
+            .map(|(domain, power)| (format!("{{domain=\"{domain}\"}}"), *power))
+            .collect();
+        gauge("capping_rapl_power_milliwatts", "Power of each RAPL domain", &rapl);
+
+        if let Some(trial) = &state.trial {
+            let event = state.last_trial_event.as_deref().unwrap_or("");
+            gauge("capping_trial_info", "Capping scenario of the current trial", &[(
+                format!(
+                    "{{capping_order=\"{}\",capping_operation=\"{}\",event=\"{event}\"}}",
+                    trial.capping_order, trial.capping_operation
+                ),
+                1,
+            )]);
+            gauge("capping_trial_id", "Id of the current trial", &unlabelled(Some(trial.trial_id)));
+            gauge("capping_trial_load_percent", "Requested load of the current trial",
+                &unlabelled(Some(trial.load_pct)));
+            gauge("capping_trial_load_period_microseconds", "Requested load period of the current trial",
+                &unlabelled(Some(trial.load_period_us)));
+            gauge("capping_trial_threads", "Requested load threads of the current trial (0 = all)",
+                &unlabelled(Some(trial.n_threads)));
+            gauge("capping_trial_cap_from_watts", "Cap level before the capping operation",
+                &unlabelled(Some(trial.cap_from)));
+            gauge("capping_trial_cap_to_watts", "Cap level after the capping operation",
+                &unlabelled(Some(trial.cap_to)));
+        }
+        out
+    }
+}
+
+/// Binds the exporter to `addr` (e.g. "127.0.0.1:9464") and serves `METRICS`
+/// on its own thread. Returns the bound address, useful when binding to port 0.
+///
+/// # Errors
+/// If the address can't be parsed or bound
+pub fn start_exporter(addr: &str) -> ResultType<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    info!("Prometheus exporter listening on http://{local_addr}{METRICS_PATH}");
+    thread::spawn(move || serve(&listener, &METRICS));
+    Ok(local_addr)
+}
+
+/// Accepts connections forever, answering each one in turn. Scrapes are rare and
+/// cheap, so there's no need to handle connections concurrently.
+fn serve(listener: &TcpListener, metrics: &Metrics) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle_connection(stream, metrics) {
+                    warn!("Prometheus exporter failed to answer request: {e}");
+                }
+            }
+            Err(e) => warn!("Prometheus exporter failed to accept connection: {e}"),
+        }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, metrics: &Metrics) -> ResultType<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+    // Read until the end of the request headers - the request has no body
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let request_line = request.lines().next().unwrap_or_default();
+    debug!("Prometheus exporter request: {request_line}");
+
+    let mut parts = request_line.split_ascii_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(METRICS_PATH)) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", String::from("Not Found\n")),
+        _ => ("405 Method Not Allowed", String::from("Method Not Allowed\n")),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http_get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_render_omits_missing_readings() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.render(), "");
+
+        metrics.update_bmc_power(450);
+        let rendered = metrics.render();
+        assert!(rendered.contains("# TYPE capping_bmc_power_watts gauge\ncapping_bmc_power_watts 450\n"));
+        assert!(!rendered.contains("capping_bmc_cap_limit_watts"));
+        assert!(!rendered.contains("capping_trial_id"));
+    }
+
+    #[test]
+    fn test_exporter_on_loopback() {
+        let metrics: &'static Metrics = Box::leak(Box::default());
+        metrics.update_bmc_power(512);
+        metrics.update_cap_settings(&BMC_CapSetting { is_active: true, power_limit: 400 });
+        metrics.update_rapl_power(&[RAPL_Reading::new("pkg0", 95_000), RAPL_Reading::new("core0", 80_000)]);
+        metrics.update_trial(TrialMetrics {
+            trial_id: 7,
+            load_pct: 97,
+            load_period_us: 10_000,
+            n_threads: 0,
+            cap_from: 580,
+            cap_to: 400,
+            capping_order: String::from("LevelToLevel"),
+            capping_operation: String::from("Activate"),
+        });
+        metrics.update_trial_event("cap_request");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(&listener, metrics));
+
+        let response = http_get(addr, METRICS_PATH);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("capping_bmc_power_watts 512\n"));
+        assert!(response.contains("capping_bmc_cap_limit_watts 400\n"));
+        assert!(response.contains("capping_bmc_cap_active 1\n"));
+        assert!(response.contains("capping_rapl_power_milliwatts{domain=\"pkg0\"} 95000\n"));
+        assert!(response.contains("capping_rapl_power_milliwatts{domain=\"core0\"} 80000\n"));
+        assert!(response.contains(
+            "capping_trial_info{capping_order=\"LevelToLevel\",capping_operation=\"Activate\",event=\"cap_request\"} 1\n"
+        ));
+        assert!(response.contains("capping_trial_id 7\n"));
+        assert!(response.contains("capping_trial_load_percent 97\n"));
+
+        let response = http_get(addr, "/elsewhere");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
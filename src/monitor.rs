@@ -1,39 +1,249 @@
 mod monitor_bmc;
+mod monitor_cpu;
 mod monitor_rapl;
+mod poll_schedule;
 
-use log::{trace, debug};
+use crate::cli::Configuration;
+use crate::metrics::METRICS;
+use crate::monitor::poll_schedule::PollSummary;
+use crate::{append_csv, ResultType};
+use chrono::{DateTime, Local, SecondsFormat};
+use log::{trace, debug, info};
+use std::fmt::{self, Display};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
 use std::thread;
 
+/// The milestones of a trial, as signalled by the driver
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TrialEventKind {
+    WarmupStart,
+    CapRequest,
+    CapAcknowledged,
+    /// The load profile (see `driver/load_profile.rs`) changed the load
+    LoadChange,
+    TrialEnd,
+}
+
+impl Display for TrialEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}",
+            match self {
+                Self::WarmupStart => "warmup_start",
+                Self::CapRequest => "cap_request",
+                Self::CapAcknowledged => "cap_acknowledged",
+                Self::LoadChange => "load_change",
+                Self::TrialEnd => "trial_end",
+            }
+        )
+    }
+}
+
+/// A timestamped milestone of a given trial
+#[derive(Debug, Copy, Clone)]
+pub struct TrialEvent {
+    pub trial_id: u64,
+    pub kind: TrialEventKind,
+    pub timestamp: DateTime<Local>,
+    /// Monotonic timestamp (see `clock.rs`) at which the event was raised, in µs
+    pub monotonic_us: u64,
+}
+
+impl TrialEvent {
+    #[must_use]
+    pub fn new(
+        trial_id: u64,
+        kind: TrialEventKind,
+        timestamp: DateTime<Local>,
+        monotonic_us: u64,
+    ) -> Self {
+        Self { trial_id, kind, timestamp, monotonic_us }
+    }
+}
+
+/// Messages sent to the monitor thread, and cascaded to its children
+#[derive(Debug, Copy, Clone)]
+pub enum MonitorMessage {
+    /// The driver has reached a trial milestone
+    Event(TrialEvent),
+    /// Time to save the stats and exit
+    Shutdown,
+}
+
+/// The most recent trial event seen by a monitor. Every sample is stamped with
+/// the marker current when it was taken, so that the monitor streams can be
+/// sliced by trial without relying on wall-clock correlation.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct TrialMarker {
+    trial_id: Option<u64>,
+    last_event: Option<TrialEventKind>,
+}
+
+impl TrialMarker {
+    fn update(&mut self, event: &TrialEvent) {
+        self.trial_id = Some(event.trial_id);
+        self.last_event = Some(event.kind);
+    }
+}
 
-/// Controls the monitoring of BMC and local RAPL
-/// Runs on its own thread. Once started it launches two sub-threads, one for RAPL
-/// the other for BMC and then waits on the provided mpsc channel for a signal from
-/// the main thread that it's time to shutdown. It cascades the message to its own
-/// child threads, waits for them to finish writing their stats and then exits.
+impl Display for TrialMarker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Samples taken before the first trial have empty fields
+        match (self.trial_id, self.last_event) {
+            (Some(trial_id), Some(event)) => write!(f, "{trial_id},{event}"),
+            _ => write!(f, ","),
+        }
+    }
+}
+
+/// Drains the messages waiting on a child monitor's channel, updating its
+/// trial marker. Returns true if the monitor has been asked to shutdown.
+pub(crate) fn poll_messages(rx: &Receiver<MonitorMessage>, marker: &mut TrialMarker) -> bool {
+    let mut shutdown = false;
+    for message in rx.try_iter() {
+        match message {
+            MonitorMessage::Event(event) => marker.update(&event),
+            MonitorMessage::Shutdown => shutdown = true,
+        }
+    }
+    shutdown
+}
+
+/// Controls the monitoring of BMC, local RAPL and CPU utilisation
+/// Runs on its own thread. Once started it launches three sub-threads, one each for RAPL,
+/// BMC and CPU utilisation and then waits on the provided mpsc channel for messages from
+/// the main thread. Trial events from the driver are forwarded to each of the children,
+/// which stamp them on their samples, and are saved to their own CSV file. When signalled
+/// that it's time to shutdown, it cascades the message to its own child threads, waits for
+/// them to finish writing their stats, saves a summary of the sampling intervals each of
+/// them achieved and then exits.
 ///
 /// # Arguments
 /// * `rx` - the receiving end of a channel with the main thread
-pub fn monitor(rx: &Receiver<()>) {
+/// * `config` - the configuration, shared with the child threads
+pub fn monitor(rx: &Receiver<MonitorMessage>, config: &Arc<Configuration>) {
     debug!("MONITOR: starting");
 
     let (rapl_tx, rapl_rx) = mpsc::channel();
     let (bmc_tx, bmc_rx) = mpsc::channel();
+    let (cpu_tx, cpu_rx) = mpsc::channel();
+
+    let rapl_config = Arc::clone(config);
+    let rapl_thread = thread::spawn(move || monitor_rapl::monitor_rapl(&rapl_rx, &rapl_config));
+    let bmc_config = Arc::clone(config);
+    let bmc_thread = thread::spawn(move || monitor_bmc::monitor_bmc(&bmc_rx, &bmc_config));
+    let cpu_config = Arc::clone(config);
+    let cpu_thread = thread::spawn(move || monitor_cpu::monitor_cpu(&cpu_rx, &cpu_config));
+    let children = [
+        (rapl_tx, rapl_thread),
+        (bmc_tx, bmc_thread),
+        (cpu_tx, cpu_thread),
+    ];
 
-    let rapl_thread = thread::spawn(move || monitor_rapl::monitor_rapl(&rapl_rx));
-    let bmc_thread = thread::spawn(move || monitor_bmc::monitor_bmc(&bmc_rx));
+    let mut events = Vec::<TrialEvent>::new();
+    loop {
+        let message = rx.recv()
+            .expect("Monitor driver failed to receive message from main thread");
+        if let MonitorMessage::Event(event) = message {
+            trace!("MONITOR: trial {} {}", event.trial_id, event.kind);
+            METRICS.update_trial_event(&event.kind.to_string());
+            events.push(event);
+        }
 
-    rx.recv()
-        .expect("Monitor driver failed to receive message from main thread");
+        for (channel, _) in &children {
+            channel
+                .send(message)
+                .expect("Monitor driver failed to send messaged to child monitor");
+        }
+
+        if let MonitorMessage::Shutdown = message {
+            break;
+        }
+    }
 
-    trace!("MONITOR: received message - signaling children to exit");
-    for (channel, thread) in [(rapl_tx, rapl_thread), (bmc_tx, bmc_thread)] {
-        channel
-            .send(())
-            .expect("Monitor driver failed to send messaged to child monitor");
-        thread
+    trace!("MONITOR: received shutdown - waiting for children to exit");
+    let mut summaries = Vec::<PollSummary>::new();
+    for (_, thread) in children {
+        let summary = thread
             .join()
             .expect("Monitor driver failed to join children");
+        info!(
+            "MONITOR: {} sampled {} times, mean interval {:.1} ms (target {} ms), p99 {:.1} ms, {} missed deadlines",
+            summary.monitor,
+            summary.samples,
+            summary.mean_interval_ms,
+            summary.interval_ms,
+            summary.p99_interval_ms,
+            summary.missed_deadlines
+        );
+        summaries.push(summary);
     }
+    save_trial_events(config, &events).expect("Failed to save trial events");
+    save_poll_summaries(config, &summaries).expect("Failed to save monitor summary");
     debug!("MONITOR: children halted, exiting");
 }
+
+/// Writes the trial events received from the driver to CSV file.
+fn save_trial_events(config: &Configuration, events: &[TrialEvent]) -> ResultType<PathBuf> {
+    let filename = format!("{}_{}.csv",
+        config.events_filename_prefix,
+        config.test_timestamp
+    );
+    let filepath = Path::new(&config.stats_dir).join(filename);
+    debug!("Saving trial events to: {filepath:?}");
+
+    let mut writer = append_csv(&filepath, "timestamp,monotonic_us,trial_id,event")?;
+    for event in events {
+        writeln!(writer, "{},{},{},{}",
+            event.timestamp.to_rfc3339_opts(SecondsFormat::Millis, false),
+            event.monotonic_us,
+            event.trial_id,
+            event.kind
+        )?;
+    }
+    Ok(filepath)
+}
+
+/// Writes the achieved sampling interval statistics of each monitor to CSV file.
+fn save_poll_summaries(config: &Configuration, summaries: &[PollSummary]) -> ResultType<PathBuf> {
+    let filename = format!("{}_{}.csv",
+        config.monitor_summary_filename_prefix,
+        config.test_timestamp
+    );
+    let filepath = Path::new(&config.stats_dir).join(filename);
+    debug!("Saving monitor summary to: {filepath:?}");
+
+    let mut writer = append_csv(
+        &filepath,
+        "monitor,interval_ms,samples,mean_interval_ms,p99_interval_ms,missed_deadlines"
+    )?;
+    for summary in summaries {
+        writeln!(writer, "{summary}")?;
+    }
+    Ok(filepath)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_messages_updates_marker() {
+        let (tx, rx) = mpsc::channel();
+        let mut marker = TrialMarker::default();
+        assert_eq!(marker.to_string(), ",");
+
+        let now = Local::now();
+        tx.send(MonitorMessage::Event(TrialEvent::new(3, TrialEventKind::WarmupStart, now, 0))).unwrap();
+        tx.send(MonitorMessage::Event(TrialEvent::new(3, TrialEventKind::CapRequest, now, 1))).unwrap();
+        assert!(!poll_messages(&rx, &mut marker));
+        assert_eq!(marker.to_string(), "3,cap_request");
+
+        tx.send(MonitorMessage::Event(TrialEvent::new(3, TrialEventKind::TrialEnd, now, 2))).unwrap();
+        tx.send(MonitorMessage::Shutdown).unwrap();
+        assert!(poll_messages(&rx, &mut marker));
+        assert_eq!(marker.to_string(), "3,trial_end");
+    }
+}
@@ -1,87 +1,144 @@
-use crate::bmc::{BMC, BMC_CapSetting};
-use crate::cli::CONFIGURATION;
-use crate::ResultType;
+use crate::bmc::{BMC, BMC_CapSetting, BMC_PowerReading};
+use crate::cli::Configuration;
+use crate::clock::{self, ClockOffsetEstimator};
+use crate::metrics::METRICS;
+use crate::monitor::poll_schedule::{PollSchedule, PollSummary};
+use crate::monitor::{poll_messages, MonitorMessage, TrialMarker};
+use crate::{append_csv, ResultType};
 use log::{info, trace, debug};
 use std::fmt;
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Receiver;
 use std::thread;
 use std::time::Duration;
-use chrono::{DateTime, Local, SecondsFormat};
-
-const BMC_INTER_COMMAND_SLEEP_MILLIS: u64 = 500;
-const BMC_POLL_INTERVAL_MILLIS: u64 = 500;
+use chrono::{DateTime, Local, NaiveDateTime, SecondsFormat};
 
 #[allow(non_camel_case_types)]
 #[derive(Debug)]
 struct BMC_Stats {
     timestamp: DateTime<Local>,
+    monotonic_us: u64,
+    bmc_timestamp: NaiveDateTime,
     power: u64,
     cap_level: u64,
     cap_is_active: bool,
+    marker: TrialMarker,
 }
 
 impl fmt::Display for BMC_Stats {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{},{},{},{}",
+            "{},{},{},{},{},{},{}",
             self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, false),
+            self.monotonic_us,
+            self.bmc_timestamp.format("%Y-%m-%dT%H:%M:%S"),
             self.power,
             self.cap_level,
             self.cap_is_active,
+            self.marker,
         )
     }
 }
 
 impl BMC_Stats {
-    pub fn new(power: u64, cap_settings: &BMC_CapSetting) -> Self {
+    /// Builds the stats for a power reading taken at the given host wall-clock
+    /// and monotonic times.
+    pub fn new(
+        timestamp: DateTime<Local>,
+        monotonic_us: u64,
+        power_reading: &BMC_PowerReading,
+        cap_settings: &BMC_CapSetting,
+        marker: TrialMarker,
+    ) -> Self {
         Self {
-            timestamp: Local::now(),
-            power,
+            timestamp,
+            monotonic_us,
+            bmc_timestamp: power_reading.timestamp,
+            power: power_reading.instant,
             cap_level: cap_settings.power_limit,
             cap_is_active: cap_settings.is_active,
+            marker,
         }
     }
 }
 
-/// Periodically polls the BMC for power reading and saves the result. Runs on its own thread.
-/// Each time through the loop, checks for a message from the main monitor thread that signals
-/// that this thread can exit. Before exiting, saves results to CSV file.
-pub fn monitor_bmc(rx: &Receiver<()>) {
+/// Periodically polls the BMC for power reading and saves the result. Runs on its own thread,
+/// polling on the deadlines of a `PollSchedule`, and returns the achieved sampling statistics.
+/// Each time through the loop, checks for messages from the main monitor thread: trial events
+/// update the marker stamped on each reading, a shutdown signals that this thread can exit.
+/// Before exiting, saves results to CSV file.
+///
+/// The BMC's timestamp in each power reading is compared with the host clock at the
+/// midpoint of the command to estimate the offset and drift between the two clocks,
+/// which are logged on exit.
+pub(crate) fn monitor_bmc(rx: &Receiver<MonitorMessage>, config: &Configuration) -> PollSummary {
     info!("\tBMC: launched");
 
-    let thread_sleep_time_ms = BMC_POLL_INTERVAL_MILLIS;
-
+    let mut schedule = PollSchedule::new(Duration::from_millis(config.bmc_poll_interval_millis));
     let mut stats = Vec::<BMC_Stats>::new();
-    let bmc = BMC::new(
-        &CONFIGURATION.bmc_hostname,
-        &CONFIGURATION.bmc_username,
-        &CONFIGURATION.bmc_password,
-    );
+    let mut marker = TrialMarker::default();
+    let mut clock_offset = ClockOffsetEstimator::new();
+    let bmc = BMC::from_config(config);
     loop {
+        schedule.wait();
+
         // Check if monitor master asked us to exit with a message on the channel
-        if rx.try_recv().is_ok() {
+        if poll_messages(rx, &mut marker) {
             trace!("\tBMC: got message - exiting");
             break;
         }
 
         // No message, read current power and capping status
-        let current_power = bmc.current_power();
-        thread::sleep(Duration::from_millis(BMC_INTER_COMMAND_SLEEP_MILLIS));
+        let (host_before, monotonic_before) = (Local::now(), clock::monotonic_micros());
+        let power_reading = bmc.current_power_reading();
+        METRICS.update_bmc_power(power_reading.instant);
+        let (host_after, monotonic_after) = (Local::now(), clock::monotonic_micros());
+        let host_midpoint = host_before + (host_after - host_before) / 2;
+        let monotonic_midpoint = monotonic_before + (monotonic_after - monotonic_before) / 2;
+        clock_offset.add_sample(
+            monotonic_midpoint,
+            host_midpoint.naive_local(),
+            power_reading.timestamp,
+        );
+
+        // Pause between the power and cap readings, to avoid overloading the BMC.
+        // The poll interval must be long enough to accommodate it, and the latency
+        // of both ipmitool commands, or deadlines will be missed.
+        thread::sleep(Duration::from_millis(config.bmc_inter_command_millis));
         let current_cap_settings = bmc.current_cap_settings();
-        let reading = BMC_Stats::new(current_power, &current_cap_settings);
+        METRICS.update_cap_settings(&current_cap_settings);
+        let reading = BMC_Stats::new(
+            host_midpoint,
+            monotonic_midpoint,
+            &power_reading,
+            &current_cap_settings,
+            marker,
+        );
 
         trace!("BMC power reading: {reading:#?}");
         stats.push(reading);
-
-        thread::sleep(Duration::from_millis(thread_sleep_time_ms));
     }
 
-    save_bmc_stats(&stats).expect("Failed to save BMC stats");
+    log_clock_offset(&clock_offset);
+    save_bmc_stats(config, &stats).expect("Failed to save BMC stats");
     info!("\tBMC: Exiting");
+    schedule.summary("bmc")
+}
+
+/// Logs the estimated offset and drift of the BMC clock with respect to the host
+fn log_clock_offset(clock_offset: &ClockOffsetEstimator) {
+    match (clock_offset.offset_secs(), clock_offset.drift_ppm()) {
+        (Some(offset), Some(drift)) => info!(
+            "\tBMC: clock offset from host {offset:+.3} s, drift {drift:+.1} ppm ({} samples)",
+            clock_offset.sample_count()
+        ),
+        (Some(offset), None) => info!(
+            "\tBMC: clock offset from host {offset:+.3} s, too few samples to estimate drift"
+        ),
+        _ => info!("\tBMC: no BMC timestamps available to estimate clock offset"),
+    }
 }
 
 /// `save_bmc_stats`
@@ -91,21 +148,20 @@ pub fn monitor_bmc(rx: &Receiver<()>) {
 /// buffered writer.
 ///
 /// Returns the constructed path in a Result<>.
-fn save_bmc_stats(stats: &[BMC_Stats]) -> ResultType<PathBuf> {
+fn save_bmc_stats(config: &Configuration, stats: &[BMC_Stats]) -> ResultType<PathBuf> {
     // Build a timestamped csv filename
     let filename = format!("{}_{}.csv",
-        CONFIGURATION.bmc_stats_filename_prefix,
-        CONFIGURATION.test_timestamp
+        config.bmc_stats_filename_prefix,
+        config.test_timestamp
     );
-    let filepath = Path::new(&CONFIGURATION.stats_dir).join(filename);
+    let filepath = Path::new(&config.stats_dir).join(filename);
     debug!("Saving stats to: {filepath:?}");
 
-    // Create buffered writer on the file
-    let handle = File::create(&filepath)?;
-    let mut writer = BufWriter::new(handle);
-
-    // write csv header
-    writeln!(writer, "timestamp,power,cap_limit,cap_is_active")?;
+    // Create buffered writer on the file, with a csv header if it's new
+    let mut writer = append_csv(
+        &filepath,
+        "timestamp,monotonic_us,bmc_timestamp,power,cap_limit,cap_is_active,trial_id,trial_event"
+    )?;
 
     // write the data
     for stat in stats {
@@ -132,22 +188,36 @@ mod tests {
             is_active: true,
         };
 
-        let s1 = BMC_Stats::new(1200, &cap_settings1);
+        let reading1 = BMC_PowerReading { instant: 1200, ..Default::default() };
+        let s1 = BMC_Stats::new(Local::now(), 0, &reading1, &cap_settings1, TrialMarker::default());
         let mut stats = Vec::<BMC_Stats>::new();
         stats.push(s1);
 
-        // main() is not run when running tests, so ensure
-        // stats dir exists ourselves.
-        fs::create_dir_all(&CONFIGURATION.stats_dir).expect("Failed to create stats directory");
+        // write to a stats directory of our own, rather than the default one
+        let mut config = Configuration::for_bmc("bmc", "user", "password");
+        config.stats_dir = std::env::temp_dir()
+            .join(format!("bmc_stats_{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        fs::create_dir_all(&config.stats_dir).expect("Failed to create stats directory");
 
-        let s2 = BMC_Stats::new(500, &cap_settings2);
+        let reading2 = BMC_PowerReading { instant: 500, ..Default::default() };
+        let s2 = BMC_Stats::new(Local::now(), 500_000, &reading2, &cap_settings2, TrialMarker::default());
         stats.push(s2);
-        let rc = save_bmc_stats(&stats);
+        let rc = save_bmc_stats(&config, &stats);
         assert!(rc.is_ok());
         let stats_filepath = rc.unwrap();
 
-        // todo: test file path & file content
-
-        fs::remove_file(stats_filepath).expect("Test save stats, failed to remove stats file");
+        assert_eq!(
+            stats_filepath,
+            Path::new(&config.stats_dir).join(format!("bmc_stats_{}.csv", config.test_timestamp))
+        );
+        let contents = fs::read_to_string(&stats_filepath).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("timestamp,monotonic_us,"));
+        assert!(lines[2].contains(",500000,") && lines[2].ends_with(",500,600,true,,"), "{}", lines[2]);
+
+        fs::remove_dir_all(&config.stats_dir).expect("Test save stats, failed to remove stats directory");
     }
 }
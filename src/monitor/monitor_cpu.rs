@@ -0,0 +1,88 @@
+use crate::cli::Configuration;
+use crate::monitor::poll_schedule::{PollSchedule, PollSummary};
+use crate::monitor::{poll_messages, MonitorMessage, TrialMarker};
+use crate::proc_stat::{CPU_Utilisation, ProcStat};
+use crate::{append_csv, ResultType};
+use chrono::{DateTime, Local, SecondsFormat};
+use log::{debug, info, trace};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+/// The busy percentage of every CPU over the interval ending at `timestamp`
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+struct CPU_Stats {
+    timestamp: DateTime<Local>,
+    monotonic_us: u64,
+    utilisation: Vec<CPU_Utilisation>,
+    marker: TrialMarker,
+}
+
+/// Periodically reads `/proc/stat` and saves the per-CPU utilisation since the previous
+/// reading. Runs on its own thread, polling on the deadlines of a `PollSchedule`, and returns
+/// the achieved sampling statistics. Each time through the loop, checks for messages
+/// from the main monitor thread: trial events update the marker stamped on each reading,
+/// a shutdown signals that this thread can exit. Before exiting, saves results to CSV file.
+pub(crate) fn monitor_cpu(rx: &Receiver<MonitorMessage>, config: &Configuration) -> PollSummary {
+    info!("\tCPU: launched");
+
+    let mut stats = Vec::<CPU_Stats>::new();
+    let mut marker = TrialMarker::default();
+    let mut schedule = PollSchedule::new(Duration::from_millis(config.cpu_poll_interval_millis));
+
+    // The first deadline is immediate: take the reference reading for the first interval
+    schedule.wait();
+    let mut previous = ProcStat::read();
+    loop {
+        schedule.wait();
+        if poll_messages(rx, &mut marker) {
+            trace!("\tCPU: got message - exiting");
+            break;
+        }
+
+        let current = ProcStat::read();
+        let utilisation = current.utilisation_since(&previous);
+        trace!("CPU utilisation: {utilisation:?}");
+        stats.push(CPU_Stats {
+            timestamp: current.timestamp,
+            monotonic_us: current.monotonic_us,
+            utilisation,
+            marker,
+        });
+        previous = current;
+    }
+    save_cpu_stats(config, &stats).expect("Failed to save CPU stats");
+    info!("\tCPU: Exiting");
+    schedule.summary("cpu")
+}
+
+/// Writes the CPU stats to CSV file, one row per timestamp per CPU.
+fn save_cpu_stats(config: &Configuration, stats: &[CPU_Stats]) -> ResultType<PathBuf> {
+    // Build the filename - append a timestamp and ".csv"
+    let save_filename = format!(
+        "{}_{}.csv",
+        config.cpu_stats_filename_prefix,
+        config.test_timestamp
+    );
+
+    let save_path = Path::new(&config.stats_dir).join(save_filename);
+    debug!("CPU saving stats to: {save_path:?}");
+
+    let mut writer = append_csv(&save_path, "timestamp,monotonic_us,cpu,busy_pct,trial_id,trial_event")?;
+    trace!("CPU writing {} records", stats.len());
+
+    for datapoint in stats {
+        let timestamp = datapoint.timestamp.to_rfc3339_opts(SecondsFormat::Millis, false);
+        for cpu in &datapoint.utilisation {
+            writeln!(&mut writer, "{timestamp},{},{},{:.1},{}",
+                datapoint.monotonic_us,
+                cpu.cpu,
+                cpu.busy_pct,
+                datapoint.marker)?;
+        }
+    }
+
+    Ok(save_path)
+}
@@ -1,70 +1,92 @@
-use crate::cli::CONFIGURATION;
+use crate::cli::Configuration;
+use crate::metrics::METRICS;
+use crate::monitor::poll_schedule::{PollSchedule, PollSummary};
+use crate::monitor::{poll_messages, MonitorMessage, TrialMarker};
 use crate::rapl::{RAPL_Readings, RAPL_Reading, RAPL};
-use crate::ResultType;
+use crate::{append_csv, ResultType};
 use chrono::SecondsFormat;
 use log::debug;
 use log::{info, trace};
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Receiver;
-use std::thread;
 use std::time::Duration;
 
 
 
-/// Periodically reads all the `energy_uj` files and saves the result. Runs on its own thread.
-/// Each time through the loop, checks for a message from the main monitor thread that signals
-/// that this thread can exit. Before exiting, saves results to CSV file.
-pub fn monitor_rapl(rx: &Receiver<()>) {
+/// Periodically reads all the `energy_uj` files and saves the result. Runs on its own thread,
+/// polling on the deadlines of a `PollSchedule`, and returns the achieved sampling statistics.
+/// Each time through the loop, checks for messages from the main monitor thread: trial events
+/// update the marker stamped on each reading, a shutdown signals that this thread can exit.
+/// Before exiting, saves results to CSV file.
+pub(crate) fn monitor_rapl(rx: &Receiver<MonitorMessage>, config: &Configuration) -> PollSummary {
     info!("\tRAPL: launched");
 
     let mut stats = Vec::<RAPL_Readings>::new();
+    // markers[i] is the trial marker current when stats[i] was read
+    let mut markers = Vec::<TrialMarker>::new();
+    let mut marker = TrialMarker::default();
     let rapl = RAPL::new();
-    let sleep_millis = 1000/CONFIGURATION.monitor_poll_freq_hz;
+    // the counters wrap at this value; it doesn't change, so read it once
+    let max_energy_uj = RAPL::max_energy();
+    let mut schedule = PollSchedule::new(Duration::from_millis(config.rapl_poll_interval_millis));
     loop {
-        if rx.try_recv().is_ok() {
+        schedule.wait();
+        if poll_messages(rx, &mut marker) {
             trace!("\tRAPL: got message - exiting");
             break;
         }
         let energy_reading = rapl.read_current_energy();
         trace!("{energy_reading}");
         stats.push(energy_reading);
-        thread::sleep(Duration::from_millis(sleep_millis));
+        markers.push(marker);
+
+        // Publish the power since the previous reading
+        if let [.., previous, current] = stats.as_slice() {
+            if let Some(power) = energy_to_power(previous, current, max_energy_uj) {
+                METRICS.update_rapl_power(&power.readings);
+            }
+        }
     }
-    save_rapl_stats(&stats).expect("Failed to save RAPL stats");
+    save_rapl_stats(config, &stats, &markers, max_energy_uj).expect("Failed to save RAPL stats");
     info!("\tRAPL: Exiting");
+    schedule.summary("rapl")
 }
 
 
 /// Writes the RAPL stats to CSV file.
-fn save_rapl_stats(stats: &[RAPL_Readings]) -> ResultType<PathBuf> {
+fn save_rapl_stats(config: &Configuration, stats: &[RAPL_Readings], markers: &[TrialMarker], max_energy_uj: u64) -> ResultType<PathBuf> {
     // Build the filename - append a timestamp and ".csv"
     let save_filename = format!(
         "{}_{}.csv",
-        CONFIGURATION.rapl_stats_filename_prefix,
-        CONFIGURATION.test_timestamp
+        config.rapl_stats_filename_prefix,
+        config.test_timestamp
     );
 
-    let save_path = Path::new(&CONFIGURATION.stats_dir).join(save_filename);
+    let save_path = Path::new(&config.stats_dir).join(save_filename);
     debug!("RAPL saving stats to: {}", save_path.to_str().unwrap());
 
     // Create buffered writer
-    let handle = File::create(&save_path)?;
-    let mut writer = BufWriter::new(handle);
+    let csv_header = "timestamp,monotonic_us,domain,power_mW,trial_id,trial_event";
+    let mut writer = append_csv(&save_path, csv_header)?;
     trace!("RAPL writing {} records", stats.len());
-    let csv_header = "timestamp,domain,power_mW";
-    writeln!(&mut writer, "{csv_header}")?;
 
     // Rather than recording the raw energy values, calculate the power for each domain
     // One row per timestamp per domain (makes it harder to sum total power, but it's in a
     // normalized form, ready to be loaded into a database)
-    for datapoint in convert_energy_to_power(stats) {
-        for reading in datapoint.readings {
-            writeln!(&mut writer, "{},{},{}",
+    // Each power datapoint is derived from a pair of energy readings, and takes the
+    // trial marker of the later one.
+    let datapoints = stats.windows(2).zip(markers.iter().skip(1)).filter_map(|(pair, marker)| {
+        Some((energy_to_power(&pair[0], &pair[1], max_energy_uj)?, marker))
+    });
+    for (datapoint, marker) in datapoints {
+        for reading in &datapoint.readings {
+            writeln!(&mut writer, "{},{},{},{},{}",
                 datapoint.timestamp.to_rfc3339_opts(SecondsFormat::Millis, false),
+                datapoint.monotonic_us,
                 reading.domain,
-                reading.reading)?;
+                reading.reading,
+                marker)?;
         }
     }
 
@@ -73,56 +95,47 @@ fn save_rapl_stats(stats: &[RAPL_Readings]) -> ResultType<PathBuf> {
 }
 
 /// Does what it says on the packet - divides energy deltas by time deltas to give power.
-fn convert_energy_to_power(stats: &[RAPL_Readings]) -> Vec<RAPL_Readings> {
+/// The time deltas are taken from the monotonic timestamps, so are unaffected by any
+/// steps in the wall clock. Gives the power of each RAPL domain between two readings,
+/// in mW, with the timestamps of their midpoint, or None if the readings were taken in
+/// the same microsecond, there being no time to divide by. The energy counters wrap
+/// around at `max_energy_uj`.
+fn energy_to_power(previous: &RAPL_Readings, current: &RAPL_Readings, max_energy_uj: u64) -> Option<RAPL_Readings> {
     // The units of reading are µJ
-
-    let mut readings = Vec::with_capacity(stats.len());
     // sanity check: ensure all reading have same # entries
-    let n_domains = stats[0].readings.len();
-
-    // for stats[1...], calculate power by calculating the
-    // energy change from the previous reading and dividing by
-    // the time delta for each RAPL domain. The total power is
-    // the sum of the domains.
-
-    // need to check for wrap-around - keep tabs on max_energy_uj and previous reading
-    let max_energy_uj = RAPL::max_energy();
-
-    // By using skip(1), the index from the enumerate is one behind the
-    // current row, i.e. it points to the preceding row, which is exactly
-    // what's needed to calculate the deltas.
-    for (stat_index, stat) in stats.iter().skip(1).enumerate() {
-        assert_eq!(stat.readings.len(), n_domains);
-        let mut power_readings: Vec<RAPL_Reading> = Vec::with_capacity(n_domains);
-        let time_delta = stat.timestamp - stats[stat_index].timestamp;
-        let time_midpoint = stat.timestamp - (time_delta / 2);
-
-        // Loop over the domains
-        for (domain_index, reading) in stat.readings.iter().enumerate() {
-            let previous_reading = stats[stat_index].readings[domain_index].reading;
-            let current_reading = reading.reading;
-
-            // check for wrap-around
-            let energy_delta_uj = {
-                if current_reading < previous_reading {
-                    max_energy_uj - previous_reading + current_reading // wrapped
-                } else {
-                    current_reading - previous_reading // no wrap
-                }
-            };
-
-            // time delta is always positive so no loss of sign - and in any case makes no difference
-            #[allow(clippy::cast_sign_loss)]
-            let power_watts = energy_delta_uj / time_delta.num_milliseconds() as u64;
-            power_readings.push(RAPL_Reading {
-                domain: reading.domain.clone(),
-                reading: power_watts,
-            });
-        }
-        let datapoint = RAPL_Readings {timestamp: time_midpoint, readings: power_readings};
-        readings.push(datapoint);
+    assert_eq!(current.readings.len(), previous.readings.len());
+    let monotonic_delta_us = current.monotonic_us.saturating_sub(previous.monotonic_us);
+    if monotonic_delta_us == 0 {
+        return None;
     }
-    readings
+    let monotonic_midpoint = previous.monotonic_us + monotonic_delta_us / 2;
+    let time_midpoint = previous.timestamp + chrono::Duration::microseconds(
+        i64::try_from(monotonic_delta_us / 2).unwrap_or(i64::MAX));
+
+    // Loop over the domains
+    let readings = current.readings.iter().zip(&previous.readings).map(|(reading, previous_reading)| {
+        let (previous_reading, current_reading) = (previous_reading.reading, reading.reading);
+
+        // check for wrap-around
+        let energy_delta_uj = {
+            if current_reading < previous_reading {
+                max_energy_uj - previous_reading + current_reading // wrapped
+            } else {
+                current_reading - previous_reading // no wrap
+            }
+        };
+
+        // µJ/ms is mW, kept in µs for gaps of under a millisecond
+        RAPL_Reading {
+            domain: reading.domain.clone(),
+            reading: energy_delta_uj * 1000 / monotonic_delta_us,
+        }
+    }).collect();
+    Some(RAPL_Readings {
+        timestamp: time_midpoint,
+        monotonic_us: monotonic_midpoint,
+        readings,
+    })
 }
 
 
@@ -151,31 +164,50 @@ mod tests {
         let t3 = t0 + chrono::Duration::milliseconds(3000);
         let t4 = t0 + chrono::Duration::milliseconds(5000);
 
-        let readings1 = RAPL_Readings{timestamp: t0, readings: vec![r1, r2]};
-        let readings2 = RAPL_Readings{timestamp: t1, readings: vec![r3, r4]};
-        let readings3 = RAPL_Readings{timestamp: t2, readings: vec![r5, r6]};
-        let readings4 = RAPL_Readings{timestamp: t3, readings: vec![r7, r8]};
-        let readings5 = RAPL_Readings{timestamp: t4, readings: vec![r9, r10]};
+        let readings1 = RAPL_Readings{timestamp: t0, monotonic_us: 0, readings: vec![r1, r2]};
+        let readings2 = RAPL_Readings{timestamp: t1, monotonic_us: 1_000_000, readings: vec![r3, r4]};
+        let readings3 = RAPL_Readings{timestamp: t2, monotonic_us: 2_000_000, readings: vec![r5, r6]};
+        let readings4 = RAPL_Readings{timestamp: t3, monotonic_us: 3_000_000, readings: vec![r7, r8]};
+        let readings5 = RAPL_Readings{timestamp: t4, monotonic_us: 5_000_000, readings: vec![r9, r10]};
 
-        let energy_stats = vec![readings1, readings2, readings3, readings4, readings5];
-        let power_stats = convert_energy_to_power(&energy_stats);
+        let energy_stats = [readings1, readings2, readings3, readings4, readings5];
+        let power_stats: Vec<RAPL_Readings> = energy_stats.windows(2)
+            .filter_map(|pair| energy_to_power(&pair[0], &pair[1], u64::MAX))
+            .collect();
 
         assert_eq!(power_stats.len(), energy_stats.len() - 1);
 
         // check power
-        assert_eq!(power_stats[0].readings[0].reading, 100);
-        assert_eq!(power_stats[0].readings[1].reading,  50);
-        assert_eq!(power_stats[1].readings[0].reading, 100);
-        assert_eq!(power_stats[1].readings[1].reading,  50);
-        assert_eq!(power_stats[2].readings[0].reading,   0);
-        assert_eq!(power_stats[2].readings[1].reading,   0);
-        assert_eq!(power_stats[3].readings[0].reading, 100);
-        assert_eq!(power_stats[3].readings[1].reading,  50);
+        assert_eq!(power_stats[0].readings[0].reading, 100_000);
+        assert_eq!(power_stats[0].readings[1].reading,  50_000);
+        assert_eq!(power_stats[1].readings[0].reading, 100_000);
+        assert_eq!(power_stats[1].readings[1].reading,  50_000);
+        assert_eq!(power_stats[2].readings[0].reading,       0);
+        assert_eq!(power_stats[2].readings[1].reading,       0);
+        assert_eq!(power_stats[3].readings[0].reading, 100_000);
+        assert_eq!(power_stats[3].readings[1].reading,  50_000);
 
         // check timestamps
         assert_eq!(power_stats[0].timestamp, t0 + chrono::Duration::milliseconds(500));
         assert_eq!(power_stats[1].timestamp, t0 + chrono::Duration::milliseconds(1500));
         assert_eq!(power_stats[2].timestamp, t0 + chrono::Duration::milliseconds(2500));
         assert_eq!(power_stats[3].timestamp, t0 + chrono::Duration::milliseconds(4000));
+        assert_eq!(power_stats[0].monotonic_us,   500_000);
+        assert_eq!(power_stats[3].monotonic_us, 4_000_000);
+    }
+
+    #[test]
+    fn test_energy_to_power_sub_millisecond() {
+        // a 1 ms poll catching up after a late wake-up samples well under 1 ms apart
+        let reading = |monotonic_us, reading| RAPL_Readings {
+            timestamp: Local::now(),
+            monotonic_us,
+            readings: vec![RAPL_Reading { domain: String::from("0"), reading }],
+        };
+        let power = energy_to_power(&reading(1_000_000, 5_000), &reading(1_000_400, 45_000), u64::MAX).unwrap();
+        assert_eq!(power.readings[0].reading, 100_000);
+        assert_eq!(power.monotonic_us, 1_000_200);
+        // no time between them, so no power
+        assert!(energy_to_power(&reading(1_000_000, 5_000), &reading(1_000_000, 5_000), u64::MAX).is_none());
     }
 }
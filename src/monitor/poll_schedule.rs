@@ -0,0 +1,152 @@
+use std::fmt::{self, Display};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Schedules a monitor's polling on absolute deadlines: the n-th sample is due at
+/// `start + n * interval` regardless of how long the work between samples takes, so
+/// the sampling rate doesn't drift with e.g. ipmitool latency. A sample that is late by
+/// less than an interval is taken immediately; deadlines that are a whole interval or
+/// more in the past are counted as missed and skipped.
+///
+/// The actual intervals between samples are recorded so that the achieved sampling
+/// rate can be reported when the monitor exits.
+pub(crate) struct PollSchedule {
+    interval: Duration,
+    next_deadline: Instant,
+    last_tick: Option<Instant>,
+    intervals_us: Vec<u64>,
+    missed_deadlines: u64,
+}
+
+/// Achieved sampling interval statistics for a monitor
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PollSummary {
+    pub monitor: &'static str,
+    pub interval_ms: u64,
+    pub samples: u64,
+    pub mean_interval_ms: f64,
+    pub p99_interval_ms: f64,
+    pub missed_deadlines: u64,
+}
+
+impl PollSchedule {
+    /// Creates a schedule whose first deadline is now
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_deadline: Instant::now(),
+            last_tick: None,
+            intervals_us: Vec::new(),
+            missed_deadlines: 0,
+        }
+    }
+
+    /// Sleeps until the next deadline, skipping (and counting) any that have been overrun
+    /// by a whole interval
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        while self.next_deadline + self.interval <= now {
+            self.next_deadline += self.interval;
+            self.missed_deadlines += 1;
+        }
+        thread::sleep(self.next_deadline.saturating_duration_since(now));
+
+        let tick = Instant::now();
+        if let Some(last_tick) = self.last_tick {
+            self.intervals_us.push(u64::try_from((tick - last_tick).as_micros()).unwrap_or(u64::MAX));
+        }
+        self.last_tick = Some(tick);
+        self.next_deadline += self.interval;
+    }
+
+    pub fn summary(&self, monitor: &'static str) -> PollSummary {
+        PollSummary::new(
+            monitor,
+            u64::try_from(self.interval.as_millis()).unwrap_or(u64::MAX),
+            &self.intervals_us,
+            self.missed_deadlines,
+        )
+    }
+}
+
+impl PollSummary {
+    /// Summarises the intervals (in µs) between samples. The p99 uses the nearest-rank method.
+    pub fn new(monitor: &'static str, interval_ms: u64, intervals_us: &[u64], missed_deadlines: u64) -> Self {
+        let mut sorted = intervals_us.to_vec();
+        sorted.sort_unstable();
+
+        #[allow(clippy::cast_precision_loss)]
+        let (mean_interval_ms, p99_interval_ms) = if sorted.is_empty() {
+            (0.0, 0.0)
+        } else {
+            let mean = sorted.iter().sum::<u64>() as f64 / sorted.len() as f64;
+            let rank = (sorted.len() * 99).div_ceil(100);
+            (mean / 1000.0, sorted[rank - 1] as f64 / 1000.0)
+        };
+
+        Self {
+            monitor,
+            interval_ms,
+            // n intervals separate n + 1 samples
+            samples: if sorted.is_empty() { 0 } else { sorted.len() as u64 + 1 },
+            mean_interval_ms,
+            p99_interval_ms,
+            missed_deadlines,
+        }
+    }
+}
+
+impl Display for PollSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{:.3},{:.3},{}",
+            self.monitor,
+            self.interval_ms,
+            self.samples,
+            self.mean_interval_ms,
+            self.p99_interval_ms,
+            self.missed_deadlines,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_summary() {
+        // 100 intervals: 98 on time, two late
+        let mut intervals_us = vec![250_000; 98];
+        intervals_us.push(300_000);
+        intervals_us.push(500_000);
+
+        let summary = PollSummary::new("rapl", 250, &intervals_us, 1);
+        assert_eq!(summary.samples, 101);
+        assert!((summary.mean_interval_ms - 253.0).abs() < 1e-9);
+        assert!((summary.p99_interval_ms - 300.0).abs() < 1e-9);
+        assert_eq!(summary.to_string(), "rapl,250,101,253.000,300.000,1");
+
+        let empty = PollSummary::new("bmc", 1000, &[], 0);
+        assert_eq!(empty.samples, 0);
+    }
+
+    #[test]
+    fn test_poll_schedule_skips_missed_deadlines() {
+        let mut schedule = PollSchedule::new(Duration::from_millis(100));
+        let start = Instant::now();
+        schedule.wait();
+
+        // miss the deadline at 100ms, sample late for the one at 200ms...
+        thread::sleep(Duration::from_millis(250));
+        schedule.wait();
+        assert!(start.elapsed() < Duration::from_millis(300));
+
+        // ...and back on schedule for the one at 300ms
+        schedule.wait();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        let summary = schedule.summary("test");
+        assert_eq!(summary.missed_deadlines, 1);
+        assert_eq!(summary.samples, 3);
+    }
+}
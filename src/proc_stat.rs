@@ -0,0 +1,203 @@
+use crate::clock;
+use chrono::{DateTime, Local};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+
+// The kernel's per-CPU time accounting. The first line ("cpu ") is the aggregate
+// over all CPUs, followed by one line per online CPU ("cpu0", "cpu1", ...). The
+// values are cumulative jiffies (USER_HZ, typically 1/100th second) since boot.
+const PROC_STAT_PATH: &str = "/proc/stat";
+
+/// A CPU whose busy percentage over an interval is at or above this threshold is
+/// counted as running one of the load generator's threads.
+pub const ACTIVE_CPU_THRESHOLD_PCT: f64 = 50.0;
+
+/// Cumulative busy and idle jiffies for a single CPU
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CPU_Jiffies {
+    pub cpu: u64,
+    pub busy: u64,
+    pub idle: u64,
+}
+
+/// A timestamped snapshot of the jiffy counters of all online CPUs
+#[derive(Debug, Clone)]
+pub struct ProcStat {
+    pub timestamp: DateTime<Local>,
+    /// Monotonic timestamp (see `clock.rs`) of the snapshot, in µs
+    pub monotonic_us: u64,
+    pub cpus: Vec<CPU_Jiffies>,
+}
+
+/// The busy percentage of a single CPU over the interval between two snapshots
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy)]
+pub struct CPU_Utilisation {
+    pub cpu: u64,
+    pub busy_pct: f64,
+}
+
+/// Achieved load over an interval: the mean busy percentage of all CPUs and the
+/// number of CPUs that were active (see `ACTIVE_CPU_THRESHOLD_PCT`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LoadSummary {
+    pub mean_busy_pct: f64,
+    pub active_cpus: u64,
+}
+
+impl ProcStat {
+    /// Reads and parses `/proc/stat`
+    ///
+    /// # Panics
+    /// If `/proc/stat` can't be read
+    #[must_use]
+    pub fn read() -> Self {
+        let contents = fs::read_to_string(PROC_STAT_PATH).expect("Failed to read /proc/stat");
+        Self {
+            timestamp: Local::now(),
+            monotonic_us: clock::monotonic_micros(),
+            cpus: ProcStat::parse(&contents),
+        }
+    }
+
+    /// Parses the per-CPU lines of `/proc/stat`, ignoring the aggregate "cpu" line
+    /// and all the non-CPU lines (interrupts, context switches...).
+    ///
+    /// The columns are: user nice system idle iowait irq softirq steal guest guest_nice.
+    /// Time spent in iowait is counted as idle. The guest columns are already included
+    /// in user/nice so are ignored.
+    ///
+    /// # Panics
+    /// If a CPU line contains a non-numeric field
+    #[must_use]
+    pub fn parse(contents: &str) -> Vec<CPU_Jiffies> {
+        let mut cpus = Vec::new();
+        for line in contents.lines() {
+            let mut fields = line.split_ascii_whitespace();
+            let Some(label) = fields.next() else { continue };
+
+            // skip the aggregate line and anything that's not a cpu
+            let Some(cpu_id) = label.strip_prefix("cpu").filter(|id| !id.is_empty()) else {
+                continue;
+            };
+            let cpu: u64 = cpu_id.parse().expect("Failed to parse cpu id in /proc/stat");
+            let values: Vec<u64> = fields
+                .take(8)
+                .map(|field| field.parse().expect("Failed to parse /proc/stat jiffies"))
+                .collect();
+
+            let value = |index: usize| values.get(index).copied().unwrap_or(0);
+            let idle = value(3) + value(4);
+            let busy = value(0) + value(1) + value(2) + value(5) + value(6) + value(7);
+            cpus.push(CPU_Jiffies { cpu, busy, idle });
+        }
+        cpus
+    }
+
+    /// Calculates the busy percentage of each CPU between an `earlier` snapshot and this one.
+    /// CPUs that are not present in both snapshots (hot-plugged) are skipped.
+    #[must_use]
+    pub fn utilisation_since(&self, earlier: &ProcStat) -> Vec<CPU_Utilisation> {
+        self.cpus
+            .iter()
+            .filter_map(|now| {
+                let before = earlier.cpus.iter().find(|before| before.cpu == now.cpu)?;
+                let busy = now.busy.saturating_sub(before.busy);
+                let total = busy + now.idle.saturating_sub(before.idle);
+
+                #[allow(clippy::cast_precision_loss)]
+                let busy_pct = if total == 0 {
+                    0.0
+                } else {
+                    100.0 * busy as f64 / total as f64
+                };
+                Some(CPU_Utilisation { cpu: now.cpu, busy_pct })
+            })
+            .collect()
+    }
+
+    /// Summarises the achieved load between an `earlier` snapshot and this one
+    #[must_use]
+    pub fn load_since(&self, earlier: &ProcStat) -> LoadSummary {
+        LoadSummary::from_utilisation(&self.utilisation_since(earlier))
+    }
+}
+
+impl LoadSummary {
+    #[must_use]
+    pub fn from_utilisation(utilisation: &[CPU_Utilisation]) -> Self {
+        if utilisation.is_empty() {
+            return Self::default();
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let mean_busy_pct = utilisation.iter().map(|cpu| cpu.busy_pct).sum::<f64>()
+            / utilisation.len() as f64;
+        let active_cpus = utilisation
+            .iter()
+            .filter(|cpu| cpu.busy_pct >= ACTIVE_CPU_THRESHOLD_PCT)
+            .count() as u64;
+
+        Self { mean_busy_pct, active_cpus }
+    }
+}
+
+impl Display for LoadSummary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:.1},{}", self.mean_busy_pct, self.active_cpus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROC_STAT_T0: &str = "\
+cpu  300 0 150 1050 0 0 0 0 0 0
+cpu0 100 0 50 350 0 0 0 0 0 0
+cpu1 100 0 50 350 0 0 0 0 0 0
+cpu2 100 0 50 350 0 0 0 0 0 0
+intr 50250 0 0 0
+ctxt 120241
+btime 1792358015
+";
+
+    // cpu0 fully busy, cpu1 97% busy (with some iowait), cpu2 idle
+    const PROC_STAT_T1: &str = "\
+cpu  550 0 250 1253 3 0 0 0 0 0
+cpu0 180 0 70 350 0 0 0 0 0 0
+cpu1 167 0 80 351 2 0 0 0 0 0
+cpu2 100 0 50 550 0 0 0 0 0 0
+intr 50999 0 0 0
+ctxt 130000
+";
+
+    fn snapshot(contents: &str) -> ProcStat {
+        ProcStat { timestamp: Local::now(), monotonic_us: 0, cpus: ProcStat::parse(contents) }
+    }
+
+    #[test]
+    fn test_parse_proc_stat() {
+        let cpus = ProcStat::parse(PROC_STAT_T1);
+        assert_eq!(cpus.len(), 3);
+        assert_eq!(cpus[1], CPU_Jiffies { cpu: 1, busy: 247, idle: 353 });
+        assert_eq!(cpus[2], CPU_Jiffies { cpu: 2, busy: 150, idle: 550 });
+    }
+
+    #[test]
+    fn test_utilisation_since() {
+        let utilisation = snapshot(PROC_STAT_T1).utilisation_since(&snapshot(PROC_STAT_T0));
+        assert_eq!(utilisation.len(), 3);
+        assert!((utilisation[0].busy_pct - 100.0).abs() < f64::EPSILON);
+        assert!((utilisation[1].busy_pct - 97.0).abs() < 1e-9);
+        assert!(utilisation[2].busy_pct.abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_load_since() {
+        let summary = snapshot(PROC_STAT_T1).load_since(&snapshot(PROC_STAT_T0));
+        assert_eq!(summary.active_cpus, 2);
+        assert!((summary.mean_busy_pct - 197.0 / 3.0).abs() < 1e-9);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::clock;
 use chrono::{DateTime, Local, SecondsFormat};
 use glob::glob;
 use log::trace;
@@ -47,6 +48,8 @@ pub struct RAPL_Reading {
 #[derive(Debug)]
 pub struct RAPL_Readings {
     pub timestamp: DateTime<Local>,
+    /// Monotonic timestamp (see `clock.rs`) of the readings, in µs
+    pub monotonic_us: u64,
     /// List of readings (see above) for all known domains
     pub readings: Vec<RAPL_Reading>,
 }
@@ -100,7 +103,6 @@ impl RAPL {
             .file_name()
             .expect("RAPL failed to get directory name")
             .to_string_lossy()
-            .to_string()
             .split(':')
             .nth(1)
             .expect("Didn't find a colon separator in path")
@@ -130,6 +132,16 @@ impl RAPL {
             .expect("Failed to parse max energy reading")
     }
 
+    /// All the files the RAPL monitor reads: the energy files of every domain and the
+    /// wrap-around value
+    #[must_use]
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self.pkg_paths.values().chain(self.core_paths.values()).cloned().collect();
+        files.sort();
+        files.push(PathBuf::from(MAX_ENERGY_PATH));
+        files
+    }
+
     pub fn domain_count() -> u64 {
         glob(RAPL_GLOB).expect("RAPL failed to glob directory").count() as u64
     }
@@ -154,6 +166,7 @@ impl RAPL_Readings {
     pub fn new(readings: Vec<RAPL_Reading>) -> Self {
         Self {
             timestamp: Local::now(),
+            monotonic_us: clock::monotonic_micros(),
             readings,
         }
     }
@@ -174,8 +187,9 @@ impl Display for RAPL_Readings {
 
         write!(
             f,
-            "{},{}",
+            "{},{},{}",
             self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, false),
+            self.monotonic_us,
             readings
         )
     }
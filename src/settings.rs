@@ -0,0 +1,468 @@
+//! Layered settings: any long command-line option can also be set in a TOML config file
+//! (`--config`), for every host at the top level and per host in `[profiles.NAME]`
+//! tables, or in a `CAPPING_<OPTION>` environment variable. From lowest to highest
+//! precedence: the built-in default, the config file, the profile, the environment and
+//! the command line. For example:
+//!
+//! ```toml
+//! stats_dir = "/data/capping"
+//! ipmi = "/usr/local/bin/ipmitool"
+//! bmc_poll_millis = 500
+//!
+//! [profiles.node12]                       # used on node12, or with --profile node12
+//! bmc_hostname = "node12-bmc"
+//! bmc_username = "admin"
+//! bmc_password = "secret"
+//! cap_low = 300
+//! ```
+//!
+//! with `CAPPING_STATS_DIR=/scratch/capping` taking precedence over the file's
+//! `stats_dir`. The profile is the one given by `--profile`, or else the one named after
+//! the host, if there is one. The effective settings, with where each came from, are
+//! saved in the stats directory of each run.
+
+use crate::cli::{self, Configuration};
+use crate::ResultType;
+use chrono::Local;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use log::debug;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Prefix of the environment variable of each setting, e.g. `CAPPING_STATS_DIR`
+pub const ENV_PREFIX: &str = "CAPPING_";
+/// The settings choosing the config file and profile, which can't be set in the file
+pub const CONFIG_KEY: &str = "config";
+pub const PROFILE_KEY: &str = "profile";
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+// Settings written out commented out in the effective configuration, so that reusing
+// it as a config file doesn't pick them up: secrets, which are masked, and those
+// that only make sense for the run they were given to
+const SECRET_SETTINGS: [&str; 1] = ["bmc_password"];
+const INVOCATION_SETTINGS: [&str; 2] = ["resume", "dry_run"];
+
+/// Where a setting's value came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    File(String),
+    Profile(String),
+    Environment(String),
+    CommandLine,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::File(path) => write!(f, "config file {path}"),
+            Self::Profile(name) => write!(f, "profile {name}"),
+            Self::Environment(var) => write!(f, "environment {var}"),
+            Self::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// The value of a setting, as it would be given on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub key: String,
+    pub value: String,
+    pub origin: Origin,
+}
+
+/// A config file, as read
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigFile {
+    pub path: String,
+    /// The settings for every host
+    pub settings: BTreeMap<String, String>,
+    /// The settings of each profile, which take precedence over those for every host
+    pub profiles: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ConfigFile {
+    /// # Errors
+    /// If the file can't be read or isn't valid TOML
+    pub fn load(path: &str) -> ResultType<Self> {
+        let toml = fs::read_to_string(path).map_err(|e| format!("can't read config file {path}: {e}"))?;
+        Self::from_toml(&toml, path)
+    }
+
+    /// # Errors
+    /// If `toml` isn't valid TOML, or a setting isn't a string, number, boolean or
+    /// array of them
+    pub fn from_toml(toml: &str, path: &str) -> ResultType<Self> {
+        let mut table: toml::Table = toml.parse().map_err(|e| format!("config file {path}: {e}"))?;
+        let mut profiles = BTreeMap::new();
+        if let Some(value) = table.remove("profiles") {
+            let toml::Value::Table(tables) = value else {
+                return Err(format!("config file {path}: profiles must be a table of [profiles.NAME] tables").into());
+            };
+            for (name, profile) in tables {
+                let toml::Value::Table(profile) = profile else {
+                    return Err(format!("config file {path}: profile {name} must be a table").into());
+                };
+                let settings = settings_of(profile).map_err(|e| format!("config file {path}, profile {name}: {e}"))?;
+                profiles.insert(name, settings);
+            }
+        }
+        let settings = settings_of(table).map_err(|e| format!("config file {path}: {e}"))?;
+        Ok(Self { path: String::from(path), settings, profiles })
+    }
+}
+
+/// The settings of a table, as they would be given on the command line
+fn settings_of(table: toml::Table) -> Result<BTreeMap<String, String>, String> {
+    table.into_iter().map(|(key, value)| Ok((key.clone(), setting_value(&key, &value)?))).collect()
+}
+
+fn setting_value(key: &str, value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(x) => Ok(x.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        // e.g. a sweep's levels
+        toml::Value::Array(values) => {
+            let values: Result<Vec<String>, String> = values.iter().map(|value| setting_value(key, value)).collect();
+            Ok(values?.join(","))
+        }
+        _ => Err(format!("{key} must be a string, number, boolean or array of them")),
+    }
+}
+
+/// The setting key of a long option, e.g. `stats_dir` for `--stats-dir`
+fn setting_key(long: &str) -> String {
+    long.replace('-', "_")
+}
+
+/// The environment variable of a setting, e.g. `CAPPING_STATS_DIR` for `stats_dir`
+#[must_use]
+pub fn env_var(key: &str) -> String {
+    format!("{ENV_PREFIX}{}", key.to_uppercase())
+}
+
+/// The name of this host, for choosing its profile
+#[must_use]
+pub fn hostname() -> Option<String> {
+    fs::read_to_string(HOSTNAME_PATH).ok().map(|name| String::from(name.trim()))
+}
+
+/// The keys of all the long options of `command` and its subcommands
+#[must_use]
+pub fn keys(command: &Command) -> BTreeSet<String> {
+    command
+        .get_arguments()
+        .filter_map(|arg| arg.get_long().map(setting_key))
+        .chain(command.get_subcommands().flat_map(keys))
+        .collect()
+}
+
+/// The settings of the layers under the command line, each from the highest layer that
+/// sets it: the config file, then the profile, then the environment (looked up by
+/// `env`). The profile is `profile`, or else the one named `hostname`, if the file has
+/// one.
+///
+/// # Errors
+/// If the file or profile has a setting that isn't one of `keys`, or `profile` isn't
+/// in the file
+pub fn layers(
+    file: Option<&ConfigFile>,
+    profile: Option<&str>,
+    hostname: Option<&str>,
+    keys: &BTreeSet<String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> ResultType<BTreeMap<String, Setting>> {
+    let mut layers = BTreeMap::new();
+    let mut add = |settings: &BTreeMap<String, String>, origin: &Origin| -> ResultType<()> {
+        for (key, value) in settings {
+            if !keys.contains(key) {
+                return Err(format!("{origin}: unknown setting {key}").into());
+            }
+            layers.insert(key.clone(), Setting { key: key.clone(), value: value.clone(), origin: origin.clone() });
+        }
+        Ok(())
+    };
+
+    if let Some(file) = file {
+        add(&file.settings, &Origin::File(file.path.clone()))?;
+        let profile = match profile {
+            Some(name) => Some((name, file.profiles.get(name).ok_or_else(|| format!("no profile {name} in config file {}", file.path))?)),
+            None => hostname.and_then(|name| Some((name, file.profiles.get(name)?))),
+        };
+        if let Some((name, settings)) = profile {
+            debug!("Using profile {name} of config file {}", file.path);
+            add(settings, &Origin::Profile(String::from(name)))?;
+        }
+    } else if let Some(name) = profile {
+        return Err(format!("profile {name} given without a config file").into());
+    }
+
+    let environment: BTreeMap<String, String> =
+        keys.iter().filter_map(|key| Some((key.clone(), env(&env_var(key))?))).collect();
+    for (key, value) in environment {
+        let origin = Origin::Environment(env_var(&key));
+        layers.insert(key.clone(), Setting { key, value, origin });
+    }
+    Ok(layers)
+}
+
+/// `command` with the defaults of its options, and those of its subcommands, replaced
+/// by the values of the layers, so that the command line still takes precedence
+///
+/// # Errors
+/// If a layer's value isn't valid for its option, as it wouldn't be on the command line
+pub fn with_defaults(command: Command, layers: &BTreeMap<String, Setting>) -> ResultType<Command> {
+    check_values(&command, layers)?;
+    Ok(apply_defaults(command, layers))
+}
+
+/// Parses the layers' values as the options they set would parse them
+fn check_values(command: &Command, layers: &BTreeMap<String, Setting>) -> ResultType<()> {
+    for arg in command.get_arguments() {
+        let Some(setting) = arg.get_long().and_then(|long| layers.get(&setting_key(long))) else { continue };
+        // the option alone, taking the value as an option does, flags included
+        let probe = Command::new(command.get_name().to_owned())
+            .arg(arg.clone().global(false).required(false).action(ArgAction::Set));
+        let option = format!("--{}={}", arg.get_long().unwrap_or_default(), setting.value);
+        if let Err(e) = probe.try_get_matches_from([command.get_name(), option.as_str()]) {
+            // the first line of clap's message, which is about the command line
+            let reason = e.to_string();
+            let reason = reason.lines().next().unwrap_or_default().trim_start_matches("error: ");
+            return Err(format!("{}: {} = {:?}: {reason}", setting.origin, setting.key, setting.value).into());
+        }
+    }
+    command.get_subcommands().try_for_each(|subcommand| check_values(subcommand, layers))
+}
+
+fn apply_defaults(command: Command, layers: &BTreeMap<String, Setting>) -> Command {
+    let defaults: Vec<(String, String)> = command
+        .get_arguments()
+        .filter_map(|arg| {
+            let setting = layers.get(&setting_key(arg.get_long()?))?;
+            Some((arg.get_id().to_string(), setting.value.clone()))
+        })
+        .collect();
+    let subcommands: Vec<String> = command.get_subcommands().map(|c| String::from(c.get_name())).collect();
+
+    let mut command = command;
+    for (id, value) in defaults {
+        command = command.mut_arg(id, |arg| arg.default_value(value));
+    }
+    for name in subcommands {
+        command = command.mut_subcommand(name, |subcommand| apply_defaults(subcommand, layers));
+    }
+    command
+}
+
+/// The value of each option of `command`, and of the subcommand run, with where it
+/// came from, ordered by key. Options that are unset are left out, as are the options
+/// of `command` that a subcommand run takes instead of it, such as those of the
+/// default `run`.
+#[must_use]
+pub fn effective(command: &Command, matches: &ArgMatches, layers: &BTreeMap<String, Setting>) -> Vec<Setting> {
+    let mut settings = Vec::new();
+    match matches.subcommand().and_then(|(name, sub_matches)| Some((command.find_subcommand(name)?, sub_matches))) {
+        Some((subcommand, sub_matches)) => {
+            add_effective(command, matches, layers, true, &mut settings);
+            add_effective(subcommand, sub_matches, layers, false, &mut settings);
+        }
+        None => add_effective(command, matches, layers, false, &mut settings),
+    }
+    settings.sort_by(|a, b| a.key.cmp(&b.key));
+    settings
+}
+
+fn add_effective(
+    command: &Command,
+    matches: &ArgMatches,
+    layers: &BTreeMap<String, Setting>,
+    global_only: bool,
+    settings: &mut Vec<Setting>,
+) {
+    for arg in command.get_arguments().filter(|arg| !global_only || arg.is_global_set()) {
+        let Some(key) = arg.get_long().map(setting_key) else { continue };
+        let Some(values) = matches.get_raw(arg.get_id().as_str()) else { continue };
+        let value = values.map(|v| v.to_string_lossy()).collect::<Vec<_>>().join(",");
+        let origin = match (matches.value_source(arg.get_id().as_str()), layers.get(&key)) {
+            (Some(ValueSource::CommandLine), _) => Origin::CommandLine,
+            (_, Some(setting)) => setting.origin.clone(),
+            _ => Origin::Default,
+        };
+        settings.push(Setting { key, value, origin });
+    }
+}
+
+/// Writes the effective settings of the run to the stats directory, as a config file
+/// that would reproduce the run, with the origin of each setting. The password and
+/// the settings only meaningful to this invocation, such as `resume`, are commented
+/// out. The config file and profile they came from are noted in the header. A resumed
+/// run's settings are saved alongside those of the run it resumes, rather than over
+/// them.
+///
+/// # Errors
+/// If the file can't be written
+pub fn save_effective(config: &Configuration) -> ResultType<PathBuf> {
+    let filepath = effective_path(config);
+    debug!("Saving effective configuration to: {filepath:?}");
+
+    let handle = File::create(&filepath)?;
+    let mut writer = BufWriter::new(handle);
+    write!(writer, "{}", effective_toml(config))?;
+    Ok(filepath)
+}
+
+/// e.g. `effective_config_240101_1200.toml`, or for a run resuming it
+/// `effective_config_240101_1200_resumed_240102_093000.toml`
+fn effective_path(config: &Configuration) -> PathBuf {
+    let filename = if config.resume && config.command == cli::Command::Run {
+        format!("{}_{}_resumed_{}.toml",
+            config.effective_config_filename_prefix,
+            config.test_timestamp,
+            Local::now().format("%y%m%d_%H%M%S")
+        )
+    } else {
+        format!("{}_{}.toml",
+            config.effective_config_filename_prefix,
+            config.test_timestamp
+        )
+    };
+    Path::new(&config.stats_dir).join(filename)
+}
+
+fn effective_toml(config: &Configuration) -> String {
+    let mut toml = format!("# Effective configuration of run {}, with the origin of each setting\n", config.test_timestamp);
+    if let Some(path) = &config.config_file {
+        toml += &format!("# Config file {path}, profile {}\n", config.profile.as_deref().unwrap_or("none"));
+    }
+    for setting in config.settings.iter().filter(|s| s.key != CONFIG_KEY && s.key != PROFILE_KEY) {
+        let key = setting.key.as_str();
+        let (comment, value) = if SECRET_SETTINGS.contains(&key) {
+            ("# ", "****")
+        } else if INVOCATION_SETTINGS.contains(&key) {
+            ("# ", setting.value.as_str())
+        } else {
+            ("", setting.value.as_str())
+        };
+        toml += &format!("{comment}{key} = {}  # {}\n", toml_value(value), setting.origin);
+    }
+    toml
+}
+
+/// A setting's value as TOML: numbers and booleans as they are, anything else quoted
+fn toml_value(value: &str) -> String {
+    if value.parse::<i64>().is_ok() || value.parse::<bool>().is_ok() {
+        String::from(value)
+    } else {
+        toml::Value::String(String::from(value)).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+        stats_dir = "/data/capping"
+        bmc_poll_millis = 500
+
+        [profiles.node12]
+        bmc_hostname = "node12-bmc"
+        bmc_poll_millis = 250
+        sweep = [300, 420]
+
+        [profiles.node13]
+        bmc_hostname = "node13-bmc"
+        "#;
+
+    fn keys() -> BTreeSet<String> {
+        ["stats_dir", "bmc_poll_millis", "bmc_hostname", "sweep"].map(String::from).into()
+    }
+
+    fn values(layers: &BTreeMap<String, Setting>) -> Vec<(&str, &str)> {
+        layers.iter().map(|(key, setting)| (key.as_str(), setting.value.as_str())).collect()
+    }
+
+    #[test]
+    fn test_config_file() {
+        let file = ConfigFile::from_toml(FILE, "capping.toml").unwrap();
+        assert_eq!(file.settings["bmc_poll_millis"], "500");
+        assert_eq!(file.profiles["node12"]["sweep"], "300,420");
+        assert_eq!(file.profiles.len(), 2);
+
+        assert!(ConfigFile::from_toml("stats_dir = ", "bad.toml").is_err());
+        assert!(ConfigFile::from_toml("profiles = 3", "bad.toml").is_err());
+        assert!(ConfigFile::from_toml("[profiles]\nnode12 = 3", "bad.toml").is_err());
+        assert!(ConfigFile::from_toml("[loads]\nload_pct = 3", "bad.toml").is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        let file = ConfigFile::from_toml(FILE, "capping.toml").unwrap();
+        let no_env = |_: &str| None;
+
+        let layers = layers(Some(&file), None, None, &keys(), no_env).unwrap();
+        assert_eq!(values(&layers), [("bmc_poll_millis", "500"), ("stats_dir", "/data/capping")]);
+        assert_eq!(layers["stats_dir"].origin, Origin::File(String::from("capping.toml")));
+
+        // the host's profile, unless another is given
+        let layers = super::layers(Some(&file), None, Some("node12"), &keys(), no_env).unwrap();
+        assert_eq!(layers["bmc_poll_millis"].value, "250");
+        assert_eq!(layers["bmc_hostname"].origin, Origin::Profile(String::from("node12")));
+        let layers = super::layers(Some(&file), Some("node13"), Some("node12"), &keys(), no_env).unwrap();
+        assert_eq!(layers["bmc_hostname"].value, "node13-bmc");
+        assert_eq!(layers["bmc_poll_millis"].value, "500");
+        assert!(super::layers(Some(&file), Some("node14"), None, &keys(), no_env).is_err());
+        assert!(super::layers(None, Some("node12"), None, &keys(), no_env).is_err());
+
+        // the environment takes precedence over the file and profile
+        let env = |var: &str| (var == "CAPPING_BMC_POLL_MILLIS").then(|| String::from("100"));
+        let layers = super::layers(Some(&file), None, Some("node12"), &keys(), env).unwrap();
+        assert_eq!(layers["bmc_poll_millis"].value, "100");
+        assert_eq!(layers["bmc_poll_millis"].origin, Origin::Environment(String::from("CAPPING_BMC_POLL_MILLIS")));
+        assert_eq!(values(&super::layers(None, None, None, &keys(), env).unwrap()), [("bmc_poll_millis", "100")]);
+
+        // only the options' settings can be set
+        let file = ConfigFile::from_toml("bmc_pole_millis = 500", "typo.toml").unwrap();
+        assert!(super::layers(Some(&file), None, None, &keys(), no_env).is_err());
+    }
+
+    #[test]
+    fn test_effective_config() {
+        let mut config = Configuration::for_bmc("bmc", "user", "secret");
+        config.test_timestamp = String::from("240101_1200");
+        config.settings = vec![
+            Setting { key: String::from("bmc_password"), value: String::from("secret"), origin: Origin::CommandLine },
+            Setting { key: String::from("resume"), value: String::from("true"), origin: Origin::CommandLine },
+            Setting { key: String::from("warmup"), value: String::from("30"), origin: Origin::Profile(String::from("node12")) },
+        ];
+        let toml = effective_toml(&config);
+        assert_eq!(toml.lines().skip(1).collect::<Vec<_>>(), [
+            "# bmc_password = \"****\"  # command line",
+            "# resume = true  # command line",
+            "warmup = 30  # profile node12",
+        ]);
+        // reused as a config file, only the run's own settings are taken
+        let file = ConfigFile::from_toml(&toml, "effective_config.toml").unwrap();
+        assert_eq!(file.settings.keys().collect::<Vec<_>>(), ["warmup"]);
+
+        assert!(effective_path(&config).ends_with("effective_config_240101_1200.toml"));
+        config.resume = true;
+        let resumed = effective_path(&config);
+        let resumed = resumed.file_name().unwrap().to_str().unwrap();
+        assert!(resumed.starts_with("effective_config_240101_1200_resumed_"), "{resumed}");
+    }
+
+    #[test]
+    fn test_toml_value() {
+        assert_eq!(toml_value("500"), "500");
+        assert_eq!(toml_value("true"), "true");
+        assert_eq!(toml_value("./stats"), "\"./stats\"");
+        assert_eq!(toml_value("300,420"), "\"300,420\"");
+    }
+}
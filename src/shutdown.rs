@@ -0,0 +1,36 @@
+//! Orderly shutdown on Ctrl-C / SIGTERM. The signal handler only raises a flag: the
+//! driver checks it between (and during) trials, the load generator is stopped, and `main()`
+//! then stops the monitors, so that they save their stats, and restores the BMC's
+//! original cap settings before exiting.
+
+use crate::ResultType;
+use log::warn;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Exit status of a process killed by SIGINT
+const FORCED_EXIT_STATUS: i32 = 130;
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs the Ctrl-C / SIGTERM handler. A second signal while shutting down exits
+/// immediately, without restoring the cap settings.
+///
+/// # Errors
+/// If a handler is already installed
+pub fn install_handler() -> ResultType<()> {
+    ctrlc::set_handler(|| {
+        if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+            warn!("Interrupted again - exiting immediately, the BMC cap settings are NOT restored");
+            process::exit(FORCED_EXIT_STATUS);
+        }
+        warn!("Interrupted - stopping the current trial and restoring the BMC cap settings (interrupt again to force exit)");
+    })?;
+    Ok(())
+}
+
+/// True once a shutdown has been requested
+#[must_use]
+pub fn requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,281 @@
+use crate::ResultType;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::str::FromStr;
+
+// The online CPUs, as a CPU list (e.g. "0-63"), and the directory of each CPU's
+// topology: the socket ("physical_package_id") and physical core ("core_id") it's on.
+const ONLINE_CPUS_PATH: &str = "/sys/devices/system/cpu/online";
+const CPU_TOPOLOGY_DIR: &str = "/sys/devices/system/cpu/cpu";
+
+/// An online CPU (hardware thread), with the socket and physical core it's on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    pub id: usize,
+    pub package: usize,
+    pub core: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    pub cpus: Vec<Cpu>,
+}
+
+impl Topology {
+    /// Reads the topology of the online CPUs from sysfs
+    ///
+    /// # Errors
+    /// If the sysfs files can't be read or parsed
+    pub fn read() -> ResultType<Self> {
+        let read_number = |path: String| -> ResultType<usize> {
+            let contents = fs::read_to_string(&path).map_err(|e| format!("{path}: {e}"))?;
+            Ok(contents.trim().parse().map_err(|e| format!("{path}: {e}"))?)
+        };
+
+        let online = fs::read_to_string(ONLINE_CPUS_PATH).map_err(|e| format!("{ONLINE_CPUS_PATH}: {e}"))?;
+        let cpus = parse_cpu_list(online.trim())?
+            .into_iter()
+            .map(|id| {
+                Ok(Cpu {
+                    id,
+                    package: read_number(format!("{CPU_TOPOLOGY_DIR}{id}/topology/physical_package_id"))?,
+                    core: read_number(format!("{CPU_TOPOLOGY_DIR}{id}/topology/core_id"))?,
+                })
+            })
+            .collect::<ResultType<Vec<Cpu>>>()?;
+        Ok(Self { cpus })
+    }
+
+    /// The ids of the sockets, in order
+    #[must_use]
+    pub fn packages(&self) -> Vec<usize> {
+        let mut packages: Vec<usize> = self.cpus.iter().map(|cpu| cpu.package).collect();
+        packages.sort_unstable();
+        packages.dedup();
+        packages
+    }
+}
+
+/// Where the load generator's threads run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Placement {
+    /// Wherever the OS schedules them
+    None,
+    /// Fill each socket's cores, and their hardware threads, before the next socket
+    Compact,
+    /// Spread round-robin across the sockets
+    Scatter,
+    /// Only the given socket
+    Socket(usize),
+    /// The given CPUs
+    Cpus(Vec<usize>),
+}
+
+impl Placement {
+    /// The CPUs to run `n_threads` threads on (0 = one per CPU available under the
+    /// placement), one thread per CPU, or `None` if the threads aren't bound
+    ///
+    /// # Errors
+    /// If the placement has fewer CPUs than threads, or names CPUs or a socket
+    /// that aren't online
+    pub fn cpus(&self, topology: &Topology, n_threads: u64) -> ResultType<Option<Vec<usize>>> {
+        let mut cpus: Vec<Cpu> = topology.cpus.clone();
+        match self {
+            Placement::None => return Ok(None),
+            Placement::Compact => cpus.sort_by_key(|cpu| (cpu.package, cpu.core, cpu.id)),
+            Placement::Scatter => {
+                // number each CPU's hardware thread within its core, taken in id order
+                cpus.sort_by_key(|cpu| cpu.id);
+                let mut threads_seen: HashMap<(usize, usize), usize> = HashMap::new();
+                let mut by_package: BTreeMap<usize, Vec<(usize, Cpu)>> = BTreeMap::new();
+                for cpu in &cpus {
+                    let thread = threads_seen.entry((cpu.package, cpu.core)).or_insert(0);
+                    by_package.entry(cpu.package).or_default().push((*thread, *cpu));
+                    *thread += 1;
+                }
+                // within each socket, the first hardware thread of every core before
+                // the second ones...
+                for package_cpus in by_package.values_mut() {
+                    package_cpus.sort_by_key(|(thread, cpu)| (*thread, cpu.core));
+                }
+                // ...and round-robin across the sockets
+                let most_cpus = by_package.values().map(Vec::len).max().unwrap_or(0);
+                cpus = (0..most_cpus)
+                    .flat_map(|i| by_package.values().filter_map(move |package_cpus| package_cpus.get(i)))
+                    .map(|(_, cpu)| *cpu)
+                    .collect();
+            }
+            Placement::Socket(package) => {
+                cpus.retain(|cpu| cpu.package == *package);
+                if cpus.is_empty() {
+                    return Err(format!("socket {package} has no online CPUs").into());
+                }
+                cpus.sort_by_key(|cpu| (cpu.core, cpu.id));
+            }
+            Placement::Cpus(ids) => {
+                if let Some(id) = ids.iter().find(|id| !topology.cpus.iter().any(|cpu| cpu.id == **id)) {
+                    return Err(format!("CPU {id} isn't online").into());
+                }
+                return Placement::take(ids.clone(), n_threads, self).map(Some);
+            }
+        }
+        Placement::take(cpus.iter().map(|cpu| cpu.id).collect(), n_threads, self).map(Some)
+    }
+
+    /// As `cpus`, reading the topology of this machine if the threads are to be bound
+    ///
+    /// # Errors
+    /// If the topology can't be read, or as for `cpus`
+    pub fn place(&self, n_threads: u64) -> ResultType<Option<Vec<usize>>> {
+        if *self == Placement::None {
+            return Ok(None);
+        }
+        self.cpus(&Topology::read()?, n_threads)
+    }
+
+    fn take(mut ids: Vec<usize>, n_threads: u64, placement: &Placement) -> ResultType<Vec<usize>> {
+        let n_threads = usize::try_from(n_threads)?;
+        if n_threads > ids.len() {
+            return Err(format!("{n_threads} threads don't fit on the {} CPUs of placement {placement}", ids.len()).into());
+        }
+        if n_threads > 0 {
+            ids.truncate(n_threads);
+        }
+        Ok(ids)
+    }
+
+    /// The policy alone, without a CPU list, for the CSV files
+    #[must_use]
+    pub fn policy(&self) -> String {
+        match self {
+            Placement::Cpus(_) => String::from("cpus"),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    /// "none", "compact", "scatter", "socket:N" or "cpus:LIST", where LIST is as
+    /// for `taskset -c`, e.g. "0,2,4-7"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(Placement::None),
+            None if s == "compact" => Ok(Placement::Compact),
+            None if s == "scatter" => Ok(Placement::Scatter),
+            Some(("socket", package)) => package
+                .parse()
+                .map(Placement::Socket)
+                .map_err(|e| format!("invalid socket {package:?}: {e}")),
+            Some(("cpus", list)) => parse_cpu_list(list).map(Placement::Cpus).map_err(|e| e.to_string()),
+            _ => Err(format!("unknown placement {s:?}, expected none, compact, scatter, socket:N or cpus:LIST")),
+        }
+    }
+}
+
+impl Display for Placement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Placement::None => write!(f, "none"),
+            Placement::Compact => write!(f, "compact"),
+            Placement::Scatter => write!(f, "scatter"),
+            Placement::Socket(package) => write!(f, "socket:{package}"),
+            Placement::Cpus(ids) => write!(f, "cpus:{}", format_cpu_list(ids, ",")),
+        }
+    }
+}
+
+/// Parses a CPU list as used by sysfs and `taskset -c`, e.g. "0,2,4-7"
+///
+/// # Errors
+/// If the list is empty or malformed
+pub fn parse_cpu_list(list: &str) -> ResultType<Vec<usize>> {
+    let mut ids = Vec::new();
+    for part in list.split(',') {
+        let part = part.trim();
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse()?, last.parse()?);
+                if first > last {
+                    return Err(format!("invalid CPU range {part:?}").into());
+                }
+                ids.extend(first..=last);
+            }
+            None => ids.push(part.parse().map_err(|e| format!("invalid CPU {part:?}: {e}"))?),
+        }
+    }
+    Ok(ids)
+}
+
+/// Formats CPU ids as a CPU list, collapsing consecutive ids into ranges and
+/// separating the parts with `separator` (e.g. ";" for the CSV files)
+#[must_use]
+pub fn format_cpu_list(ids: &[usize], separator: &str) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < ids.len() {
+        let mut j = i;
+        while j + 1 < ids.len() && ids[j + 1] == ids[j] + 1 {
+            j += 1;
+        }
+        parts.push(if i == j { ids[i].to_string() } else { format!("{}-{}", ids[i], ids[j]) });
+        i = j + 1;
+    }
+    parts.join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2 sockets * 2 cores * 2 hardware threads, numbered as Linux usually does:
+    /// the second hardware threads of every core after all the first ones
+    fn two_sockets() -> Topology {
+        let cpu = |id, package, core| Cpu { id, package, core };
+        Topology {
+            cpus: vec![
+                cpu(0, 0, 0), cpu(1, 0, 1), cpu(2, 1, 0), cpu(3, 1, 1),
+                cpu(4, 0, 0), cpu(5, 0, 1), cpu(6, 1, 0), cpu(7, 1, 1),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11").unwrap(), [0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(format_cpu_list(&[0, 1, 2, 3, 8, 10, 11], ";"), "0-3;8;10-11");
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn test_parse_placement() {
+        for placement in ["none", "compact", "scatter", "socket:1", "cpus:0-3,8"] {
+            assert_eq!(placement.parse::<Placement>().unwrap().to_string(), placement);
+        }
+        assert_eq!("cpus:0-3,8".parse::<Placement>().unwrap().policy(), "cpus");
+        assert!("spread".parse::<Placement>().is_err());
+        assert!("socket:x".parse::<Placement>().is_err());
+    }
+
+    #[test]
+    fn test_placement_cpus() {
+        let topology = two_sockets();
+        assert_eq!(topology.packages(), [0, 1]);
+        let cpus = |placement: &str, n_threads| placement.parse::<Placement>().unwrap().cpus(&topology, n_threads);
+
+        assert_eq!(cpus("none", 4).unwrap(), None);
+        assert_eq!(cpus("compact", 4).unwrap(), Some(vec![0, 4, 1, 5]));
+        assert_eq!(cpus("scatter", 4).unwrap(), Some(vec![0, 2, 1, 3]));
+        assert_eq!(cpus("scatter", 0).unwrap(), Some(vec![0, 2, 1, 3, 4, 6, 5, 7]));
+        assert_eq!(cpus("socket:1", 0).unwrap(), Some(vec![2, 6, 3, 7]));
+        assert_eq!(cpus("cpus:7,1", 0).unwrap(), Some(vec![7, 1]));
+
+        assert!(cpus("socket:1", 5).is_err());
+        assert!(cpus("socket:2", 0).is_err());
+        assert!(cpus("cpus:8", 0).is_err());
+    }
+}